@@ -0,0 +1,598 @@
+/*
+ * the audio processing unit: two pulse channels, a wave channel and a noise
+ * channel, each feeding its own DAC, panned by NR51 and scaled by NR50.
+ * lengths, envelopes and the sweep are clocked by a frame sequencer that
+ * moves on each time bit 4 of DIV falls, 512 times a second.
+ * each change in the mix goes in as a band-limited step at the host's
+ * sample rate, then through a high-pass like the DMG's output capacitor
+ * into a SampleBuffer.
+ * pandocs: https://gbdev.io/pandocs/Audio.html
+ */
+mod blip;
+mod buffer;
+mod envelope;
+mod high_pass;
+mod noise;
+mod pulse;
+mod recording;
+mod wave;
+
+pub use buffer::SampleBuffer;
+pub use recording::Recording;
+
+use blip::Blip;
+use high_pass::HighPass;
+use noise::Noise;
+use pulse::Pulse;
+use recording::Stems;
+use wave::Wave;
+
+// T-cycles a second
+pub const CLOCK_RATE: u32 = 4_194_304;
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+pub const NR50: u16 = 0xFF24;
+pub const NR51: u16 = 0xFF25;
+pub const NR52: u16 = 0xFF26;
+const REGISTERS_START: u16 = 0xFF10;
+const WAVE_RAM_START: u16 = 0xFF30;
+
+// bits that always read back as 1, 0xFF10 - 0xFF25
+const READ_MASKS: [u8; 0x16] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10 - NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR21 - NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30 - NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR41 - NR44
+    0x00, 0x00, // NR50, NR51
+];
+
+// the timer's counter bit that clocks the frame sequencer, DIV bit 4
+const FRAME_SEQUENCER_BIT: u16 = 1 << 12;
+
+#[derive(Debug, Clone)]
+pub struct Apu {
+    power: bool,
+    // as written, for reading back
+    registers: [u8; READ_MASKS.len()],
+    pulse1: Pulse,
+    pulse2: Pulse,
+    wave: Wave,
+    noise: Noise,
+    // 0 - 7
+    frame_step: u8,
+    // FRAME_SEQUENCER_BIT last time we looked
+    div_bit: bool,
+    sample_rate: u32,
+    // T-cycles times sample_rate since the last sample, so there's no drift
+    sample_phase: u32,
+    // left and right
+    blips: [Blip; 2],
+    high_passes: [HighPass; 2],
+    // the mix as last fed to the blips
+    level: (f32, f32),
+    buffer: SampleBuffer,
+    recording: Option<Recording>,
+    // only while recording stems
+    stems: Option<Box<Stems>>,
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Apu::new(DEFAULT_SAMPLE_RATE)
+    }
+}
+
+impl Apu {
+    pub fn new(sample_rate: u32) -> Self {
+        Apu {
+            power: false,
+            registers: [0; READ_MASKS.len()],
+            pulse1: Pulse::new(true),
+            pulse2: Pulse::new(false),
+            wave: Wave::new(),
+            noise: Noise::new(),
+            frame_step: 0,
+            div_bit: false,
+            sample_rate,
+            sample_phase: 0,
+            blips: Default::default(),
+            high_passes: [HighPass::new(sample_rate), HighPass::new(sample_rate)],
+            level: (0.0, 0.0),
+            // a second of audio
+            buffer: SampleBuffer::new(sample_rate as usize * 2),
+            recording: None,
+            stems: None,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // anything not yet drained is thrown away
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.sample_phase = 0;
+        self.blips = Default::default();
+        self.high_passes = [HighPass::new(sample_rate), HighPass::new(sample_rate)];
+        self.level = (0.0, 0.0);
+        self.buffer = SampleBuffer::new(sample_rate as usize * 2);
+        if self.stems.is_some() {
+            self.stems = Some(Box::new(Stems::new(sample_rate)));
+        }
+    }
+
+    pub fn buffer(&self) -> &SampleBuffer {
+        &self.buffer
+    }
+
+    pub fn buffer_mut(&mut self) -> &mut SampleBuffer {
+        &mut self.buffer
+    }
+
+    /*
+     * from here on every sample that goes to the buffer is kept in a
+     * Recording as well, along with each channel on its own if `stems`.
+     * anything recorded and not yet taken is thrown away
+     */
+    pub fn start_recording(&mut self, stems: bool) {
+        self.recording = Some(Recording::new(stems));
+        self.stems = stems.then(|| Box::new(Stems::new(self.sample_rate)));
+    }
+
+    // what's been recorded since the last call, None if not recording
+    pub fn take_recording(&mut self) -> Option<Recording> {
+        self.recording.as_mut().map(Recording::take)
+    }
+
+    pub fn stop_recording(&mut self) -> Option<Recording> {
+        self.stems = None;
+        self.recording.take()
+    }
+
+    // 0xFF10 - 0xFF26 and wave ram at 0xFF30 - 0xFF3F
+    pub fn read8(&self, address: u16) -> u8 {
+        match address {
+            NR52 => {
+                let status = [
+                    self.pulse1.enabled(),
+                    self.pulse2.enabled(),
+                    self.wave.enabled(),
+                    self.noise.enabled(),
+                ]
+                .into_iter()
+                .enumerate()
+                .fold(0, |bits, (i, on)| bits | (on as u8) << i);
+                0x70 | (self.power as u8) << 7 | status
+            }
+            0xFF10..=0xFF25 => {
+                let i = (address - REGISTERS_START) as usize;
+                self.registers[i] | READ_MASKS[i]
+            }
+            0xFF30..=0xFF3F => self.wave.read_ram((address - WAVE_RAM_START) as usize),
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write8(&mut self, address: u16, v: u8) {
+        match address {
+            NR52 => self.write_power(v & 0x80 != 0),
+            0xFF30..=0xFF3F => self.wave.write_ram((address - WAVE_RAM_START) as usize, v),
+            // everything else is locked while the power's off
+            _ if !self.power => {}
+            0xFF10..=0xFF25 => {
+                self.registers[(address - REGISTERS_START) as usize] = v;
+                match address {
+                    0xFF10..=0xFF14 => self.pulse1.write(address - 0xFF10, v),
+                    0xFF15..=0xFF19 => self.pulse2.write(address - 0xFF15, v),
+                    0xFF1A..=0xFF1E => self.wave.write(address - 0xFF1A, v),
+                    0xFF1F..=0xFF23 => self.noise.write(address - 0xFF1F, v),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn write_power(&mut self, on: bool) {
+        if self.power && !on {
+            self.registers = [0; READ_MASKS.len()];
+            self.pulse1 = Pulse::new(true);
+            self.pulse2 = Pulse::new(false);
+            self.wave.power_off();
+            self.noise = Noise::new();
+        } else if !self.power && on {
+            self.frame_step = 0;
+        }
+        self.power = on;
+    }
+
+    /*
+     * runs for `cycles` M-cycles. `div_counter` is the timer's internal
+     * counter afterwards, the frame sequencer steps when its bit 12 falls
+     * (including when DIV is written)
+     */
+    pub fn tick(&mut self, cycles: u8, div_counter: u16) {
+        for _ in 0..cycles {
+            if self.power {
+                self.pulse1.tick(4);
+                self.pulse2.tick(4);
+                self.wave.tick(4);
+                self.noise.tick(4);
+            }
+            self.sample_phase += 4 * self.sample_rate;
+            while self.sample_phase >= CLOCK_RATE {
+                self.sample_phase -= CLOCK_RATE;
+                self.read_sample();
+            }
+            self.add_steps();
+        }
+
+        let div_bit = div_counter & FRAME_SEQUENCER_BIT != 0;
+        if self.div_bit && !div_bit && self.power {
+            self.step_frame_sequencer();
+        }
+        self.div_bit = div_bit;
+    }
+
+    /*
+     * step  length  sweep  envelope
+     * 0     clock
+     * 2     clock   clock
+     * 4     clock
+     * 6     clock   clock
+     * 7                    clock
+     */
+    fn step_frame_sequencer(&mut self) {
+        if self.frame_step.is_multiple_of(2) {
+            self.pulse1.clock_length();
+            self.pulse2.clock_length();
+            self.wave.clock_length();
+            self.noise.clock_length();
+        }
+        if self.frame_step == 2 || self.frame_step == 6 {
+            self.pulse1.clock_sweep();
+        }
+        if self.frame_step == 7 {
+            self.pulse1.clock_envelope();
+            self.pulse2.clock_envelope();
+            self.noise.clock_envelope();
+        }
+        self.frame_step = (self.frame_step + 1) % 8;
+    }
+
+    // feeds any change in the mix since the last M-cycle to the blips
+    fn add_steps(&mut self) {
+        let (left, right) = self.mix();
+        let phase = (self.sample_phase as u64 * blip::PHASES as u64 / CLOCK_RATE as u64) as usize;
+        if left != self.level.0 {
+            self.blips[0].add_delta(phase, left - self.level.0);
+        }
+        if right != self.level.1 {
+            self.blips[1].add_delta(phase, right - self.level.1);
+        }
+        self.level = (left, right);
+        if self.stems.is_some() {
+            let outputs = self.channel_outputs();
+            if let Some(stems) = &mut self.stems {
+                stems.add_steps(phase, outputs);
+            }
+        }
+    }
+
+    fn read_sample(&mut self) {
+        let left = self.high_passes[0].filter(self.blips[0].read_sample());
+        let right = self.high_passes[1].filter(self.blips[1].read_sample());
+        self.buffer.push(left, right);
+        if let Some(recording) = &mut self.recording {
+            recording.mix.extend([left, right]);
+            if let (Some(stems), Some(samples)) = (&mut self.stems, &mut recording.stems) {
+                stems.read_sample(samples);
+            }
+        }
+    }
+
+    // each channel's DAC output, -1.0 to 1.0, or None with the DAC off
+    fn channel_outputs(&self) -> [Option<f32>; 4] {
+        let dac = |enabled: bool, value: u8| enabled.then(|| value as f32 / 7.5 - 1.0);
+        [
+            dac(self.pulse1.dac_enabled(), self.pulse1.output()),
+            dac(self.pulse2.dac_enabled(), self.pulse2.output()),
+            dac(self.wave.dac_enabled(), self.wave.output()),
+            dac(self.noise.dac_enabled(), self.noise.output()),
+        ]
+    }
+
+    // NR51 pans each channel, NR50 sets each side's volume from 1/8 to 8/8
+    fn mix(&self) -> (f32, f32) {
+        let panning = self.registers[(NR51 - REGISTERS_START) as usize];
+        let volume = self.registers[(NR50 - REGISTERS_START) as usize];
+        let (mut left, mut right) = (0.0, 0.0);
+        for (i, output) in self.channel_outputs().into_iter().enumerate() {
+            let Some(output) = output else {
+                continue;
+            };
+            if panning & (0x10 << i) != 0 {
+                left += output;
+            }
+            if panning & (0x01 << i) != 0 {
+                right += output;
+            }
+        }
+        let left_volume = ((volume >> 4) & 0x07) as f32 + 1.0;
+        let right_volume = (volume & 0x07) as f32 + 1.0;
+        // four channels at full volume come to 1.0
+        (left * left_volume / 32.0, right * right_volume / 32.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::apu::Apu;
+    use crate::apu::CLOCK_RATE;
+    use std::f64::consts::PI;
+
+    fn powered() -> Apu {
+        let mut apu = Apu::new(48_000);
+        apu.write8(0xFF26, 0x80);
+        apu.write8(0xFF24, 0x77);
+        apu.write8(0xFF25, 0xFF);
+        apu
+    }
+
+    // runs for `cycles` M-cycles with DIV running off the same count
+    fn run(apu: &mut Apu, div: &mut u16, cycles: u32) {
+        for _ in 0..cycles {
+            *div = div.wrapping_add(4);
+            apu.tick(1, *div);
+        }
+    }
+
+    fn drain(apu: &mut Apu) -> Vec<f32> {
+        let mut out = vec![0.0; apu.buffer().len()];
+        let n = apu.buffer_mut().drain(&mut out);
+        out.truncate(n);
+        out
+    }
+
+    #[test]
+    fn test_read_masks() {
+        let mut apu = powered();
+        assert_eq!(apu.read8(0xFF10), 0x80);
+        assert_eq!(apu.read8(0xFF11), 0x3F);
+        apu.write8(0xFF11, 0x80);
+        assert_eq!(apu.read8(0xFF11), 0xBF);
+        apu.write8(0xFF12, 0xF3);
+        assert_eq!(apu.read8(0xFF12), 0xF3);
+        // frequencies are write only
+        apu.write8(0xFF13, 0x12);
+        assert_eq!(apu.read8(0xFF13), 0xFF);
+        assert_eq!(apu.read8(0xFF15), 0xFF);
+        assert_eq!(apu.read8(0xFF27), 0xFF);
+        assert_eq!(apu.read8(0xFF24), 0x77);
+    }
+
+    #[test]
+    fn test_nr52_status() {
+        let mut apu = powered();
+        assert_eq!(apu.read8(0xFF26), 0xF0);
+        apu.write8(0xFF17, 0xF0);
+        apu.write8(0xFF19, 0x80);
+        assert_eq!(apu.read8(0xFF26), 0xF2);
+        apu.write8(0xFF1A, 0x80);
+        apu.write8(0xFF1E, 0x80);
+        assert_eq!(apu.read8(0xFF26), 0xF6);
+    }
+
+    #[test]
+    fn test_power_off() {
+        let mut apu = powered();
+        apu.write8(0xFF30, 0x12);
+        apu.write8(0xFF17, 0xF0);
+        apu.write8(0xFF19, 0x80);
+        apu.write8(0xFF26, 0x00);
+        assert_eq!(apu.read8(0xFF26), 0x70);
+        assert_eq!(apu.read8(0xFF17), 0x00);
+        assert_eq!(apu.read8(0xFF24), 0x00);
+        // locked until it's back on, except wave ram
+        apu.write8(0xFF24, 0x77);
+        assert_eq!(apu.read8(0xFF24), 0x00);
+        assert_eq!(apu.read8(0xFF30), 0x12);
+        apu.write8(0xFF31, 0x34);
+        assert_eq!(apu.read8(0xFF31), 0x34);
+        apu.write8(0xFF26, 0x80);
+        apu.write8(0xFF24, 0x77);
+        assert_eq!(apu.read8(0xFF24), 0x77);
+    }
+
+    #[test]
+    fn test_sample_rate() {
+        let mut apu = powered();
+        let mut div = 0;
+        // a tenth of a second
+        run(&mut apu, &mut div, CLOCK_RATE / 4 / 10);
+        assert!((4799..=4800).contains(&(apu.buffer().len() / 2)));
+        apu.set_sample_rate(22_050);
+        run(&mut apu, &mut div, CLOCK_RATE / 4 / 10);
+        assert!((2204..=2205).contains(&(apu.buffer().len() / 2)));
+    }
+
+    #[test]
+    fn test_length_from_div() {
+        let mut apu = powered();
+        let mut div = 0;
+        // length 62, so 2 length clocks
+        apu.write8(0xFF17, 0xF0);
+        apu.write8(0xFF16, 62);
+        apu.write8(0xFF19, 0xC0);
+        // frame sequencer steps every 2048 M-cycles, lengths every other one
+        run(&mut apu, &mut div, 2048 * 2);
+        assert_eq!(apu.read8(0xFF26) & 0x02, 0x02);
+        run(&mut apu, &mut div, 2048);
+        assert_eq!(apu.read8(0xFF26) & 0x02, 0x00);
+    }
+
+    #[test]
+    fn test_div_write_steps_frame_sequencer() {
+        let mut apu = powered();
+        apu.write8(0xFF17, 0xF0);
+        apu.write8(0xFF16, 63);
+        apu.write8(0xFF19, 0xC0);
+        // bit 12 high, then DIV written
+        apu.tick(1, 0x1000);
+        apu.tick(1, 0x0000);
+        assert_eq!(apu.read8(0xFF26) & 0x02, 0x00);
+    }
+
+    #[test]
+    fn test_square_wave_pitch() {
+        let mut apu = powered();
+        let mut div = 0;
+        // channel 2 at 50% duty: 131072 / (2048 - 1899) = ~880 Hz
+        apu.write8(0xFF16, 0x80);
+        apu.write8(0xFF17, 0xF0);
+        apu.write8(0xFF18, (1899 & 0xFF) as u8);
+        apu.write8(0xFF19, 0x80 | (1899 >> 8) as u8);
+        run(&mut apu, &mut div, CLOCK_RATE / 4);
+        // skipping the ringing ahead of the first step, from silence
+        let left: Vec<f32> = drain(&mut apu).into_iter().step_by(2).skip(100).collect();
+        let rising = left.windows(2).filter(|w| w[0] < 0.0 && w[1] > 0.0).count();
+        assert!((875..=885).contains(&rising), "{}", rising);
+    }
+
+    // the amplitude of the `freq` component, through a hann window so the
+    // loud bins don't leak into the quiet ones
+    fn amplitude(samples: &[f32], rate: u32, freq: f64) -> f64 {
+        let n = samples.len() as f64;
+        let (mut re, mut im, mut weight) = (0.0, 0.0, 0.0);
+        for (i, &sample) in samples.iter().enumerate() {
+            let i = i as f64;
+            let window = 0.5 - 0.5 * (2.0 * PI * i / n).cos();
+            let angle = 2.0 * PI * freq * i / rate as f64;
+            re += sample as f64 * window * angle.cos();
+            im -= sample as f64 * window * angle.sin();
+            weight += window;
+        }
+        2.0 * (re * re + im * im).sqrt() / weight
+    }
+
+    #[test]
+    fn test_square_wave_spectrum() {
+        let mut apu = powered();
+        let mut div = 0;
+        // channel 2 at 50% duty: 131072 / (2048 - 1992) = ~2341 Hz, a
+        // square of +-0.25 after the mixer
+        apu.write8(0xFF16, 0x80);
+        apu.write8(0xFF17, 0xF0);
+        apu.write8(0xFF18, (1992 & 0xFF) as u8);
+        apu.write8(0xFF19, 0x80 | (1992 >> 8) as u8);
+        run(&mut apu, &mut div, CLOCK_RATE / 4);
+        let left: Vec<f32> = drain(&mut apu).into_iter().step_by(2).skip(4800).collect();
+        let f0 = 131_072.0 / 56.0;
+
+        // odd harmonics at 4 / (pi * n) of the square's height, up to
+        // where the resampler starts rolling off
+        for n in [1, 3, 5, 7] {
+            let expected = 0.25 * 4.0 / (PI * n as f64);
+            let measured = amplitude(&left, 48_000, f0 * n as f64);
+            assert!(
+                (measured / expected - 1.0).abs() < 0.05,
+                "{} {}",
+                n,
+                measured
+            );
+        }
+        // no even ones at 50% duty, bar a little from rounding where the
+        // edges land
+        let fundamental = amplitude(&left, 48_000, f0);
+        assert!(amplitude(&left, 48_000, f0 * 2.0) < fundamental * 1e-3);
+
+        // harmonics past nyquist would fold back to |n * f0 - k * 48000|,
+        // well away from any real harmonic. point sampling leaves them at
+        // 1 / n of the fundamental, here they should be gone
+        for n in [21, 23, 25, 41, 43] {
+            let folded = (f0 * n as f64) % 48_000.0;
+            let alias = folded.min(48_000.0 - folded);
+            let measured = amplitude(&left, 48_000, alias);
+            assert!(
+                measured < fundamental * 1e-3,
+                "{} {} {}",
+                n,
+                alias,
+                measured
+            );
+        }
+    }
+
+    #[test]
+    fn test_panning_and_volume() {
+        let mut apu = powered();
+        let mut div = 0;
+        // channel 2 on both sides, the left at half volume
+        apu.write8(0xFF17, 0xF0);
+        apu.write8(0xFF16, 0x80);
+        apu.write8(0xFF19, 0x80);
+        apu.write8(0xFF25, 0x22);
+        apu.write8(0xFF24, 0x37);
+        run(&mut apu, &mut div, 10_000);
+        let samples = drain(&mut apu);
+        assert!(samples.iter().any(|&s| s.abs() > 0.1));
+        for frame in samples.chunks(2) {
+            assert!((frame[0] - frame[1] / 2.0).abs() < 1e-6, "{:?}", frame);
+        }
+        // then on the left only
+        apu.write8(0xFF25, 0x20);
+        run(&mut apu, &mut div, 100_000);
+        let samples = drain(&mut apu);
+        let (left, right): (Vec<f32>, Vec<f32>) =
+            samples.chunks(2).map(|frame| (frame[0], frame[1])).unzip();
+        assert!(left.iter().any(|s| s.abs() > 0.1));
+        // once the capacitor's discharged
+        assert!(right[right.len() / 2..].iter().all(|s| s.abs() < 0.01));
+    }
+
+    #[test]
+    fn test_recording() {
+        let mut apu = powered();
+        let mut div = 0;
+        assert!(apu.take_recording().is_none());
+        apu.start_recording(true);
+        // pulse 2 and noise, everything panned both ways at full volume
+        apu.write8(0xFF16, 0x80);
+        apu.write8(0xFF17, 0xF0);
+        apu.write8(0xFF19, 0x87);
+        apu.write8(0xFF21, 0xF0);
+        apu.write8(0xFF22, 0x21);
+        apu.write8(0xFF23, 0x80);
+        run(&mut apu, &mut div, 20_000);
+
+        let recording = apu.take_recording().unwrap();
+        // the same samples as went to the buffer
+        assert_eq!(recording.mix, drain(&mut apu));
+        let stems = recording.stems.unwrap();
+        assert!(stems
+            .iter()
+            .all(|stem| stem.len() * 2 == recording.mix.len()));
+        assert!(stems[0].iter().chain(&stems[2]).all(|&s| s == 0.0));
+        assert!(stems[1].iter().chain(&stems[3]).any(|&s| s != 0.0));
+        for (i, frame) in recording.mix.chunks(2).enumerate() {
+            let sum: f32 = stems.iter().map(|stem| stem[i]).sum();
+            assert!((frame[0] - sum).abs() < 1e-4, "{} {}", frame[0], sum);
+        }
+
+        // taking it starts over
+        run(&mut apu, &mut div, 1000);
+        let recording = apu.take_recording().unwrap();
+        assert!(recording.mix.len() < 100);
+        assert!(apu.stop_recording().is_some());
+        assert!(apu.take_recording().is_none());
+    }
+
+    #[test]
+    fn test_dac_off_is_silent() {
+        let mut apu = powered();
+        let mut div = 0;
+        run(&mut apu, &mut div, 1000);
+        assert!(drain(&mut apu).iter().all(|&s| s == 0.0));
+    }
+}
@@ -0,0 +1,141 @@
+/*
+ * band-limited step synthesis, the way blip_buf does it. the mix only ever
+ * changes in steps, so instead of point sampling it we add each step as a
+ * windowed sinc impulse spread over the next WIDTH output samples and
+ * integrate, which leaves out everything above the output's nyquist that
+ * point sampling would alias back down.
+ * http://www.slack.net/~ant/bl-synth/
+ */
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::sync::OnceLock;
+
+// output samples each step is spread over, so also the latency (twice)
+const WIDTH: usize = 32;
+const HALF: usize = WIDTH / 2;
+// how finely a step's position between two output samples is resolved
+pub(super) const PHASES: usize = 64;
+// as a fraction of the output sample rate, a little under nyquist to leave
+// room for the window's transition band
+const CUTOFF: f64 = 0.45;
+// steps the impulse is integrated in for each tap
+const SUBDIVISIONS: usize = 16;
+
+#[derive(Debug, Clone)]
+pub(super) struct Blip {
+    // impulses waiting to be integrated, the front one for the next sample
+    deltas: VecDeque<f32>,
+    level: f32,
+}
+
+impl Default for Blip {
+    fn default() -> Self {
+        Blip::new()
+    }
+}
+
+impl Blip {
+    pub(super) fn new() -> Self {
+        Blip {
+            deltas: VecDeque::from(vec![0.0; WIDTH]),
+            level: 0.0,
+        }
+    }
+
+    /*
+     * a step of `delta` `phase` / PHASES of the way from the last sample
+     * read to the next one
+     */
+    pub(super) fn add_delta(&mut self, phase: usize, delta: f32) {
+        let taps = &kernel()[phase * WIDTH..][..WIDTH];
+        for (slot, tap) in self.deltas.iter_mut().zip(taps) {
+            *slot += delta * tap;
+        }
+    }
+
+    pub(super) fn read_sample(&mut self) -> f32 {
+        self.level += self.deltas.pop_front().unwrap_or(0.0);
+        self.deltas.push_back(0.0);
+        self.level
+    }
+}
+
+/*
+ * PHASES rows of WIDTH taps, each summing to 1. tap i is how much the
+ * band-limited step rises between the samples i and i + 1 after the last
+ * one read, so integrating the taps gives back the step exactly. sampling
+ * the impulse itself instead would tilt the response up towards nyquist.
+ * the step is centred HALF samples after where it happened.
+ */
+fn kernel() -> &'static [f32] {
+    static KERNEL: OnceLock<Vec<f32>> = OnceLock::new();
+    KERNEL.get_or_init(|| {
+        let mut kernel = Vec::with_capacity(PHASES * WIDTH);
+        for phase in 0..PHASES {
+            let offset = phase as f64 / PHASES as f64;
+            let row: Vec<f64> = (0..WIDTH)
+                .map(|i| {
+                    let end = i as f64 + 1.0 - offset - HALF as f64;
+                    (0..SUBDIVISIONS)
+                        .map(|s| impulse(end - (s as f64 + 0.5) / SUBDIVISIONS as f64))
+                        .sum::<f64>()
+                })
+                .collect();
+            let sum: f64 = row.iter().sum();
+            kernel.extend(row.iter().map(|tap| (tap / sum) as f32));
+        }
+        kernel
+    })
+}
+
+// a blackman windowed sinc at time `t` in output samples
+fn impulse(t: f64) -> f64 {
+    let sinc = if t == 0.0 {
+        1.0
+    } else {
+        (2.0 * PI * CUTOFF * t).sin() / (2.0 * PI * CUTOFF * t)
+    };
+    let x = PI * t / HALF as f64;
+    let window = 0.42 + 0.5 * x.cos() + 0.08 * (2.0 * x).cos();
+    2.0 * CUTOFF * sinc * window.max(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::apu::blip::Blip;
+    use crate::apu::blip::PHASES;
+
+    #[test]
+    fn test_step_settles() {
+        for phase in [0, 1, PHASES / 2, PHASES - 1] {
+            let mut blip = Blip::new();
+            blip.add_delta(phase, 1.0);
+            let samples: Vec<f32> = (0..64).map(|_| blip.read_sample()).collect();
+            // nothing before the step, then it rises through the middle
+            assert!(samples[..8].iter().all(|s| s.abs() < 0.01), "{}", phase);
+            assert!(samples[13] < 0.5 && samples[17] > 0.5, "{}", phase);
+            assert!(samples[40..].iter().all(|s| (s - 1.0).abs() < 1e-5));
+        }
+    }
+
+    #[test]
+    fn test_later_phase_is_later() {
+        let mut early = Blip::new();
+        let mut late = Blip::new();
+        early.add_delta(0, 1.0);
+        late.add_delta(PHASES - 1, 1.0);
+        let sample = |blip: &mut Blip| (0..16).map(|_| blip.read_sample()).last().unwrap();
+        assert!(sample(&mut early) > sample(&mut late));
+    }
+
+    #[test]
+    fn test_steps_add_up() {
+        let mut blip = Blip::new();
+        blip.add_delta(10, 1.0);
+        blip.read_sample();
+        blip.add_delta(20, -0.25);
+        blip.add_delta(30, 0.5);
+        let last = (0..64).map(|_| blip.read_sample()).last().unwrap();
+        assert!((last - 1.25).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,75 @@
+// interleaved stereo samples waiting for the host. when it falls behind the
+// oldest samples are dropped, better a skip than unbounded latency
+use std::collections::VecDeque;
+
+#[derive(Debug, Clone)]
+pub struct SampleBuffer {
+    samples: VecDeque<f32>,
+    // in samples, so twice the frames
+    capacity: usize,
+}
+
+impl SampleBuffer {
+    pub fn new(capacity: usize) -> Self {
+        SampleBuffer {
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, left: f32, right: f32) {
+        while self.samples.len() + 2 > self.capacity {
+            self.samples.pop_front();
+            self.samples.pop_front();
+        }
+        self.samples.push_back(left);
+        self.samples.push_back(right);
+    }
+
+    // fills `out` with as many whole frames as fit, returns how many
+    // samples (not frames) were written
+    pub fn drain(&mut self, out: &mut [f32]) -> usize {
+        let n = self.samples.len().min(out.len() & !1);
+        for (slot, sample) in out.iter_mut().zip(self.samples.drain(..n)) {
+            *slot = sample;
+        }
+        n
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::apu::buffer::SampleBuffer;
+
+    #[test]
+    fn test_drain() {
+        let mut buffer = SampleBuffer::new(8);
+        buffer.push(0.1, 0.2);
+        buffer.push(0.3, 0.4);
+        let mut out = [0.0; 3];
+        // only whole frames come out
+        assert_eq!(buffer.drain(&mut out), 2);
+        assert_eq!(out, [0.1, 0.2, 0.0]);
+        assert_eq!(buffer.len(), 2);
+    }
+
+    #[test]
+    fn test_overflow_drops_oldest() {
+        let mut buffer = SampleBuffer::new(4);
+        buffer.push(1.0, 1.0);
+        buffer.push(2.0, 2.0);
+        buffer.push(3.0, 3.0);
+        let mut out = [0.0; 8];
+        assert_eq!(buffer.drain(&mut out), 4);
+        assert_eq!(out[..4], [2.0, 2.0, 3.0, 3.0]);
+        assert!(buffer.is_empty());
+    }
+}
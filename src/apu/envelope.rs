@@ -0,0 +1,158 @@
+/*
+ * the length counter and volume envelope shared by the channels, both
+ * clocked by the frame sequencer.
+ * pandocs: https://gbdev.io/pandocs/Audio_details.html
+ */
+
+// silences the channel once it counts down to 0, if enabled
+#[derive(Debug, Clone, Default)]
+pub(super) struct Length {
+    counter: u16,
+    // 64 for most channels, 256 for the wave channel
+    max: u16,
+    enabled: bool,
+}
+
+impl Length {
+    pub(super) fn new(max: u16) -> Self {
+        Length {
+            counter: 0,
+            max,
+            enabled: false,
+        }
+    }
+
+    // the value written to NRx1, counting up from there to max
+    pub(super) fn load(&mut self, v: u16) {
+        self.counter = self.max - v;
+    }
+
+    pub(super) fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub(super) fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+
+    // false once the channel should turn off
+    pub(super) fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter != 0;
+        }
+        true
+    }
+}
+
+// NRx2: initial volume, direction and period
+#[derive(Debug, Clone, Default)]
+pub(super) struct Envelope {
+    register: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    pub(super) fn write(&mut self, v: u8) {
+        self.register = v;
+    }
+
+    // the top 5 bits all clear turns the channel's DAC off
+    pub(super) fn dac_enabled(&self) -> bool {
+        self.register & 0xF8 != 0
+    }
+
+    pub(super) fn volume(&self) -> u8 {
+        self.volume
+    }
+
+    fn period(&self) -> u8 {
+        self.register & 0x07
+    }
+
+    pub(super) fn trigger(&mut self) {
+        self.volume = self.register >> 4;
+        self.timer = self.period();
+    }
+
+    pub(super) fn clock(&mut self) {
+        // a period of 0 stops the envelope
+        if self.period() == 0 || self.timer == 0 {
+            return;
+        }
+        self.timer -= 1;
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = self.period();
+        if self.register & 0x08 != 0 {
+            self.volume = (self.volume + 1).min(15);
+        } else {
+            self.volume = self.volume.saturating_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::apu::envelope::Envelope;
+    use crate::apu::envelope::Length;
+
+    #[test]
+    fn test_length() {
+        let mut length = Length::new(64);
+        length.load(60);
+        // not counting until enabled
+        assert!(length.clock());
+        length.set_enabled(true);
+        for _ in 0..3 {
+            assert!(length.clock());
+        }
+        assert!(!length.clock());
+        // triggering at 0 starts over from the top
+        length.trigger();
+        for _ in 0..63 {
+            assert!(length.clock());
+        }
+        assert!(!length.clock());
+    }
+
+    #[test]
+    fn test_envelope_down() {
+        let mut envelope = Envelope::default();
+        envelope.write(0x32);
+        envelope.trigger();
+        assert_eq!(envelope.volume(), 3);
+        envelope.clock();
+        assert_eq!(envelope.volume(), 3);
+        envelope.clock();
+        assert_eq!(envelope.volume(), 2);
+        for _ in 0..10 {
+            envelope.clock();
+        }
+        assert_eq!(envelope.volume(), 0);
+    }
+
+    #[test]
+    fn test_envelope_up() {
+        let mut envelope = Envelope::default();
+        envelope.write(0xE9);
+        envelope.trigger();
+        envelope.clock();
+        assert_eq!(envelope.volume(), 15);
+        envelope.clock();
+        assert_eq!(envelope.volume(), 15);
+    }
+
+    #[test]
+    fn test_dac() {
+        let mut envelope = Envelope::default();
+        envelope.write(0x08);
+        assert!(envelope.dac_enabled());
+        envelope.write(0x07);
+        assert!(!envelope.dac_enabled());
+    }
+}
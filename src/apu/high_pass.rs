@@ -0,0 +1,62 @@
+// the capacitor on the DMG's audio output, which lets the DC offset of the
+// DACs decay away. the charge rate per T-cycle is from blargg's
+// measurements, as written up in pandocs:
+// https://gbdev.io/pandocs/Audio_details.html#mixer
+use crate::apu::CLOCK_RATE;
+
+const CHARGE_PER_CYCLE: f64 = 0.999958;
+
+#[derive(Debug, Clone)]
+pub(super) struct HighPass {
+    // how much charge is kept from one output sample to the next
+    charge: f32,
+    capacitor: f32,
+}
+
+impl HighPass {
+    pub(super) fn new(sample_rate: u32) -> Self {
+        HighPass {
+            charge: CHARGE_PER_CYCLE.powf(CLOCK_RATE as f64 / sample_rate as f64) as f32,
+            capacitor: 0.0,
+        }
+    }
+
+    pub(super) fn filter(&mut self, input: f32) -> f32 {
+        let output = input - self.capacitor;
+        self.capacitor = input - output * self.charge;
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::apu::high_pass::HighPass;
+
+    #[test]
+    fn test_dc_decays() {
+        let mut filter = HighPass::new(48_000);
+        assert_eq!(filter.filter(1.0), 1.0);
+        // most of it's gone within a tenth of a second
+        let last = (0..48_000 / 10).map(|_| filter.filter(1.0)).last().unwrap();
+        assert!(last.abs() < 0.05, "{}", last);
+        // and taking the input away swings the other way
+        assert!(filter.filter(0.0) < -0.9);
+    }
+
+    #[test]
+    fn test_audio_passes() {
+        let mut filter = HighPass::new(48_000);
+        // a 1 kHz square settles around 0 at its own size
+        let square = |i: usize| if i % 48 < 24 { 1.0 } else { 0.0 };
+        let out: Vec<f32> = (0..48_000).map(|i| filter.filter(square(i))).collect();
+        let tail = &out[out.len() - 480..];
+        let max = tail.iter().cloned().fold(f32::MIN, f32::max);
+        let min = tail.iter().cloned().fold(f32::MAX, f32::min);
+        assert!(
+            (max - 0.5).abs() < 0.05 && (min + 0.5).abs() < 0.05,
+            "{} {}",
+            min,
+            max
+        );
+    }
+}
@@ -0,0 +1,171 @@
+/*
+ * channel 4, pseudo random noise from a 15 bit LFSR. in 7 bit mode the
+ * feedback goes into bit 6 as well, which gives a short, metallic loop.
+ * pandocs: https://gbdev.io/pandocs/Audio_Registers.html#sound-channel-4--noise
+ */
+use crate::apu::envelope::Envelope;
+use crate::apu::envelope::Length;
+
+const DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+#[derive(Debug, Clone)]
+pub(super) struct Noise {
+    enabled: bool,
+    length: Length,
+    envelope: Envelope,
+    // NR43: clock shift, width and divisor
+    polynomial: u8,
+    lfsr: u16,
+    // T-cycles until the LFSR shifts
+    timer: u32,
+}
+
+impl Noise {
+    pub(super) fn new() -> Self {
+        Noise {
+            enabled: false,
+            length: Length::new(64),
+            envelope: Envelope::default(),
+            polynomial: 0,
+            lfsr: 0x7FFF,
+            timer: 0,
+        }
+    }
+
+    pub(super) fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub(super) fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    // NR41 - NR44 as 1 - 4, 0 doesn't exist
+    pub(super) fn write(&mut self, register: u16, v: u8) {
+        match register {
+            0 => {}
+            1 => self.length.load((v & 0x3F) as u16),
+            2 => {
+                self.envelope.write(v);
+                if !self.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.polynomial = v,
+            _ => {
+                self.length.set_enabled(v & 0x40 != 0);
+                if v & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled();
+        self.length.trigger();
+        self.envelope.trigger();
+        self.lfsr = 0x7FFF;
+        self.timer = self.period();
+    }
+
+    fn period(&self) -> u32 {
+        DIVISORS[(self.polynomial & 0x07) as usize] << (self.polynomial >> 4)
+    }
+
+    pub(super) fn tick(&mut self, t_cycles: u32) {
+        let mut left = t_cycles;
+        while left >= self.timer {
+            left -= self.timer;
+            self.timer = self.period();
+            self.shift();
+        }
+        self.timer -= left;
+    }
+
+    fn shift(&mut self) {
+        let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+        self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+        if self.polynomial & 0x08 != 0 {
+            self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+        }
+    }
+
+    pub(super) fn output(&self) -> u8 {
+        if !self.enabled || self.lfsr & 1 != 0 {
+            return 0;
+        }
+        self.envelope.volume()
+    }
+
+    pub(super) fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub(super) fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::apu::noise::Noise;
+
+    fn noise(polynomial: u8) -> Noise {
+        let mut noise = Noise::new();
+        noise.write(2, 0xF0);
+        noise.write(3, polynomial);
+        noise.write(4, 0x80);
+        noise
+    }
+
+    // how many shifts until the LFSR comes back around
+    fn cycle_length(noise: &mut Noise) -> u32 {
+        let start = noise.lfsr;
+        let mut n = 0;
+        loop {
+            noise.shift();
+            n += 1;
+            if noise.lfsr == start || n > 40000 {
+                return n;
+            }
+        }
+    }
+
+    #[test]
+    fn test_lfsr_periods() {
+        assert_eq!(cycle_length(&mut noise(0x00)), 32767);
+        // 7 bit mode settles into a 127 step loop
+        let mut short = noise(0x08);
+        for _ in 0..200 {
+            short.shift();
+        }
+        assert_eq!(cycle_length(&mut short), 127);
+    }
+
+    #[test]
+    fn test_period() {
+        // divisor 48, shifted by 2
+        let mut noise = noise(0x23);
+        let lfsr = noise.lfsr;
+        noise.tick(48 * 4 - 1);
+        assert_eq!(noise.lfsr, lfsr);
+        noise.tick(1);
+        assert_ne!(noise.lfsr, lfsr);
+    }
+
+    #[test]
+    fn test_output() {
+        let mut noise = noise(0x00);
+        // all ones after a trigger, so silent until a 0 shifts down
+        assert_eq!(noise.output(), 0);
+        let mut seen = false;
+        for _ in 0..20 {
+            noise.shift();
+            seen |= noise.output() == 15;
+        }
+        assert!(seen);
+    }
+}
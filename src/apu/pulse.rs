@@ -0,0 +1,298 @@
+/*
+ * channels 1 and 2, square waves with 4 duty cycles. channel 1 also has a
+ * frequency sweep.
+ * pandocs: https://gbdev.io/pandocs/Audio_Registers.html#sound-channel-1--pulse-with-period-sweep
+ */
+use crate::apu::envelope::Envelope;
+use crate::apu::envelope::Length;
+
+// one bit per step, the wave plays from bit 0 up
+const DUTIES: [u8; 4] = [0b1000_0000, 0b1000_0001, 0b1110_0001, 0b0111_1110];
+
+// NR10, only channel 1 has one
+#[derive(Debug, Clone, Default)]
+struct Sweep {
+    register: u8,
+    enabled: bool,
+    timer: u8,
+    shadow: u16,
+}
+
+impl Sweep {
+    fn period(&self) -> u8 {
+        (self.register >> 4) & 0x07
+    }
+
+    fn shift(&self) -> u8 {
+        self.register & 0x07
+    }
+
+    // the next frequency, past 2047 the channel gets switched off
+    fn next(&self) -> u16 {
+        let delta = self.shadow >> self.shift();
+        if self.register & 0x08 != 0 {
+            self.shadow - delta
+        } else {
+            self.shadow + delta
+        }
+    }
+
+    // a period of 0 is treated as 8
+    fn reload(&mut self) {
+        self.timer = match self.period() {
+            0 => 8,
+            p => p,
+        };
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(super) struct Pulse {
+    sweep: Option<Sweep>,
+    enabled: bool,
+    length: Length,
+    envelope: Envelope,
+    duty: u8,
+    step: u8,
+    frequency: u16,
+    // T-cycles until the next duty step
+    timer: u32,
+}
+
+impl Pulse {
+    pub(super) fn new(with_sweep: bool) -> Self {
+        Pulse {
+            sweep: with_sweep.then(Sweep::default),
+            enabled: false,
+            length: Length::new(64),
+            envelope: Envelope::default(),
+            duty: 0,
+            step: 0,
+            frequency: 0,
+            timer: 0,
+        }
+    }
+
+    pub(super) fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub(super) fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    // NRx0 - NRx4 by their index
+    pub(super) fn write(&mut self, register: u16, v: u8) {
+        match register {
+            0 => {
+                if let Some(sweep) = &mut self.sweep {
+                    sweep.register = v;
+                }
+            }
+            1 => {
+                self.duty = v >> 6;
+                self.length.load((v & 0x3F) as u16);
+            }
+            2 => {
+                self.envelope.write(v);
+                if !self.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x700) | v as u16,
+            _ => {
+                self.frequency = (self.frequency & 0xFF) | ((v as u16 & 0x07) << 8);
+                self.length.set_enabled(v & 0x40 != 0);
+                if v & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled();
+        self.length.trigger();
+        self.envelope.trigger();
+        self.timer = self.period();
+        let frequency = self.frequency;
+        if let Some(sweep) = &mut self.sweep {
+            sweep.shadow = frequency;
+            sweep.reload();
+            sweep.enabled = sweep.period() != 0 || sweep.shift() != 0;
+            if sweep.shift() != 0 && sweep.next() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 4
+    }
+
+    pub(super) fn tick(&mut self, t_cycles: u32) {
+        let mut left = t_cycles;
+        while left >= self.timer {
+            left -= self.timer;
+            self.timer = self.period();
+            self.step = (self.step + 1) % 8;
+        }
+        self.timer -= left;
+    }
+
+    // 0 - 15, what goes to the DAC
+    pub(super) fn output(&self) -> u8 {
+        if !self.enabled || DUTIES[self.duty as usize] & (1 << self.step) == 0 {
+            return 0;
+        }
+        self.envelope.volume()
+    }
+
+    pub(super) fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub(super) fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub(super) fn clock_sweep(&mut self) {
+        let Some(sweep) = &mut self.sweep else {
+            return;
+        };
+        if sweep.timer > 0 {
+            sweep.timer -= 1;
+        }
+        if sweep.timer > 0 {
+            return;
+        }
+        sweep.reload();
+        if !sweep.enabled || sweep.period() == 0 {
+            return;
+        }
+        let next = sweep.next();
+        if next > 2047 {
+            self.enabled = false;
+            return;
+        }
+        if sweep.shift() != 0 {
+            sweep.shadow = next;
+            self.frequency = next;
+            // checked again straight away with the new frequency
+            if sweep.next() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::apu::pulse::Pulse;
+
+    // triggered at full volume with `duty` and frequency `frequency`
+    fn pulse(duty: u8, frequency: u16) -> Pulse {
+        let mut pulse = Pulse::new(true);
+        pulse.write(1, duty << 6);
+        pulse.write(2, 0xF0);
+        pulse.write(3, frequency as u8);
+        pulse.write(4, 0x80 | (frequency >> 8) as u8);
+        pulse
+    }
+
+    // one output per duty step
+    fn wave(pulse: &mut Pulse) -> Vec<u8> {
+        let period = (2048 - pulse.frequency as u32) * 4;
+        (0..8)
+            .map(|_| {
+                pulse.tick(period);
+                pulse.output()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_duty_cycles() {
+        for (duty, high) in [(0, 1), (1, 2), (2, 4), (3, 6)] {
+            let mut pulse = pulse(duty, 2000);
+            let wave = wave(&mut pulse);
+            assert_eq!(wave.iter().filter(|&&v| v == 15).count(), high, "{}", duty);
+            assert_eq!(wave.iter().filter(|&&v| v == 0).count(), 8 - high);
+        }
+    }
+
+    #[test]
+    fn test_period() {
+        // 2048 - 2040 = 8, 32 T-cycles a step
+        let mut pulse = pulse(2, 2040);
+        let start = pulse.step;
+        pulse.tick(31);
+        assert_eq!(pulse.step, start);
+        pulse.tick(1);
+        assert_eq!(pulse.step, (start + 1) % 8);
+        pulse.tick(32 * 8);
+        assert_eq!(pulse.step, (start + 1) % 8);
+    }
+
+    #[test]
+    fn test_dac_off_disables() {
+        let mut pulse = pulse(2, 1000);
+        assert!(pulse.enabled());
+        pulse.write(2, 0x00);
+        assert!(!pulse.enabled());
+        // and a trigger doesn't bring it back
+        pulse.write(4, 0x80);
+        assert!(!pulse.enabled());
+    }
+
+    #[test]
+    fn test_length_expires() {
+        let mut pulse = pulse(2, 1000);
+        pulse.write(1, 62);
+        pulse.write(4, 0xC0);
+        pulse.clock_length();
+        assert!(pulse.enabled());
+        pulse.clock_length();
+        assert!(!pulse.enabled());
+    }
+
+    #[test]
+    fn test_sweep_up() {
+        let mut pulse = pulse(2, 0x100);
+        // period 1, shift 1, up
+        pulse.write(0, 0x11);
+        pulse.write(4, 0x80 | 0x01);
+        pulse.clock_sweep();
+        assert_eq!(pulse.frequency, 0x180);
+        pulse.clock_sweep();
+        assert_eq!(pulse.frequency, 0x240);
+    }
+
+    #[test]
+    fn test_sweep_down() {
+        let mut pulse = pulse(2, 0x400);
+        pulse.write(0, 0x1A);
+        pulse.write(4, 0x80 | 0x04);
+        pulse.clock_sweep();
+        assert_eq!(pulse.frequency, 0x300);
+    }
+
+    #[test]
+    fn test_sweep_overflow() {
+        let mut pulse = pulse(2, 0x700);
+        // shift 1 would go past 2047 on the first calculation
+        pulse.write(0, 0x11);
+        pulse.write(4, 0x87);
+        assert!(!pulse.enabled());
+
+        // 0x500 + 0x280 fits but the check after it doesn't
+        pulse.write(3, 0x00);
+        pulse.write(4, 0x85);
+        assert!(pulse.enabled());
+        pulse.clock_sweep();
+        assert_eq!(pulse.frequency, 0x780);
+        assert!(!pulse.enabled());
+    }
+}
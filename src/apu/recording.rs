@@ -0,0 +1,72 @@
+// a copy of what goes to the SampleBuffer, kept for writing out to disk,
+// and optionally each channel on its own
+use std::mem;
+
+use crate::apu::blip::Blip;
+use crate::apu::high_pass::HighPass;
+
+// a channel's share of the mix at 8/8 volume, so with everything panned
+// to both sides at full volume the stems add up to the mix
+const STEM_SCALE: f32 = 8.0 / 32.0;
+
+#[derive(Debug, Clone, Default)]
+pub struct Recording {
+    // interleaved stereo, left first
+    pub mix: Vec<f32>,
+    // mono, one per channel: pulse 1, pulse 2, wave, noise
+    pub stems: Option<[Vec<f32>; 4]>,
+}
+
+impl Recording {
+    pub(super) fn new(stems: bool) -> Self {
+        Recording {
+            mix: Vec::new(),
+            stems: stems.then(Default::default),
+        }
+    }
+
+    // everything so far, leaving this one empty to carry on
+    pub(super) fn take(&mut self) -> Recording {
+        Recording {
+            mix: mem::take(&mut self.mix),
+            stems: self
+                .stems
+                .as_mut()
+                .map(|stems| stems.each_mut().map(mem::take)),
+        }
+    }
+}
+
+// the same resampling as the mix, for each channel before panning
+#[derive(Debug, Clone)]
+pub(super) struct Stems {
+    blips: [Blip; 4],
+    high_passes: [HighPass; 4],
+    levels: [f32; 4],
+}
+
+impl Stems {
+    pub(super) fn new(sample_rate: u32) -> Self {
+        Stems {
+            blips: Default::default(),
+            high_passes: [(); 4].map(|_| HighPass::new(sample_rate)),
+            levels: [0.0; 4],
+        }
+    }
+
+    pub(super) fn add_steps(&mut self, phase: usize, outputs: [Option<f32>; 4]) {
+        for (i, output) in outputs.into_iter().enumerate() {
+            let level = output.unwrap_or(0.0) * STEM_SCALE;
+            if level != self.levels[i] {
+                self.blips[i].add_delta(phase, level - self.levels[i]);
+                self.levels[i] = level;
+            }
+        }
+    }
+
+    pub(super) fn read_sample(&mut self, stems: &mut [Vec<f32>; 4]) {
+        for (i, stem) in stems.iter_mut().enumerate() {
+            stem.push(self.high_passes[i].filter(self.blips[i].read_sample()));
+        }
+    }
+}
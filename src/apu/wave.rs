@@ -0,0 +1,192 @@
+/*
+ * channel 3 plays back 32 4-bit samples from wave ram, 0xFF30 - 0xFF3F,
+ * high nibble first.
+ * pandocs: https://gbdev.io/pandocs/Audio_Registers.html#sound-channel-3--wave-output
+ */
+use crate::apu::envelope::Length;
+
+pub(super) const WAVE_RAM_SIZE: usize = 16;
+
+#[derive(Debug, Clone)]
+pub(super) struct Wave {
+    enabled: bool,
+    dac_enabled: bool,
+    length: Length,
+    // NR32 bits 5-6: mute, full, half, quarter
+    level: u8,
+    frequency: u16,
+    // 0 - 31 into wave ram
+    position: u8,
+    // T-cycles until the next sample
+    timer: u32,
+    ram: [u8; WAVE_RAM_SIZE],
+}
+
+impl Wave {
+    pub(super) fn new() -> Self {
+        Wave {
+            enabled: false,
+            dac_enabled: false,
+            length: Length::new(256),
+            level: 0,
+            frequency: 0,
+            position: 0,
+            timer: 0,
+            ram: [0; WAVE_RAM_SIZE],
+        }
+    }
+
+    // powering the apu off clears everything but wave ram
+    pub(super) fn power_off(&mut self) {
+        let ram = self.ram;
+        *self = Wave::new();
+        self.ram = ram;
+    }
+
+    pub(super) fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub(super) fn dac_enabled(&self) -> bool {
+        self.dac_enabled
+    }
+
+    pub(super) fn read_ram(&self, index: usize) -> u8 {
+        self.ram[index]
+    }
+
+    pub(super) fn write_ram(&mut self, index: usize, v: u8) {
+        self.ram[index] = v;
+    }
+
+    // NR30 - NR34 by their index
+    pub(super) fn write(&mut self, register: u16, v: u8) {
+        match register {
+            0 => {
+                self.dac_enabled = v & 0x80 != 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.load(v as u16),
+            2 => self.level = (v >> 5) & 0x03,
+            3 => self.frequency = (self.frequency & 0x700) | v as u16,
+            _ => {
+                self.frequency = (self.frequency & 0xFF) | ((v as u16 & 0x07) << 8);
+                self.length.set_enabled(v & 0x40 != 0);
+                if v & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.position = 0;
+        self.timer = self.period();
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 2
+    }
+
+    pub(super) fn tick(&mut self, t_cycles: u32) {
+        let mut left = t_cycles;
+        while left >= self.timer {
+            left -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) % 32;
+        }
+        self.timer -= left;
+    }
+
+    pub(super) fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        let byte = self.ram[self.position as usize / 2];
+        let sample = if self.position.is_multiple_of(2) {
+            byte >> 4
+        } else {
+            byte & 0x0F
+        };
+        match self.level {
+            0 => 0,
+            level => sample >> (level - 1),
+        }
+    }
+
+    pub(super) fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::apu::wave::Wave;
+
+    // a ramp 0, 1, .. 15, 0, 1, .. 15 playing at full volume
+    fn ramp() -> Wave {
+        let mut wave = Wave::new();
+        for i in 0..16 {
+            let lo = (i * 2) % 16;
+            wave.write_ram(i, (lo << 4 | (lo + 1)) as u8);
+        }
+        wave.write(0, 0x80);
+        wave.write(2, 0x20);
+        wave.write(3, 0x00);
+        wave.write(4, 0x87);
+        wave
+    }
+
+    #[test]
+    fn test_playback() {
+        let mut wave = ramp();
+        let period = (2048 - 0x700) * 2;
+        // the first sample played is the second nibble, like hardware
+        let samples: Vec<u8> = (0..32)
+            .map(|_| {
+                wave.tick(period);
+                wave.output()
+            })
+            .collect();
+        let expected: Vec<u8> = (1..33).map(|i| i % 16).collect();
+        assert_eq!(samples, expected);
+    }
+
+    #[test]
+    fn test_output_level() {
+        let mut wave = ramp();
+        wave.tick((2048 - 0x700) * 2 * 15);
+        assert_eq!(wave.output(), 15);
+        for (level, expected) in [(0x00, 0), (0x40, 7), (0x60, 3)] {
+            wave.write(2, level);
+            assert_eq!(wave.output(), expected);
+        }
+    }
+
+    #[test]
+    fn test_dac() {
+        let mut wave = ramp();
+        assert!(wave.enabled());
+        wave.write(0, 0x00);
+        assert!(!wave.enabled());
+        wave.write(4, 0x80);
+        assert!(!wave.enabled());
+    }
+
+    #[test]
+    fn test_length() {
+        let mut wave = ramp();
+        wave.write(1, 0xFE);
+        wave.write(4, 0xC7);
+        wave.clock_length();
+        assert!(wave.enabled());
+        wave.clock_length();
+        assert!(!wave.enabled());
+    }
+}
@@ -0,0 +1,514 @@
+/*
+ * the cpu only ever sees memory through a Bus.
+ * pandocs memory map: https://gbdev.io/pandocs/Memory_Map.html
+ */
+use crate::apu::Apu;
+use crate::cartridge::Cartridge;
+use crate::cartridge::CartridgeError;
+use crate::interrupts::Interrupt;
+use crate::interrupts::IF_ADDRESS;
+use crate::joypad::Button;
+use crate::joypad::Joypad;
+use crate::joypad::P1_ADDRESS;
+use crate::mbc;
+use crate::mbc::CartridgeEvent;
+use crate::mbc::Mbc;
+use crate::mbc::RomOnly;
+use crate::ppu::Ppu;
+use crate::timer::Timer;
+
+pub trait Bus {
+    fn read8(&self, address: u16) -> u8;
+    fn write8(&mut self, address: u16, v: u8);
+
+    // 16bit values are little endian, the low byte lives at `address`
+    fn read16(&self, address: u16) -> u16 {
+        let lo = self.read8(address);
+        let hi = self.read8(address.wrapping_add(1));
+        u16::from_le_bytes([lo, hi])
+    }
+
+    fn write16(&mut self, address: u16, v: u16) {
+        let [lo, hi] = v.to_le_bytes();
+        self.write8(address, lo);
+        self.write8(address.wrapping_add(1), hi);
+    }
+
+    // called by the cpu with the M-cycles that have passed so anything
+    // hanging off the bus can catch up
+    fn tick(&mut self, _cycles: u8) {}
+}
+
+// region sizes
+const WRAM_SIZE: usize = 0x2000;
+const IO_SIZE: usize = 0x80;
+const HRAM_SIZE: usize = 0x7F;
+
+pub const DMA_ADDRESS: u16 = 0xFF46;
+// one byte a M-cycle
+const DMA_LENGTH: u8 = 0xA0;
+
+/*
+ * OAM DMA copies 0xXX00 - 0xXX9F into oam, one byte each M-cycle, after a
+ * write of XX to 0xFF46. the copy has the bus to itself while it runs.
+ * pandocs: https://gbdev.io/pandocs/OAM_DMA_Transfer.html
+ */
+#[derive(Debug, Clone, Copy)]
+struct OamDma {
+    source: u16,
+    // bytes copied so far
+    copied: u8,
+}
+
+/*
+ * the default bus, routing each region of the address space:
+ * 0x0000 - 0x7FFF  cartridge rom, writes go to its mbc
+ * 0x8000 - 0x9FFF  vram, in the ppu
+ * 0xA000 - 0xBFFF  cartridge ram, through the mbc
+ * 0xC000 - 0xDFFF  wram
+ * 0xE000 - 0xFDFF  echo of 0xC000 - 0xDDFF
+ * 0xFE00 - 0xFE9F  oam, in the ppu
+ * 0xFEA0 - 0xFEFF  unusable, reads 0 and ignores writes
+ * 0xFF00 - 0xFF7F  io registers, 0xFF00 is the joypad, 0xFF04 - 0xFF07
+ *                  belong to the timer, 0xFF10 - 0xFF3F to the apu and
+ *                  0xFF40 - 0xFF4B to the ppu
+ * 0xFF80 - 0xFFFE  hram
+ * 0xFFFF           interrupt enable
+ * while an OAM DMA runs only 0xFF00 - 0xFFFF can be reached, everything
+ * else reads 0xFF and ignores writes
+ */
+#[derive(Debug)]
+pub struct MemoryMap {
+    cartridge: Box<dyn Mbc>,
+    ppu: Ppu,
+    timer: Timer,
+    joypad: Joypad,
+    apu: Apu,
+    wram: [u8; WRAM_SIZE],
+    io: [u8; IO_SIZE],
+    hram: [u8; HRAM_SIZE],
+    ie: u8,
+    dma: Option<OamDma>,
+}
+
+impl MemoryMap {
+    // a bare rom with no header or mbc, mapped straight in
+    pub fn new(rom: Vec<u8>) -> Self {
+        MemoryMap::with_mbc(Box::new(RomOnly::new(rom, 0)))
+    }
+
+    pub fn from_cartridge(cartridge: Cartridge) -> Result<Self, CartridgeError> {
+        Ok(MemoryMap::with_mbc(mbc::from_cartridge(cartridge)?))
+    }
+
+    pub fn with_mbc(cartridge: Box<dyn Mbc>) -> Self {
+        MemoryMap::with_ppu(cartridge, Ppu::new())
+    }
+
+    // for picking the ppu's renderer
+    pub fn with_ppu(cartridge: Box<dyn Mbc>, ppu: Ppu) -> Self {
+        MemoryMap {
+            cartridge,
+            ppu,
+            timer: Timer::new(),
+            joypad: Joypad::new(),
+            apu: Apu::default(),
+            wram: [0; WRAM_SIZE],
+            io: [0; IO_SIZE],
+            hram: [0; HRAM_SIZE],
+            ie: 0,
+            dma: None,
+        }
+    }
+
+    pub fn ppu(&self) -> &Ppu {
+        &self.ppu
+    }
+
+    pub fn ppu_mut(&mut self) -> &mut Ppu {
+        &mut self.ppu
+    }
+
+    // the cartridge's battery backed state, see Mbc::save_data
+    pub fn save_data(&mut self) -> Option<Vec<u8>> {
+        self.cartridge.save_data()
+    }
+
+    pub fn load_save_data(&mut self, data: &[u8]) {
+        self.cartridge.load_save_data(data);
+    }
+
+    pub fn cartridge_ram_enabled(&self) -> bool {
+        self.cartridge.ram_enabled()
+    }
+
+    // see Mbc::poll_event
+    pub fn poll_event(&mut self) -> Option<CartridgeEvent> {
+        self.cartridge.poll_event()
+    }
+
+    pub fn timer(&self) -> &Timer {
+        &self.timer
+    }
+
+    pub fn apu(&self) -> &Apu {
+        &self.apu
+    }
+
+    pub fn apu_mut(&mut self) -> &mut Apu {
+        &mut self.apu
+    }
+
+    pub fn joypad(&self) -> &Joypad {
+        &self.joypad
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        self.joypad.set_button(button, pressed);
+        self.io[(IF_ADDRESS - 0xFF00) as usize] |= self.joypad.take_interrupts();
+    }
+
+    pub fn dma_active(&self) -> bool {
+        self.dma.is_some()
+    }
+
+    // the memory map as the dma sees it, nothing blocked
+    fn read_direct(&self, address: u16) -> u8 {
+        let a = address as usize;
+        match address {
+            0x0000..=0x7FFF => self.cartridge.read_rom(address),
+            0x8000..=0x9FFF => self.ppu.read8(address),
+            0xA000..=0xBFFF => self.cartridge.read_ram(address - 0xA000),
+            0xC000..=0xDFFF => self.wram[a - 0xC000],
+            0xE000..=0xFDFF => self.wram[a - 0xE000],
+            0xFE00..=0xFE9F => self.ppu.read8(address),
+            0xFEA0..=0xFEFF => 0x00,
+            P1_ADDRESS => self.joypad.read8(),
+            0xFF04..=0xFF07 => self.timer.read8(address),
+            0xFF10..=0xFF3F => self.apu.read8(address),
+            // only 5 bits of IF exist, the rest read high
+            IF_ADDRESS => self.io[a - 0xFF00] | 0xE0,
+            0xFF40..=0xFF45 | 0xFF47..=0xFF4B => self.ppu.read8(address),
+            0xFF01..=0xFF7F => self.io[a - 0xFF00],
+            0xFF80..=0xFFFE => self.hram[a - 0xFF80],
+            0xFFFF => self.ie,
+        }
+    }
+
+    fn step_dma(&mut self) {
+        let Some(mut dma) = self.dma else {
+            return;
+        };
+        // sources past 0xDF00 land in echo ram
+        let source = dma.source + dma.copied as u16;
+        let source = if source >= 0xE000 {
+            source - 0x2000
+        } else {
+            source
+        };
+        let v = self.read_direct(source);
+        self.ppu.write8(0xFE00 + dma.copied as u16, v);
+        dma.copied += 1;
+        self.dma = (dma.copied < DMA_LENGTH).then_some(dma);
+    }
+
+    // sets the source's bit in IF, the cpu picks it up on its next step
+    pub fn request_interrupt(&mut self, interrupt: Interrupt) {
+        self.io[(IF_ADDRESS - 0xFF00) as usize] |= interrupt.bit();
+    }
+}
+
+impl Bus for MemoryMap {
+    fn read8(&self, address: u16) -> u8 {
+        if self.dma.is_some() && address < 0xFF00 {
+            return 0xFF;
+        }
+        self.read_direct(address)
+    }
+
+    fn write8(&mut self, address: u16, v: u8) {
+        if self.dma.is_some() && address < 0xFF00 {
+            return;
+        }
+        let a = address as usize;
+        match address {
+            0x0000..=0x7FFF => self.cartridge.write_rom(address, v),
+            0x8000..=0x9FFF => self.ppu.write8(address, v),
+            0xA000..=0xBFFF => self.cartridge.write_ram(address - 0xA000, v),
+            0xC000..=0xDFFF => self.wram[a - 0xC000] = v,
+            0xE000..=0xFDFF => self.wram[a - 0xE000] = v,
+            0xFE00..=0xFE9F => self.ppu.write8(address, v),
+            0xFEA0..=0xFEFF => {}
+            P1_ADDRESS => {
+                self.joypad.write8(v);
+                self.io[(IF_ADDRESS - 0xFF00) as usize] |= self.joypad.take_interrupts();
+            }
+            0xFF04..=0xFF07 => self.timer.write8(address, v),
+            0xFF10..=0xFF3F => self.apu.write8(address, v),
+            0xFF40..=0xFF45 | 0xFF47..=0xFF4B => self.ppu.write8(address, v),
+            DMA_ADDRESS => {
+                self.io[a - 0xFF00] = v;
+                // a write mid-transfer starts over from the new source
+                self.dma = Some(OamDma {
+                    source: (v as u16) << 8,
+                    copied: 0,
+                });
+            }
+            0xFF01..=0xFF7F => self.io[a - 0xFF00] = v,
+            0xFF80..=0xFFFE => self.hram[a - 0xFF80] = v,
+            0xFFFF => self.ie = v,
+        }
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            self.step_dma();
+        }
+        let raised = self.ppu.tick(cycles) | self.timer.tick(cycles);
+        self.io[(IF_ADDRESS - 0xFF00) as usize] |= raised;
+        self.apu.tick(cycles, self.timer.counter());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bus::Bus;
+    use crate::bus::MemoryMap;
+    use crate::cartridge::tests::make_rom;
+    use crate::cartridge::Cartridge;
+    use crate::cpu::Cpu;
+    use crate::interrupts::Interrupt;
+    use crate::joypad::Button;
+    use crate::mbc::CartridgeEvent;
+    use crate::register_bank::Register;
+    use crate::register_bank::Register16;
+
+    #[test]
+    fn test_rom_is_read_only() {
+        let mut bus = MemoryMap::new(vec![0x12, 0x34]);
+        bus.write8(0x0000, 0xFF);
+        assert_eq!(bus.read8(0x0000), 0x12);
+        assert_eq!(bus.read16(0x0000), 0x3412);
+        assert_eq!(bus.read8(0x7FFF), 0xFF);
+    }
+
+    #[test]
+    fn test_regions_round_trip() {
+        let mut bus = MemoryMap::new(vec![]);
+        for address in [
+            0x8000, 0x9FFF, 0xC000, 0xDFFF, 0xFE00, 0xFF42, 0xFF80, 0xFFFE, 0xFFFF,
+        ] {
+            bus.write8(address, 0x5A);
+            assert_eq!(bus.read8(address), 0x5A, "{:#06X}", address);
+        }
+    }
+
+    #[test]
+    fn test_echo_ram_mirrors_wram() {
+        let mut bus = MemoryMap::new(vec![]);
+        bus.write8(0xC123, 0xAB);
+        assert_eq!(bus.read8(0xE123), 0xAB);
+        bus.write8(0xFDFF, 0xCD);
+        assert_eq!(bus.read8(0xDDFF), 0xCD);
+    }
+
+    #[test]
+    fn test_unusable_region() {
+        let mut bus = MemoryMap::new(vec![]);
+        bus.write8(0xFEA0, 0xFF);
+        assert_eq!(bus.read8(0xFEA0), 0x00);
+    }
+
+    #[test]
+    fn test_write16_little_endian() {
+        let mut bus = MemoryMap::new(vec![]);
+        bus.write16(0xC000, 0xBEEF);
+        assert_eq!(bus.read8(0xC000), 0xEF);
+        assert_eq!(bus.read8(0xC001), 0xBE);
+    }
+
+    #[test]
+    fn test_request_interrupt() {
+        let mut bus = MemoryMap::new(vec![]);
+        assert_eq!(bus.read8(0xFF0F), 0xE0);
+        bus.request_interrupt(Interrupt::Timer);
+        bus.request_interrupt(Interrupt::VBlank);
+        assert_eq!(bus.read8(0xFF0F), 0xE5);
+        bus.write8(0xFF0F, 0x00);
+        assert_eq!(bus.read8(0xFF0F), 0xE0);
+    }
+
+    #[test]
+    fn test_ppu_raises_vblank() {
+        let mut bus = MemoryMap::new(vec![]);
+        bus.write8(0xFF40, 0x80);
+        for _ in 0..144 * 114 {
+            bus.tick(1);
+        }
+        assert_eq!(bus.read8(0xFF44), 144);
+        assert_eq!(bus.read8(0xFF0F), 0xE1);
+    }
+
+    #[test]
+    fn test_oam_dma() {
+        let mut bus = MemoryMap::new(vec![]);
+        for i in 0..0xA0 {
+            bus.write8(0xC100 + i, i as u8 + 1);
+        }
+        bus.write8(0xFF80, 0x42);
+        bus.write8(0xFF46, 0xC1);
+        assert_eq!(bus.read8(0xFF46), 0xC1);
+        assert!(bus.dma_active());
+
+        bus.tick(80);
+        // the cpu only gets at 0xFF00 and up, oam reads 0xFF
+        assert_eq!(bus.read8(0xFE00), 0xFF);
+        assert_eq!(bus.read8(0xC100), 0xFF);
+        bus.write8(0xC000, 0x12);
+        assert_eq!(bus.read8(0xFF80), 0x42);
+        // half way there
+        assert_eq!(bus.ppu().read8(0xFE4F), 0x50);
+        assert_eq!(bus.ppu().read8(0xFE50), 0x00);
+
+        bus.tick(80);
+        assert!(!bus.dma_active());
+        assert_eq!(bus.read8(0xFE00), 0x01);
+        assert_eq!(bus.read8(0xFE9F), 0xA0);
+        assert_eq!(bus.read8(0xC000), 0x00);
+    }
+
+    #[test]
+    fn test_oam_dma_runs_with_the_cpu() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100..0x0104].copy_from_slice(&[
+            0x3E, 0xC0, // LD A,0xC0
+            0xE0, 0x46, // LDH (0x46),A
+        ]);
+        let mut bus = MemoryMap::new(rom);
+        for i in 0..0xA0 {
+            bus.write8(0xC000 + i, 0xAA);
+        }
+        let mut cpu = Cpu::new(bus);
+        cpu.registers_mut().write16(Register16::PC, 0x0100);
+        cpu.step().unwrap();
+        assert_eq!(cpu.step().unwrap(), 3);
+        // the write is on LDH's last M-cycle, copying starts on the next
+        assert!(cpu.bus().dma_active());
+        assert_eq!(cpu.bus().ppu().read8(0xFE00), 0x00);
+        cpu.bus_mut().tick(3);
+        let ppu = cpu.bus().ppu();
+        assert_eq!(ppu.read8(0xFE02), 0xAA);
+        assert_eq!(ppu.read8(0xFE03), 0x00);
+        // and the next fetch from rom is cut off
+        assert_eq!(cpu.bus().read8(0x0104), 0xFF);
+    }
+
+    #[test]
+    fn test_timer_sees_accesses_on_their_cycle() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100..0x010C].copy_from_slice(&[
+            0x3E, 0x05, // LD A,0x05
+            0xE0, 0x07, // LDH (0x07),A, TIMA every 4 M-cycles
+            0xE0, 0x04, // LDH (0x04),A, reset DIV
+            0xF0, 0x05, // LDH A,(0x05), 3 M-cycles after the reset
+            0x47, // LD B,A
+            0xFA, 0x05, 0xFF, // LD A,(0xFF05), 8 M-cycles after it
+        ]);
+        let mut cpu = Cpu::new(MemoryMap::new(rom));
+        cpu.registers_mut().write16(Register16::PC, 0x0100);
+        for _ in 0..6 {
+            cpu.step().unwrap();
+        }
+        // TIMA went up 4 and 8 M-cycles after the reset. ticking the bus
+        // after the whole instruction would read it a cycle early and miss
+        // the second one
+        let registers = cpu.registers();
+        let before = registers.read(Register::B);
+        assert_eq!(registers.read(Register::A), before.wrapping_add(2));
+    }
+
+    #[test]
+    fn test_timer_wakes_halt() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100..0x010D].copy_from_slice(&[
+            0x3E, 0x04, // LD A,0x04
+            0xE0, 0xFF, // LDH (0xFF),A   IE = timer
+            0x3E, 0xF0, // LD A,0xF0
+            0xE0, 0x05, // LDH (0x05),A   TIMA
+            0x3E, 0x05, // LD A,0x05
+            0xE0, 0x07, // LDH (0x07),A   TAC, every 4 M-cycles
+            0x76, // HALT
+        ]);
+        let mut cpu = Cpu::new(MemoryMap::new(rom));
+        cpu.registers_mut().write16(Register16::PC, 0x0100);
+        for _ in 0..7 {
+            cpu.step().unwrap();
+        }
+        assert!(cpu.is_halted());
+        let mut cycles = 0;
+        while cpu.is_halted() {
+            cycles += cpu.step().unwrap() as u32;
+        }
+        // 16 increments and the reload, less what the HALT itself took
+        assert!((60..=66).contains(&cycles), "{}", cycles);
+        assert_eq!(cpu.bus().read8(0xFF0F) & 0x04, 0x04);
+    }
+
+    #[test]
+    fn test_joypad() {
+        let mut bus = MemoryMap::new(vec![]);
+        bus.write8(0xFF00, 0x10);
+        bus.set_button(Button::Start, true);
+        assert_eq!(bus.read8(0xFF00), 0xD7);
+        assert_eq!(bus.read8(0xFF0F), 0xF0);
+    }
+
+    #[test]
+    fn test_apu_length_runs_off_div() {
+        let mut bus = MemoryMap::new(vec![]);
+        bus.write8(0xFF26, 0x80);
+        bus.write8(0xFF17, 0xF0);
+        bus.write8(0xFF16, 0x3F);
+        bus.write8(0xFF19, 0xC0);
+        assert_eq!(bus.read8(0xFF26), 0xF2);
+        // the first frame sequencer step comes when DIV bit 4 falls
+        for _ in 0..2048 {
+            bus.tick(1);
+        }
+        assert_eq!(bus.read8(0xFF26), 0xF0);
+    }
+
+    #[test]
+    fn test_cartridge_banking() {
+        // MBC1+RAM, 64KiB rom, 8KiB ram
+        let mut rom = make_rom("TEST", 0x02, 0x01, 0x02);
+        rom[0x8000] = 0xBB;
+        crate::cartridge::tests::fix_checksums(&mut rom);
+        let cart = Cartridge::from_bytes(rom).unwrap();
+        let mut bus = MemoryMap::from_cartridge(cart).unwrap();
+        assert_eq!(bus.read8(0x4000), 0x00);
+        bus.write8(0x2000, 0x02);
+        assert_eq!(bus.read8(0x4000), 0xBB);
+
+        assert_eq!(bus.read8(0xA000), 0xFF);
+        bus.write8(0x0000, 0x0A);
+        bus.write8(0xA000, 0x5A);
+        assert_eq!(bus.read8(0xA000), 0x5A);
+    }
+
+    #[test]
+    fn test_rumble_events_reach_the_bus() {
+        // MBC5+RUMBLE
+        let cart = Cartridge::from_bytes(make_rom("TEST", 0x1C, 0x00, 0x00)).unwrap();
+        let mut bus = MemoryMap::from_cartridge(cart).unwrap();
+        bus.write8(0x4000, 0x08);
+        assert_eq!(bus.poll_event(), Some(CartridgeEvent::Rumble(true)));
+        assert_eq!(bus.poll_event(), None);
+    }
+
+    #[test]
+    fn test_unsupported_mbc() {
+        let cart = Cartridge::from_bytes(make_rom("TEST", 0xFC, 0x00, 0x00)).unwrap();
+        assert!(MemoryMap::from_cartridge(cart).is_err());
+    }
+}
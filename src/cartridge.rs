@@ -0,0 +1,487 @@
+/*
+ * pandocs: https://gbdev.io/pandocs/The_Cartridge_Header.html
+ * the header lives at 0x0100 - 0x014F of every rom
+ */
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const HEADER_END: usize = 0x0150;
+const TITLE: usize = 0x0134;
+const CGB_FLAG: usize = 0x0143;
+const NEW_LICENSEE: usize = 0x0144;
+const SGB_FLAG: usize = 0x0146;
+const CARTRIDGE_TYPE: usize = 0x0147;
+const ROM_SIZE: usize = 0x0148;
+const RAM_SIZE: usize = 0x0149;
+const DESTINATION: usize = 0x014A;
+const OLD_LICENSEE: usize = 0x014B;
+const VERSION: usize = 0x014C;
+const HEADER_CHECKSUM: usize = 0x014D;
+const GLOBAL_CHECKSUM: usize = 0x014E;
+
+#[derive(Debug)]
+pub enum CartridgeError {
+    Io(io::Error),
+    // not even long enough to hold the header
+    TooShort(usize),
+    // shorter than the rom size the header declares
+    Truncated { expected: usize, actual: usize },
+    UnknownCartridgeType(u8),
+    UnknownRomSize(u8),
+    UnknownRamSize(u8),
+    HeaderChecksum { expected: u8, actual: u8 },
+    // the header parsed fine but there's no emulation for its mbc
+    UnsupportedMbc(MbcType),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CartridgeError::Io(e) => write!(f, "couldn't read rom: {}", e),
+            CartridgeError::TooShort(len) => {
+                write!(f, "rom is {} bytes, too short to hold a header", len)
+            }
+            CartridgeError::Truncated { expected, actual } => write!(
+                f,
+                "rom is truncated: header says {} bytes but there are {}",
+                expected, actual
+            ),
+            CartridgeError::UnknownCartridgeType(code) => {
+                write!(f, "unknown cartridge type {:#04X}", code)
+            }
+            CartridgeError::UnknownRomSize(code) => write!(f, "unknown rom size {:#04X}", code),
+            CartridgeError::UnknownRamSize(code) => write!(f, "unknown ram size {:#04X}", code),
+            CartridgeError::HeaderChecksum { expected, actual } => write!(
+                f,
+                "header checksum mismatch: expected {:#04X}, got {:#04X}",
+                expected, actual
+            ),
+            CartridgeError::UnsupportedMbc(mbc) => write!(f, "{:?} isn't supported", mbc),
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
+
+impl From<io::Error> for CartridgeError {
+    fn from(e: io::Error) -> Self {
+        CartridgeError::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CgbSupport {
+    None,
+    // runs on both, with colour on a CGB
+    Enhanced,
+    Only,
+}
+
+// the memory bank controller on the cartridge, if there is one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MbcType {
+    RomOnly,
+    Mbc1,
+    Mbc2,
+    Mmm01,
+    Mbc3,
+    Mbc5,
+    Mbc6,
+    Mbc7,
+    PocketCamera,
+    Tama5,
+    HuC3,
+    HuC1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CartridgeType {
+    pub code: u8,
+    pub mbc: MbcType,
+    pub ram: bool,
+    pub battery: bool,
+    pub timer: bool,
+    pub rumble: bool,
+}
+
+impl CartridgeType {
+    pub fn from_code(code: u8) -> Result<CartridgeType, CartridgeError> {
+        // (mbc, ram, battery, timer, rumble)
+        let (mbc, ram, battery, timer, rumble) = match code {
+            0x00 => (MbcType::RomOnly, false, false, false, false),
+            0x01 => (MbcType::Mbc1, false, false, false, false),
+            0x02 => (MbcType::Mbc1, true, false, false, false),
+            0x03 => (MbcType::Mbc1, true, true, false, false),
+            // MBC2 ram is built into the controller, so it's always there
+            0x05 => (MbcType::Mbc2, true, false, false, false),
+            0x06 => (MbcType::Mbc2, true, true, false, false),
+            0x08 => (MbcType::RomOnly, true, false, false, false),
+            0x09 => (MbcType::RomOnly, true, true, false, false),
+            0x0B => (MbcType::Mmm01, false, false, false, false),
+            0x0C => (MbcType::Mmm01, true, false, false, false),
+            0x0D => (MbcType::Mmm01, true, true, false, false),
+            0x0F => (MbcType::Mbc3, false, true, true, false),
+            0x10 => (MbcType::Mbc3, true, true, true, false),
+            0x11 => (MbcType::Mbc3, false, false, false, false),
+            0x12 => (MbcType::Mbc3, true, false, false, false),
+            0x13 => (MbcType::Mbc3, true, true, false, false),
+            0x19 => (MbcType::Mbc5, false, false, false, false),
+            0x1A => (MbcType::Mbc5, true, false, false, false),
+            0x1B => (MbcType::Mbc5, true, true, false, false),
+            0x1C => (MbcType::Mbc5, false, false, false, true),
+            0x1D => (MbcType::Mbc5, true, false, false, true),
+            0x1E => (MbcType::Mbc5, true, true, false, true),
+            0x20 => (MbcType::Mbc6, false, false, false, false),
+            0x22 => (MbcType::Mbc7, true, true, false, true),
+            0xFC => (MbcType::PocketCamera, false, false, false, false),
+            0xFD => (MbcType::Tama5, false, false, false, false),
+            0xFE => (MbcType::HuC3, false, false, false, false),
+            0xFF => (MbcType::HuC1, true, true, false, false),
+            _ => return Err(CartridgeError::UnknownCartridgeType(code)),
+        };
+        Ok(CartridgeType {
+            code,
+            mbc,
+            ram,
+            battery,
+            timer,
+            rumble,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Licensee {
+    // 0x014B, anything but 0x33
+    Old(u8),
+    // 0x0144 - 0x0145, two ascii characters
+    New(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub title: String,
+    pub cgb: CgbSupport,
+    pub sgb: bool,
+    pub cartridge_type: CartridgeType,
+    pub rom_size: usize,
+    pub ram_size: usize,
+    pub japanese: bool,
+    pub licensee: Licensee,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+}
+
+impl Header {
+    // parses the header without checking any checksums
+    pub fn parse(rom: &[u8]) -> Result<Header, CartridgeError> {
+        if rom.len() < HEADER_END {
+            return Err(CartridgeError::TooShort(rom.len()));
+        }
+        let cgb = match rom[CGB_FLAG] {
+            0x80 => CgbSupport::Enhanced,
+            0xC0 => CgbSupport::Only,
+            _ => CgbSupport::None,
+        };
+        // newer carts took the end of the title for the manufacturer
+        // code and cgb flag, the title stops at the first NUL either way
+        let title_end = if cgb == CgbSupport::None {
+            0x0144
+        } else {
+            CGB_FLAG
+        };
+        let title = rom[TITLE..title_end]
+            .iter()
+            .take_while(|&&c| c != 0)
+            .map(|&c| {
+                if c.is_ascii_graphic() || c == b' ' {
+                    c as char
+                } else {
+                    '?'
+                }
+            })
+            .collect::<String>()
+            .trim_end()
+            .to_string();
+        let rom_size = match rom[ROM_SIZE] {
+            code @ 0x00..=0x08 => 0x8000 << code,
+            code => return Err(CartridgeError::UnknownRomSize(code)),
+        };
+        let ram_size = match rom[RAM_SIZE] {
+            0x00 | 0x01 => 0,
+            0x02 => 0x2000,
+            0x03 => 0x8000,
+            0x04 => 0x20000,
+            0x05 => 0x10000,
+            code => return Err(CartridgeError::UnknownRamSize(code)),
+        };
+        let licensee = match rom[OLD_LICENSEE] {
+            0x33 => Licensee::New(
+                String::from_utf8_lossy(&rom[NEW_LICENSEE..NEW_LICENSEE + 2]).into_owned(),
+            ),
+            code => Licensee::Old(code),
+        };
+        Ok(Header {
+            title,
+            cgb,
+            sgb: rom[SGB_FLAG] == 0x03,
+            cartridge_type: CartridgeType::from_code(rom[CARTRIDGE_TYPE])?,
+            rom_size,
+            ram_size,
+            japanese: rom[DESTINATION] == 0x00,
+            licensee,
+            version: rom[VERSION],
+            header_checksum: rom[HEADER_CHECKSUM],
+            global_checksum: u16::from_be_bytes([rom[GLOBAL_CHECKSUM], rom[GLOBAL_CHECKSUM + 1]]),
+        })
+    }
+}
+
+impl fmt::Display for Header {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let t = &self.cartridge_type;
+        let mut features = vec![format!("{:?}", t.mbc)];
+        for (has, name) in [
+            (t.ram, "RAM"),
+            (t.battery, "BATTERY"),
+            (t.timer, "TIMER"),
+            (t.rumble, "RUMBLE"),
+        ] {
+            if has {
+                features.push(name.to_string());
+            }
+        }
+        let licensee = match &self.licensee {
+            Licensee::Old(code) => format!("{:#04X}", code),
+            Licensee::New(code) => code.clone(),
+        };
+        writeln!(f, "title:     {}", self.title)?;
+        writeln!(f, "type:      {:#04X} ({})", t.code, features.join("+"))?;
+        writeln!(f, "rom size:  {} KiB", self.rom_size / 1024)?;
+        writeln!(f, "ram size:  {} KiB", self.ram_size / 1024)?;
+        writeln!(f, "cgb:       {:?}", self.cgb)?;
+        writeln!(f, "sgb:       {}", self.sgb)?;
+        writeln!(
+            f,
+            "region:    {}",
+            if self.japanese { "Japan" } else { "Overseas" }
+        )?;
+        writeln!(f, "licensee:  {}", licensee)?;
+        writeln!(f, "version:   {}", self.version)?;
+        write!(
+            f,
+            "checksums: header {:#04X}, global {:#06X}",
+            self.header_checksum, self.global_checksum
+        )
+    }
+}
+
+// the boot rom refuses to start a cart whose header doesn't add up
+pub fn header_checksum(rom: &[u8]) -> u8 {
+    rom[TITLE..HEADER_CHECKSUM]
+        .iter()
+        .fold(0u8, |x, &b| x.wrapping_sub(b).wrapping_sub(1))
+}
+
+// the sum of every byte but the checksum itself. nothing on hardware checks this
+pub fn global_checksum(rom: &[u8]) -> u16 {
+    rom.iter()
+        .enumerate()
+        .filter(|(i, _)| *i != GLOBAL_CHECKSUM && *i != GLOBAL_CHECKSUM + 1)
+        .fold(0u16, |sum, (_, &b)| sum.wrapping_add(b as u16))
+}
+
+#[derive(Debug, Clone)]
+pub struct Cartridge {
+    header: Header,
+    rom: Vec<u8>,
+    // what the rom actually sums to, see global_checksum_ok
+    global_checksum: u16,
+}
+
+impl Cartridge {
+    pub fn load(path: impl AsRef<Path>) -> Result<Cartridge, CartridgeError> {
+        let rom = fs::read(path)?;
+        Cartridge::from_bytes(rom)
+    }
+
+    pub fn from_bytes(rom: Vec<u8>) -> Result<Cartridge, CartridgeError> {
+        let header = Header::parse(&rom)?;
+        if rom.len() < header.rom_size {
+            return Err(CartridgeError::Truncated {
+                expected: header.rom_size,
+                actual: rom.len(),
+            });
+        }
+        let actual = header_checksum(&rom);
+        if actual != header.header_checksum {
+            return Err(CartridgeError::HeaderChecksum {
+                expected: header.header_checksum,
+                actual,
+            });
+        }
+        let global_checksum = global_checksum(&rom);
+        Ok(Cartridge {
+            header,
+            rom,
+            global_checksum,
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    // hacks, translations and homebrew often don't fix this up, and since
+    // hardware doesn't care neither do we beyond reporting it
+    pub fn global_checksum_ok(&self) -> bool {
+        self.global_checksum == self.header.global_checksum
+    }
+
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    pub fn into_rom(self) -> Vec<u8> {
+        self.rom
+    }
+}
+
+// the header summary, plus what the rom really sums to if the header's
+// global checksum is off
+impl fmt::Display for Cartridge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.header)?;
+        if !self.global_checksum_ok() {
+            write!(f, " (mismatch, rom sums to {:#06X})", self.global_checksum)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::cartridge::*;
+
+    // a blank rom, sized by rom_code, with a valid header
+    pub fn make_rom(title: &str, cartridge_type: u8, rom_code: u8, ram_code: u8) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000 << rom_code];
+        rom[TITLE..TITLE + title.len()].copy_from_slice(title.as_bytes());
+        rom[CARTRIDGE_TYPE] = cartridge_type;
+        rom[ROM_SIZE] = rom_code;
+        rom[RAM_SIZE] = ram_code;
+        fix_checksums(&mut rom);
+        rom
+    }
+
+    pub fn fix_checksums(rom: &mut [u8]) {
+        rom[HEADER_CHECKSUM] = header_checksum(rom);
+        let [hi, lo] = global_checksum(rom).to_be_bytes();
+        rom[GLOBAL_CHECKSUM] = hi;
+        rom[GLOBAL_CHECKSUM + 1] = lo;
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let mut rom = make_rom("POKEMON RED", 0x13, 0x05, 0x03);
+        rom[OLD_LICENSEE] = 0x33;
+        rom[NEW_LICENSEE..NEW_LICENSEE + 2].copy_from_slice(b"01");
+        rom[SGB_FLAG] = 0x03;
+        rom[DESTINATION] = 0x01;
+        rom[VERSION] = 1;
+        fix_checksums(&mut rom);
+        let cart = Cartridge::from_bytes(rom).unwrap();
+        let header = cart.header();
+        assert_eq!(header.title, "POKEMON RED");
+        assert_eq!(header.cgb, CgbSupport::None);
+        assert!(header.sgb);
+        assert_eq!(header.cartridge_type.mbc, MbcType::Mbc3);
+        assert!(header.cartridge_type.ram);
+        assert!(header.cartridge_type.battery);
+        assert!(!header.cartridge_type.timer);
+        assert_eq!(header.rom_size, 1024 * 1024);
+        assert_eq!(header.ram_size, 32 * 1024);
+        assert!(!header.japanese);
+        assert_eq!(header.licensee, Licensee::New("01".to_string()));
+        assert_eq!(header.version, 1);
+    }
+
+    #[test]
+    fn test_cgb_title() {
+        let mut rom = make_rom("ABCDEFGHIJKLMNO", 0x00, 0x00, 0x00);
+        rom[CGB_FLAG] = 0xC0;
+        fix_checksums(&mut rom);
+        let header = Header::parse(&rom).unwrap();
+        assert_eq!(header.cgb, CgbSupport::Only);
+        assert_eq!(header.title, "ABCDEFGHIJKLMNO");
+        assert_eq!(header.licensee, Licensee::Old(0x00));
+    }
+
+    #[test]
+    fn test_too_short() {
+        assert!(matches!(
+            Cartridge::from_bytes(vec![0; 0x100]),
+            Err(CartridgeError::TooShort(0x100))
+        ));
+    }
+
+    #[test]
+    fn test_truncated() {
+        let mut rom = make_rom("TEST", 0x01, 0x01, 0x00);
+        rom.truncate(0x8000);
+        assert!(matches!(
+            Cartridge::from_bytes(rom),
+            Err(CartridgeError::Truncated {
+                expected: 0x10000,
+                actual: 0x8000
+            })
+        ));
+    }
+
+    #[test]
+    fn test_header_checksum() {
+        let mut rom = make_rom("TEST", 0x00, 0x00, 0x00);
+        rom[VERSION] ^= 0xFF;
+        assert!(matches!(
+            Cartridge::from_bytes(rom),
+            Err(CartridgeError::HeaderChecksum { .. })
+        ));
+    }
+
+    #[test]
+    fn test_global_checksum() {
+        let mut rom = make_rom("TEST", 0x00, 0x00, 0x00);
+        let cartridge = Cartridge::from_bytes(rom.clone()).unwrap();
+        assert!(cartridge.global_checksum_ok());
+        assert!(!cartridge.to_string().contains("mismatch"));
+        // a mismatch still loads, it's only reported
+        rom[0x4000] = 0x42;
+        let cartridge = Cartridge::from_bytes(rom).unwrap();
+        assert!(!cartridge.global_checksum_ok());
+        let summary = cartridge.to_string();
+        assert!(
+            summary.ends_with("(mismatch, rom sums to 0x0229)"),
+            "{}",
+            summary
+        );
+    }
+
+    #[test]
+    fn test_unknown_codes() {
+        let mut rom = make_rom("TEST", 0x00, 0x00, 0x00);
+        rom[CARTRIDGE_TYPE] = 0x04;
+        assert!(matches!(
+            Header::parse(&rom),
+            Err(CartridgeError::UnknownCartridgeType(0x04))
+        ));
+        rom[CARTRIDGE_TYPE] = 0x00;
+        rom[ROM_SIZE] = 0x52;
+        assert!(matches!(
+            Header::parse(&rom),
+            Err(CartridgeError::UnknownRomSize(0x52))
+        ));
+    }
+}
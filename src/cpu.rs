@@ -1,42 +1,1803 @@
+use crate::bus::Bus;
+use crate::bus::MemoryMap;
 use crate::instruction::ArithmeticTarget;
+use crate::instruction::ByteTarget;
+use crate::instruction::Condition;
+use crate::instruction::DecodeError;
+use crate::instruction::Indirect;
 use crate::instruction::Instruction;
+use crate::instruction::LoadType;
+use crate::instruction::StackTarget;
+use crate::instruction::WideTarget;
+use crate::interrupts::Interrupt;
+use crate::interrupts::IE_ADDRESS;
+use crate::interrupts::IF_ADDRESS;
 use crate::register_bank::Register;
+use crate::register_bank::Register16;
 use crate::register_bank::RegisterBank;
 
-#[derive(Debug, Clone, Copy)]
-struct Cpu {
+// (address, value) for the io registers the boot rom leaves set
+const POST_BOOT_IO: [(u16, u8); 3] = [
+    (0xFF40, 0x91), // LCDC: lcd and background on
+    (0xFF47, 0xFC), // BGP
+    (0xFF0F, 0xE1), // IF: vblank left pending
+];
+
+#[derive(Debug, Clone)]
+pub struct Cpu<B: Bus = MemoryMap> {
     registers: RegisterBank,
+    bus: B,
+    // interrupt master enable
+    ime: bool,
+    // EI turns IME on only after the instruction that follows it
+    ei_pending: bool,
+    halted: bool,
+    // HALT with IME off and an interrupt already pending doesn't halt,
+    // instead the next opcode fetch fails to move PC along
+    halt_bug: bool,
+    stopped: bool,
+    // M-cycles the bus has been ticked so far this step
+    ticked: u8,
 }
 
-impl Cpu {
-    pub fn exec(&mut self, ins: Instruction) {
+impl<B: Bus> Cpu<B> {
+    pub fn new(bus: B) -> Self {
+        Cpu {
+            registers: RegisterBank::default(),
+            bus,
+            ime: false,
+            ei_pending: false,
+            halted: false,
+            halt_bug: false,
+            stopped: false,
+            ticked: 0,
+        }
+    }
+
+    /*
+     * puts the cpu in the state the DMG boot rom leaves it in, for running
+     * a cartridge without one. the io registers the boot rom touches are
+     * set through the bus.
+     * pandocs: https://gbdev.io/pandocs/Power_Up_Sequence.html
+     */
+    pub fn skip_boot_rom(&mut self) {
+        self.registers.write16(Register16::AF, 0x01B0);
+        self.registers.write16(Register16::BC, 0x0013);
+        self.registers.write16(Register16::DE, 0x00D8);
+        self.registers.write16(Register16::HL, 0x014D);
+        self.registers.write16(Register16::SP, 0xFFFE);
+        self.registers.write16(Register16::PC, 0x0100);
+        for (address, v) in POST_BOOT_IO {
+            self.bus.write8(address, v);
+        }
+    }
+
+    pub fn registers(&self) -> &RegisterBank {
+        &self.registers
+    }
+
+    pub fn registers_mut(&mut self) -> &mut RegisterBank {
+        &mut self.registers
+    }
+
+    pub fn bus(&self) -> &B {
+        &self.bus
+    }
+
+    pub fn bus_mut(&mut self) -> &mut B {
+        &mut self.bus
+    }
+
+    pub fn ime(&self) -> bool {
+        self.ime
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /*
+     * runs one instruction at PC: fetch, decode, execute. the bus ticks
+     * before every memory access rather than once at the end, so a write
+     * lands on the M-cycle it does on hardware and a read sees the timer,
+     * ppu and dma as they are by then. returns the M-cycles it took.
+     * before fetching, a pending interrupt either wakes a halted cpu or,
+     * with IME set, gets dispatched instead of running an instruction.
+     */
+    pub fn step(&mut self) -> Result<u8, DecodeError> {
+        self.ticked = 0;
+        if self.stopped {
+            // STOP stops the clock too, so the bus doesn't tick. only a
+            // joypad line going low brings it back
+            if self.bus.read8(IF_ADDRESS) & Interrupt::Joypad.bit() == 0 {
+                return Ok(1);
+            }
+            self.stopped = false;
+        }
+
+        let pending = self.pending_interrupts();
+        if pending != 0 {
+            // a pending interrupt ends HALT whether or not IME is set
+            self.halted = false;
+            if self.ime {
+                let cycles = self.dispatch_interrupt(pending);
+                self.tick_rest(cycles);
+                return Ok(cycles);
+            }
+        }
+        if self.halted {
+            self.tick();
+            return Ok(1);
+        }
+
+        if self.ei_pending {
+            self.ei_pending = false;
+            self.ime = true;
+        }
+
+        let pc = self.registers.read16(Register16::PC);
+        let opcode = self.read8(pc);
+        // after the HALT bug the opcode byte gets read a second time
+        // as if PC had moved on, so everything after it is off by one
+        let operand_pc = if self.halt_bug {
+            pc
+        } else {
+            pc.wrapping_add(1)
+        };
+        // peeked to decode, the cycles they take to fetch are ticked after.
+        // STOP's padding byte is skipped over without a fetch
+        let operands = [
+            self.bus.read8(operand_pc),
+            self.bus.read8(operand_pc.wrapping_add(1)),
+        ];
+        let decoded = Instruction::decode(opcode, &operands)?;
+        for _ in 1..decoded.length.min(decoded.cycles) {
+            self.tick();
+        }
+        let mut next_pc = pc.wrapping_add(decoded.length as u16);
+        if self.halt_bug {
+            self.halt_bug = false;
+            next_pc = next_pc.wrapping_sub(1);
+        }
+        // PC points past the instruction while it runs, JR and CALL rely on it
+        self.registers.write16(Register16::PC, next_pc);
+        let cycles = decoded.cycles + self.exec(decoded.instruction);
+        self.tick_rest(cycles);
+        Ok(cycles)
+    }
+
+    // an M-cycle with nothing on the bus
+    fn tick(&mut self) {
+        self.bus.tick(1);
+        self.ticked += 1;
+    }
+
+    // the internal cycles not ticked yet. they all come at the end except
+    // for RET cc and pushes, which tick theirs up front
+    fn tick_rest(&mut self, cycles: u8) {
+        debug_assert!(self.ticked <= cycles, "{} > {}", self.ticked, cycles);
+        self.bus.tick(cycles.saturating_sub(self.ticked));
+        self.ticked = cycles;
+    }
+
+    // an M-cycle with a read or a write in it
+    fn read8(&mut self, address: u16) -> u8 {
+        self.tick();
+        self.bus.read8(address)
+    }
+
+    fn write8(&mut self, address: u16, v: u8) {
+        self.tick();
+        self.bus.write8(address, v);
+    }
+
+    fn pending_interrupts(&self) -> u8 {
+        self.bus.read8(IE_ADDRESS) & self.bus.read8(IF_ADDRESS) & 0x1F
+    }
+
+    // acknowledges the highest priority interrupt and calls its vector.
+    // that's two wait states, two pushes and the jump: 5 M-cycles. IF is
+    // cleared straight away rather than on the cycle it would be
+    fn dispatch_interrupt(&mut self, pending: u8) -> u8 {
+        let Some(interrupt) = Interrupt::highest_priority(pending) else {
+            return 0;
+        };
+        self.ime = false;
+        let flags = self.bus.read8(IF_ADDRESS);
+        self.bus.write8(IF_ADDRESS, flags & !interrupt.bit());
+        let pc = self.registers.read16(Register16::PC);
+        // the first wait state, push's own idle cycle is the second
+        self.tick();
+        self.push(pc);
+        self.registers.write16(Register16::PC, interrupt.vector());
+        5
+    }
+
+    // returns any M-cycles on top of the decoded cost, which is only
+    // ever the extra time a conditional branch takes when it's taken.
+    // memory accesses tick the bus as they go, see step
+    pub fn exec(&mut self, ins: Instruction) -> u8 {
         match ins {
-            Instruction::Add(target) => match target {
-                ArithmeticTarget::A => self.add(Register::A),
-                ArithmeticTarget::B => self.add(Register::B),
-                ArithmeticTarget::C => self.add(Register::C),
-                ArithmeticTarget::D => self.add(Register::D),
-                ArithmeticTarget::E => self.add(Register::E),
-                ArithmeticTarget::H => self.add(Register::H),
-                ArithmeticTarget::L => self.add(Register::L),
-            },
+            Instruction::Nop => {}
+            Instruction::Di => {
+                self.ime = false;
+                self.ei_pending = false;
+            }
+            Instruction::Ei => self.ei_pending = true,
+            Instruction::Halt => {
+                if !self.ime && self.pending_interrupts() != 0 {
+                    self.halt_bug = true;
+                } else {
+                    self.halted = true;
+                }
+            }
+            Instruction::Stop => self.stopped = true,
+            Instruction::Jp(..)
+            | Instruction::JpHl
+            | Instruction::Jr(..)
+            | Instruction::Call(..)
+            | Instruction::Ret(..)
+            | Instruction::Rst(..) => return self.control_flow(ins),
+            Instruction::Reti => {
+                // RET that turns interrupts back on straight away
+                let pc = self.pop();
+                self.registers.write16(Register16::PC, pc);
+                self.ime = true;
+            }
+            Instruction::Add(target) => {
+                let v = self.read_arithmetic_target(target);
+                self.add(v, false)
+            }
+            Instruction::Adc(target) => {
+                let v = self.read_arithmetic_target(target);
+                self.add(v, self.registers.has_carry_bit())
+            }
+            Instruction::Sub(target) => {
+                let v = self.read_arithmetic_target(target);
+                let new_v = self.sub(v, false);
+                self.registers.write_register(Register::A, new_v);
+            }
+            Instruction::Sbc(target) => {
+                let v = self.read_arithmetic_target(target);
+                let new_v = self.sub(v, self.registers.has_carry_bit());
+                self.registers.write_register(Register::A, new_v);
+            }
+            Instruction::Cp(target) => {
+                // a subtraction that only keeps the flags
+                let v = self.read_arithmetic_target(target);
+                self.sub(v, false);
+            }
+            Instruction::And(target) => {
+                let v = self.read_arithmetic_target(target);
+                self.logic(v, |a, v| a & v, true)
+            }
+            Instruction::Xor(target) => {
+                let v = self.read_arithmetic_target(target);
+                self.logic(v, |a, v| a ^ v, false)
+            }
+            Instruction::Or(target) => {
+                let v = self.read_arithmetic_target(target);
+                self.logic(v, |a, v| a | v, false)
+            }
+            Instruction::Inc(target) => self.modify_byte_target(target, Self::inc),
+            Instruction::Dec(target) => self.modify_byte_target(target, Self::dec),
+            Instruction::AddHl(target) => {
+                let v = self.registers.read16(wide_register(target));
+                let hl = self.registers.read_hl();
+                let (new_v, overflow) = hl.overflowing_add(v);
+                // zero is left alone, half-carry is out of bit 11
+                self.registers.set_subtraction_bit(false);
+                self.registers
+                    .set_half_carry_bit((hl & 0xFFF) + (v & 0xFFF) > 0xFFF);
+                self.registers.set_carry_bit(overflow);
+                self.registers.write_hl(new_v);
+            }
+            Instruction::Inc16(target) => {
+                // no flags for 16bit inc/dec
+                let reg = wide_register(target);
+                let v = self.registers.read16(reg);
+                self.registers.write16(reg, v.wrapping_add(1));
+            }
+            Instruction::Dec16(target) => {
+                let reg = wide_register(target);
+                let v = self.registers.read16(reg);
+                self.registers.write16(reg, v.wrapping_sub(1));
+            }
+            Instruction::AddSp(offset) => {
+                let new_sp = self.sp_plus_offset(offset);
+                self.registers.write16(Register16::SP, new_sp);
+            }
+            Instruction::Ld(load) => self.load(load),
+            Instruction::Push(target) => {
+                let v = self.registers.read16(stack_register(target));
+                self.push(v);
+            }
+            Instruction::Pop(target) => {
+                let v = self.pop();
+                self.registers.write16(stack_register(target), v);
+            }
+            Instruction::Rlc(target) => self.modify_byte_target(target, Self::rlc),
+            Instruction::Rrc(target) => self.modify_byte_target(target, Self::rrc),
+            Instruction::Rl(target) => self.modify_byte_target(target, Self::rl),
+            Instruction::Rr(target) => self.modify_byte_target(target, Self::rr),
+            Instruction::Sla(target) => self.modify_byte_target(target, Self::sla),
+            Instruction::Sra(target) => self.modify_byte_target(target, Self::sra),
+            Instruction::Srl(target) => self.modify_byte_target(target, Self::srl),
+            Instruction::Swap(target) => self.modify_byte_target(target, Self::swap),
+            Instruction::Bit(bit, target) => {
+                let v = self.read_byte_target(target);
+                // carry is left alone
+                self.registers.set_zero_bit(v & (1 << bit) == 0);
+                self.registers.set_subtraction_bit(false);
+                self.registers.set_half_carry_bit(true);
+            }
+            Instruction::Res(bit, target) => {
+                let v = self.read_byte_target(target);
+                self.write_byte_target(target, v & !(1 << bit));
+            }
+            Instruction::Set(bit, target) => {
+                let v = self.read_byte_target(target);
+                self.write_byte_target(target, v | (1 << bit));
+            }
+            // the accumulator rotates are the CB ones on A, except zero
+            // is always cleared
+            Instruction::Rlca => self.rotate_a(Self::rlc),
+            Instruction::Rrca => self.rotate_a(Self::rrc),
+            Instruction::Rla => self.rotate_a(Self::rl),
+            Instruction::Rra => self.rotate_a(Self::rr),
+            Instruction::Daa => self.daa(),
+            Instruction::Cpl => {
+                let a = self.registers.read(Register::A);
+                self.registers.write_register(Register::A, !a);
+                self.registers.set_subtraction_bit(true);
+                self.registers.set_half_carry_bit(true);
+            }
+            Instruction::Scf => {
+                self.registers.set_subtraction_bit(false);
+                self.registers.set_half_carry_bit(false);
+                self.registers.set_carry_bit(true);
+            }
+            Instruction::Ccf => {
+                let carry = self.registers.has_carry_bit();
+                self.registers.set_subtraction_bit(false);
+                self.registers.set_half_carry_bit(false);
+                self.registers.set_carry_bit(!carry);
+            }
+        }
+        0
+    }
+
+    fn control_flow(&mut self, ins: Instruction) -> u8 {
+        let pc = self.registers.read16(Register16::PC);
+        // (condition, where to go, extra cycles if a condition passes)
+        let (condition, target, extra) = match ins {
+            Instruction::Jp(condition, address) => (condition, address, 1),
+            Instruction::JpHl => (None, self.registers.read_hl(), 0),
+            Instruction::Jr(condition, offset) => {
+                (condition, pc.wrapping_add_signed(offset as i16), 1)
+            }
+            Instruction::Call(condition, address) => (condition, address, 3),
+            Instruction::Ret(condition) => (condition, 0, 3),
+            Instruction::Rst(vector) => (None, vector as u16, 0),
+            _ => unreachable!("{:?} isn't a branch", ins),
+        };
+        if !self.condition_met(condition) {
+            return 0;
+        }
+        let target = match ins {
+            Instruction::Call(..) | Instruction::Rst(..) => {
+                self.push(pc);
+                target
+            }
+            Instruction::Ret(condition) => {
+                // RET cc checks its condition in a cycle of its own first
+                if condition.is_some() {
+                    self.tick();
+                }
+                self.pop()
+            }
+            _ => target,
+        };
+        self.registers.write16(Register16::PC, target);
+        // the decoded cost already covers unconditional branches
+        if condition.is_some() {
+            extra
+        } else {
+            0
+        }
+    }
+
+    fn condition_met(&self, condition: Option<Condition>) -> bool {
+        match condition {
+            None => true,
+            Some(Condition::NZ) => !self.registers.has_zero_bit(),
+            Some(Condition::Z) => self.registers.has_zero_bit(),
+            Some(Condition::NC) => !self.registers.has_carry_bit(),
+            Some(Condition::C) => self.registers.has_carry_bit(),
+        }
+    }
+
+    fn load(&mut self, load: LoadType) {
+        match load {
+            LoadType::Byte(target, source) => {
+                let v = self.read_arithmetic_target(source);
+                self.write_byte_target(target, v);
+            }
+            LoadType::Word(target, v) => self.registers.write16(wide_register(target), v),
+            LoadType::AFromIndirect(indirect) => {
+                let address = self.indirect_address(indirect);
+                let v = self.read8(address);
+                self.registers.write_register(Register::A, v);
+            }
+            LoadType::IndirectFromA(indirect) => {
+                let address = self.indirect_address(indirect);
+                self.write8(address, self.registers.read(Register::A));
+            }
+            LoadType::AFromAddress(address) => {
+                let v = self.read8(address);
+                self.registers.write_register(Register::A, v);
+            }
+            LoadType::AddressFromA(address) => {
+                self.write8(address, self.registers.read(Register::A));
+            }
+            // LDH addresses the 0xFF00 page, where io and hram live
+            LoadType::AFromHighPage(offset) => {
+                let v = self.read8(0xFF00 | offset as u16);
+                self.registers.write_register(Register::A, v);
+            }
+            LoadType::HighPageFromA(offset) => {
+                let a = self.registers.read(Register::A);
+                self.write8(0xFF00 | offset as u16, a);
+            }
+            LoadType::AFromHighC => {
+                let address = 0xFF00 | self.registers.read(Register::C) as u16;
+                let v = self.read8(address);
+                self.registers.write_register(Register::A, v);
+            }
+            LoadType::HighCFromA => {
+                let address = 0xFF00 | self.registers.read(Register::C) as u16;
+                self.write8(address, self.registers.read(Register::A));
+            }
+            LoadType::AddressFromSp(address) => {
+                let [lo, hi] = self.registers.read16(Register16::SP).to_le_bytes();
+                self.write8(address, lo);
+                self.write8(address.wrapping_add(1), hi);
+            }
+            LoadType::SpFromHl => {
+                let hl = self.registers.read_hl();
+                self.registers.write16(Register16::SP, hl);
+            }
+            LoadType::HlFromSpOffset(offset) => {
+                let v = self.sp_plus_offset(offset);
+                self.registers.write_hl(v);
+            }
         }
     }
 
     // helpers
-    fn add(&mut self, reg: Register) {
-        // get value from reg, overflowing_add, write to A
-        let v = self.registers.read(reg);
+
+    // the address an (rr) load goes through. (HL+) and (HL-) move HL
+    // along after the access
+    fn indirect_address(&mut self, indirect: Indirect) -> u16 {
+        match indirect {
+            Indirect::BC => self.registers.read_bc(),
+            Indirect::DE => self.registers.read_de(),
+            Indirect::HLInc => {
+                let hl = self.registers.read_hl();
+                self.registers.write_hl(hl.wrapping_add(1));
+                hl
+            }
+            Indirect::HLDec => {
+                let hl = self.registers.read_hl();
+                self.registers.write_hl(hl.wrapping_sub(1));
+                hl
+            }
+        }
+    }
+
+    fn read_arithmetic_target(&mut self, target: ArithmeticTarget) -> u8 {
+        match target {
+            ArithmeticTarget::A => self.registers.read(Register::A),
+            ArithmeticTarget::B => self.registers.read(Register::B),
+            ArithmeticTarget::C => self.registers.read(Register::C),
+            ArithmeticTarget::D => self.registers.read(Register::D),
+            ArithmeticTarget::E => self.registers.read(Register::E),
+            ArithmeticTarget::H => self.registers.read(Register::H),
+            ArithmeticTarget::L => self.registers.read(Register::L),
+            ArithmeticTarget::HLI => self.read8(self.registers.read_hl()),
+            ArithmeticTarget::D8(v) => v,
+        }
+    }
+
+    fn read_byte_target(&mut self, target: ByteTarget) -> u8 {
+        self.read_arithmetic_target(target.into())
+    }
+
+    fn write_byte_target(&mut self, target: ByteTarget, v: u8) {
+        let reg = match target {
+            ByteTarget::A => Register::A,
+            ByteTarget::B => Register::B,
+            ByteTarget::C => Register::C,
+            ByteTarget::D => Register::D,
+            ByteTarget::E => Register::E,
+            ByteTarget::H => Register::H,
+            ByteTarget::L => Register::L,
+            ByteTarget::HLI => {
+                self.write8(self.registers.read_hl(), v);
+                return;
+            }
+        };
+        self.registers.write_register(reg, v);
+    }
+
+    // read, run op over the value and write the result back
+    fn modify_byte_target(&mut self, target: ByteTarget, op: fn(&mut Self, u8) -> u8) {
+        let v = self.read_byte_target(target);
+        let new_v = op(self, v);
+        self.write_byte_target(target, new_v);
+    }
+
+    // the stack grows down, SP points at the last byte pushed. a push
+    // takes a cycle to move SP, then writes the high byte first
+    fn push(&mut self, v: u16) {
+        let [lo, hi] = v.to_le_bytes();
+        let sp = self.registers.read16(Register16::SP);
+        self.tick();
+        self.write8(sp.wrapping_sub(1), hi);
+        self.write8(sp.wrapping_sub(2), lo);
+        self.registers.write16(Register16::SP, sp.wrapping_sub(2));
+    }
+
+    fn pop(&mut self) -> u16 {
+        let sp = self.registers.read16(Register16::SP);
+        let lo = self.read8(sp);
+        let hi = self.read8(sp.wrapping_add(1));
+        self.registers.write16(Register16::SP, sp.wrapping_add(2));
+        u16::from_le_bytes([lo, hi])
+    }
+
+    /*
+     * shared by ADD SP,e8 and LD HL,SP+e8. the offset is signed but the
+     * flags come from an unsigned add of the low byte of SP and the
+     * offset, so half-carry is out of bit 3 and carry out of bit 7.
+     * zero and subtraction are always cleared.
+     */
+    fn sp_plus_offset(&mut self, offset: i8) -> u16 {
+        let sp = self.registers.read16(Register16::SP);
+        let unsigned = offset as u8 as u16;
+        let half_carry = (sp & 0xF) + (unsigned & 0xF) > 0xF;
+        let carry = (sp & 0xFF) + unsigned > 0xFF;
+        self.set_flags(false, false, half_carry, carry);
+        sp.wrapping_add_signed(offset as i16)
+    }
+
+    fn set_flags(&mut self, zero: bool, subtraction: bool, half_carry: bool, carry: bool) {
+        self.registers.set_zero_bit(zero);
+        self.registers.set_subtraction_bit(subtraction);
+        self.registers.set_half_carry_bit(half_carry);
+        self.registers.set_carry_bit(carry);
+    }
+
+    // ADD and ADC, carry_in is only ever set for ADC
+    fn add(&mut self, v: u8, carry_in: bool) {
+        // widen so both the value and the carry can overflow at once
         let old = self.registers.read(Register::A);
-        let (new_v, overflow) = old.overflowing_add(v);
+        let sum = old as u16 + v as u16 + carry_in as u16;
+        let new_v = sum as u8;
         // set flags
         self.registers.set_zero_bit(new_v == 0);
         self.registers.set_subtraction_bit(false);
-        self.registers.set_carry_bit(overflow);
+        self.registers.set_carry_bit(sum > 0xFF);
         // half-carry is set if the lower nibbles would carry
-        let lower_carry = (v & 0xF) + (old & 0xF) > 0xF;
+        let lower_carry = (v & 0xF) + (old & 0xF) + carry_in as u8 > 0xF;
         self.registers.set_half_carry_bit(lower_carry);
         // we write back to accumulator register
-        self.registers.write_register(Register::A, new_v).unwrap(); //todo
+        self.registers.write_register(Register::A, new_v);
+    }
+
+    // SUB, SBC and CP. returns A - v - carry_in without writing it back
+    fn sub(&mut self, v: u8, carry_in: bool) -> u8 {
+        let old = self.registers.read(Register::A);
+        let new_v = old.wrapping_sub(v).wrapping_sub(carry_in as u8);
+        self.registers.set_zero_bit(new_v == 0);
+        self.registers.set_subtraction_bit(true);
+        // borrows rather than carries
+        let borrow = (old as u16) < v as u16 + carry_in as u16;
+        self.registers.set_carry_bit(borrow);
+        let lower_borrow = (old & 0xF) < (v & 0xF) + carry_in as u8;
+        self.registers.set_half_carry_bit(lower_borrow);
+        new_v
+    }
+
+    // AND, XOR, OR. only AND sets half-carry
+    fn logic(&mut self, v: u8, op: fn(u8, u8) -> u8, half_carry: bool) {
+        let new_v = op(self.registers.read(Register::A), v);
+        self.set_flags(new_v == 0, false, half_carry, false);
+        self.registers.write_register(Register::A, new_v);
+    }
+
+    // INC and DEC leave carry alone
+    fn inc(&mut self, v: u8) -> u8 {
+        let new_v = v.wrapping_add(1);
+        self.registers.set_zero_bit(new_v == 0);
+        self.registers.set_subtraction_bit(false);
+        self.registers.set_half_carry_bit(v & 0xF == 0xF);
+        new_v
+    }
+
+    fn dec(&mut self, v: u8) -> u8 {
+        let new_v = v.wrapping_sub(1);
+        self.registers.set_zero_bit(new_v == 0);
+        self.registers.set_subtraction_bit(true);
+        self.registers.set_half_carry_bit(v & 0xF == 0);
+        new_v
+    }
+
+    /*
+     * rotates and shifts. all of them put the bit that falls off in carry,
+     * set zero from the result and clear subtraction and half-carry.
+     * the RL/RR variants rotate through carry rather than around the byte.
+     */
+    fn rlc(&mut self, v: u8) -> u8 {
+        let new_v = v.rotate_left(1);
+        self.set_flags(new_v == 0, false, false, v & 0x80 != 0);
+        new_v
+    }
+
+    fn rrc(&mut self, v: u8) -> u8 {
+        let new_v = v.rotate_right(1);
+        self.set_flags(new_v == 0, false, false, v & 0x01 != 0);
+        new_v
+    }
+
+    fn rl(&mut self, v: u8) -> u8 {
+        let new_v = v << 1 | self.registers.has_carry_bit() as u8;
+        self.set_flags(new_v == 0, false, false, v & 0x80 != 0);
+        new_v
+    }
+
+    fn rr(&mut self, v: u8) -> u8 {
+        let new_v = v >> 1 | (self.registers.has_carry_bit() as u8) << 7;
+        self.set_flags(new_v == 0, false, false, v & 0x01 != 0);
+        new_v
+    }
+
+    fn sla(&mut self, v: u8) -> u8 {
+        let new_v = v << 1;
+        self.set_flags(new_v == 0, false, false, v & 0x80 != 0);
+        new_v
+    }
+
+    // arithmetic shift keeps the sign bit
+    fn sra(&mut self, v: u8) -> u8 {
+        let new_v = v >> 1 | (v & 0x80);
+        self.set_flags(new_v == 0, false, false, v & 0x01 != 0);
+        new_v
+    }
+
+    fn srl(&mut self, v: u8) -> u8 {
+        let new_v = v >> 1;
+        self.set_flags(new_v == 0, false, false, v & 0x01 != 0);
+        new_v
+    }
+
+    fn rotate_a(&mut self, op: fn(&mut Self, u8) -> u8) {
+        let a = self.registers.read(Register::A);
+        let new_a = op(self, a);
+        self.registers.write_register(Register::A, new_a);
+        self.registers.set_zero_bit(false);
+    }
+
+    /*
+     * decimal adjust after a BCD add or subtract. the flags from the last
+     * op say what happened: after an add, fix up any digit that went past
+     * 9 or carried out; after a subtract, undo the digits that borrowed.
+     * subtraction is kept, half-carry always cleared.
+     */
+    fn daa(&mut self) {
+        let mut a = self.registers.read(Register::A);
+        let mut carry = self.registers.has_carry_bit();
+        let half_carry = self.registers.has_half_carry_bit();
+        if !self.registers.has_subtraction_bit() {
+            if carry || a > 0x99 {
+                a = a.wrapping_add(0x60);
+                carry = true;
+            }
+            if half_carry || a & 0x0F > 0x09 {
+                a = a.wrapping_add(0x06);
+            }
+        } else {
+            if carry {
+                a = a.wrapping_sub(0x60);
+            }
+            if half_carry {
+                a = a.wrapping_sub(0x06);
+            }
+        }
+        self.registers.set_zero_bit(a == 0);
+        self.registers.set_half_carry_bit(false);
+        self.registers.set_carry_bit(carry);
+        self.registers.write_register(Register::A, a);
+    }
+
+    fn swap(&mut self, v: u8) -> u8 {
+        let new_v = v.rotate_left(4);
+        self.set_flags(new_v == 0, false, false, false);
+        new_v
+    }
+}
+
+fn wide_register(target: WideTarget) -> Register16 {
+    match target {
+        WideTarget::BC => Register16::BC,
+        WideTarget::DE => Register16::DE,
+        WideTarget::HL => Register16::HL,
+        WideTarget::SP => Register16::SP,
+    }
+}
+
+fn stack_register(target: StackTarget) -> Register16 {
+    match target {
+        StackTarget::BC => Register16::BC,
+        StackTarget::DE => Register16::DE,
+        StackTarget::HL => Register16::HL,
+        StackTarget::AF => Register16::AF,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use crate::bus::Bus;
+    use crate::cpu::Cpu;
+    use crate::instruction::ArithmeticTarget;
+    use crate::instruction::ByteTarget;
+    use crate::instruction::Condition;
+    use crate::instruction::DecodeError;
+    use crate::instruction::Indirect;
+    use crate::instruction::Instruction;
+    use crate::instruction::LoadType;
+    use crate::instruction::StackTarget;
+    use crate::instruction::WideTarget;
+    use crate::register_bank::Register;
+    use crate::register_bank::Register16;
+
+    // flat 64KiB of ram so tests can put code and data anywhere. every
+    // access is logged as (M-cycle, address) against the ticks so far
+    struct TestBus {
+        memory: Vec<u8>,
+        cycles: u32,
+        reads: RefCell<Vec<(u32, u16)>>,
+        writes: Vec<(u32, u16)>,
+    }
+
+    impl Bus for TestBus {
+        fn read8(&self, address: u16) -> u8 {
+            self.reads.borrow_mut().push((self.cycles, address));
+            self.memory[address as usize]
+        }
+
+        fn write8(&mut self, address: u16, v: u8) {
+            self.writes.push((self.cycles, address));
+            self.memory[address as usize] = v;
+        }
+
+        fn tick(&mut self, cycles: u8) {
+            self.cycles += cycles as u32;
+        }
+    }
+
+    fn test_cpu() -> Cpu<TestBus> {
+        Cpu::new(TestBus {
+            memory: vec![0; 0x10000],
+            cycles: 0,
+            reads: RefCell::new(Vec::new()),
+            writes: Vec::new(),
+        })
+    }
+
+    // (A, operand, carry in, expected A, Z, N, H, C)
+    type AluCase = (u8, u8, bool, u8, bool, bool, bool, bool);
+
+    fn check_alu_op(op: fn(ArithmeticTarget) -> Instruction, cases: &[AluCase]) {
+        for &(a, v, carry_in, expected, z, n, h, c) in cases {
+            // register, (HL) and immediate sources all behave the same
+            for target in [
+                ArithmeticTarget::C,
+                ArithmeticTarget::HLI,
+                ArithmeticTarget::D8(v),
+            ] {
+                let mut cpu = test_cpu();
+                cpu.registers.write_hl(0xC000);
+                cpu.registers.write_register(Register::A, a);
+                cpu.registers.write_register(Register::C, v);
+                cpu.bus.memory[0xC000] = v;
+                cpu.registers.set_carry_bit(carry_in);
+                let ins = op(target);
+                cpu.exec(ins);
+                let case = format!("{:?} A={:#04X} v={:#04X} c={}", ins, a, v, carry_in);
+                assert_eq!(cpu.registers.read(Register::A), expected, "{}", case);
+                assert_eq!(cpu.registers.has_zero_bit(), z, "Z {}", case);
+                assert_eq!(cpu.registers.has_subtraction_bit(), n, "N {}", case);
+                assert_eq!(cpu.registers.has_half_carry_bit(), h, "H {}", case);
+                assert_eq!(cpu.registers.has_carry_bit(), c, "C {}", case);
+            }
+        }
+    }
+
+    #[test]
+    fn test_add() {
+        check_alu_op(
+            Instruction::Add,
+            &[
+                (0x3A, 0xC6, false, 0x00, true, false, true, true),
+                (0x3C, 0xFF, false, 0x3B, false, false, true, true),
+                (0x3C, 0x12, true, 0x4E, false, false, false, false),
+                (0x08, 0x08, false, 0x10, false, false, true, false),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_adc() {
+        check_alu_op(
+            Instruction::Adc,
+            &[
+                (0xE1, 0x0F, true, 0xF1, false, false, true, false),
+                (0xE1, 0x3B, true, 0x1D, false, false, false, true),
+                (0xE1, 0x1E, true, 0x00, true, false, true, true),
+                // the carry alone can cause both carries
+                (0x0F, 0x00, true, 0x10, false, false, true, false),
+                (0xFF, 0x00, true, 0x00, true, false, true, true),
+                (0x00, 0xFF, true, 0x00, true, false, true, true),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_sub() {
+        check_alu_op(
+            Instruction::Sub,
+            &[
+                (0x3E, 0x3E, false, 0x00, true, true, false, false),
+                (0x3E, 0x0F, false, 0x2F, false, true, true, false),
+                (0x3E, 0x40, true, 0xFE, false, true, false, true),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_sbc() {
+        check_alu_op(
+            Instruction::Sbc,
+            &[
+                (0x3B, 0x2A, true, 0x10, false, true, false, false),
+                (0x3B, 0x3A, true, 0x00, true, true, false, false),
+                (0x3B, 0x4F, true, 0xEB, false, true, true, true),
+                // the borrow alone can cause both borrows
+                (0x10, 0x00, true, 0x0F, false, true, true, false),
+                (0x00, 0x00, true, 0xFF, false, true, true, true),
+                (0x00, 0xFF, true, 0x00, true, true, true, true),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_cp() {
+        // A is left as it was
+        check_alu_op(
+            Instruction::Cp,
+            &[
+                (0x3C, 0x2F, false, 0x3C, false, true, true, false),
+                (0x3C, 0x3C, true, 0x3C, true, true, false, false),
+                (0x3C, 0x40, false, 0x3C, false, true, false, true),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_logic() {
+        check_alu_op(
+            Instruction::And,
+            &[
+                (0x5A, 0x3F, true, 0x1A, false, false, true, false),
+                (0x5A, 0x00, false, 0x00, true, false, true, false),
+            ],
+        );
+        check_alu_op(
+            Instruction::Xor,
+            &[
+                (0xFF, 0x0F, true, 0xF0, false, false, false, false),
+                (0xFF, 0xFF, false, 0x00, true, false, false, false),
+            ],
+        );
+        check_alu_op(
+            Instruction::Or,
+            &[
+                (0x5A, 0x03, true, 0x5B, false, false, false, false),
+                (0x00, 0x00, false, 0x00, true, false, false, false),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_inc_dec() {
+        for target in [ByteTarget::B, ByteTarget::HLI] {
+            let mut cpu = test_cpu();
+            cpu.registers.write_hl(0xC000);
+            cpu.registers.write_register(Register::B, 0xFF);
+            cpu.bus.memory[0xC000] = 0xFF;
+            cpu.registers.set_carry_bit(true);
+            cpu.exec(Instruction::Inc(target));
+            assert_eq!(cpu.read_byte_target(target), 0x00);
+            assert!(cpu.registers.has_zero_bit());
+            assert!(!cpu.registers.has_subtraction_bit());
+            assert!(cpu.registers.has_half_carry_bit());
+            assert!(cpu.registers.has_carry_bit(), "carry is untouched");
+
+            cpu.exec(Instruction::Dec(target));
+            assert_eq!(cpu.read_byte_target(target), 0xFF);
+            assert!(!cpu.registers.has_zero_bit());
+            assert!(cpu.registers.has_subtraction_bit());
+            assert!(cpu.registers.has_half_carry_bit());
+            assert!(cpu.registers.has_carry_bit(), "carry is untouched");
+
+            cpu.exec(Instruction::Dec(target));
+            assert_eq!(cpu.read_byte_target(target), 0xFE);
+            assert!(!cpu.registers.has_half_carry_bit());
+        }
+    }
+
+    // (input, carry in, expected result, Z, H, C) - N is always expected clear
+    type Case = (u8, bool, u8, bool, bool, bool);
+
+    fn check_byte_op(op: fn(ByteTarget) -> Instruction, cases: &[Case]) {
+        for &(input, carry_in, expected, z, h, c) in cases {
+            // once on a register and once through (HL)
+            for target in [ByteTarget::B, ByteTarget::HLI] {
+                let mut cpu = test_cpu();
+                cpu.registers.write_hl(0xC000);
+                cpu.registers.write_register(Register::B, input);
+                cpu.bus.memory[0xC000] = input;
+                cpu.registers.set_carry_bit(carry_in);
+                cpu.exec(op(target));
+                let result = cpu.read_byte_target(target);
+                let ins = op(target);
+                assert_eq!(result, expected, "{:?} on {:#04X}", ins, input);
+                assert_eq!(
+                    cpu.registers.has_zero_bit(),
+                    z,
+                    "Z {:?} {:#04X}",
+                    ins,
+                    input
+                );
+                assert!(!cpu.registers.has_subtraction_bit(), "N {:?}", ins);
+                assert_eq!(
+                    cpu.registers.has_half_carry_bit(),
+                    h,
+                    "H {:?} {:#04X}",
+                    ins,
+                    input
+                );
+                assert_eq!(
+                    cpu.registers.has_carry_bit(),
+                    c,
+                    "C {:?} {:#04X}",
+                    ins,
+                    input
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_rlc() {
+        check_byte_op(
+            Instruction::Rlc,
+            &[
+                (0x85, false, 0x0B, false, false, true),
+                (0x01, true, 0x02, false, false, false),
+                (0x00, true, 0x00, true, false, false),
+                (0xFF, false, 0xFF, false, false, true),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_rrc() {
+        check_byte_op(
+            Instruction::Rrc,
+            &[
+                (0x01, false, 0x80, false, false, true),
+                (0x80, true, 0x40, false, false, false),
+                (0x00, true, 0x00, true, false, false),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_rl() {
+        check_byte_op(
+            Instruction::Rl,
+            &[
+                (0x80, false, 0x00, true, false, true),
+                (0x80, true, 0x01, false, false, true),
+                (0x11, false, 0x22, false, false, false),
+                (0x00, true, 0x01, false, false, false),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_rr() {
+        check_byte_op(
+            Instruction::Rr,
+            &[
+                (0x01, false, 0x00, true, false, true),
+                (0x01, true, 0x80, false, false, true),
+                (0x8A, false, 0x45, false, false, false),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_sla() {
+        check_byte_op(
+            Instruction::Sla,
+            &[
+                (0x80, true, 0x00, true, false, true),
+                (0xFF, false, 0xFE, false, false, true),
+                (0x01, true, 0x02, false, false, false),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_sra() {
+        check_byte_op(
+            Instruction::Sra,
+            &[
+                (0x8A, false, 0xC5, false, false, false),
+                (0x01, false, 0x00, true, false, true),
+                (0x81, true, 0xC0, false, false, true),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_srl() {
+        check_byte_op(
+            Instruction::Srl,
+            &[
+                (0x01, false, 0x00, true, false, true),
+                (0xFF, true, 0x7F, false, false, true),
+                (0x80, true, 0x40, false, false, false),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_swap() {
+        check_byte_op(
+            Instruction::Swap,
+            &[
+                (0xF0, true, 0x0F, false, false, false),
+                (0x00, true, 0x00, true, false, false),
+                (0x12, false, 0x21, false, false, false),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_bit() {
+        // BIT leaves the operand and carry alone
+        check_byte_op(
+            |target| Instruction::Bit(7, target),
+            &[
+                (0x80, false, 0x80, false, true, false),
+                (0x7F, true, 0x7F, true, true, true),
+            ],
+        );
+        check_byte_op(
+            |target| Instruction::Bit(0, target),
+            &[
+                (0x01, true, 0x01, false, true, true),
+                (0xFE, false, 0xFE, true, true, false),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_res_and_set() {
+        // neither touches flags, so everything should still be clear
+        check_byte_op(
+            |target| Instruction::Res(3, target),
+            &[
+                (0xFF, false, 0xF7, false, false, false),
+                (0x00, false, 0x00, false, false, false),
+            ],
+        );
+        check_byte_op(
+            |target| Instruction::Set(6, target),
+            &[
+                (0x00, false, 0x40, false, false, false),
+                (0x40, false, 0x40, false, false, false),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_res_and_set_leave_flags() {
+        let mut cpu = test_cpu();
+        cpu.registers.set_zero_bit(true);
+        cpu.registers.set_carry_bit(true);
+        cpu.exec(Instruction::Set(0, ByteTarget::A));
+        cpu.exec(Instruction::Res(1, ByteTarget::A));
+        assert_eq!(cpu.registers.read(Register::A), 0x01);
+        assert!(cpu.registers.has_zero_bit());
+        assert!(cpu.registers.has_carry_bit());
+    }
+
+    #[test]
+    fn test_add_hl() {
+        let mut cpu = test_cpu();
+        cpu.registers.write_hl(0x8A23);
+        cpu.registers.write_bc(0x0605);
+        cpu.registers.set_zero_bit(true);
+        cpu.exec(Instruction::AddHl(WideTarget::BC));
+        assert_eq!(cpu.registers.read_hl(), 0x9028);
+        assert!(cpu.registers.has_zero_bit(), "zero is untouched");
+        assert!(!cpu.registers.has_subtraction_bit());
+        assert!(cpu.registers.has_half_carry_bit());
+        assert!(!cpu.registers.has_carry_bit());
+
+        cpu.registers.write_hl(0x8A23);
+        cpu.exec(Instruction::AddHl(WideTarget::HL));
+        assert_eq!(cpu.registers.read_hl(), 0x1446);
+        assert!(cpu.registers.has_half_carry_bit());
+        assert!(cpu.registers.has_carry_bit());
+    }
+
+    #[test]
+    fn test_inc_dec_16() {
+        let mut cpu = test_cpu();
+        cpu.registers.write_de(0xFFFF);
+        cpu.exec(Instruction::Inc16(WideTarget::DE));
+        assert_eq!(cpu.registers.read_de(), 0x0000);
+        assert!(!cpu.registers.has_zero_bit(), "no flags change");
+        cpu.exec(Instruction::Dec16(WideTarget::SP));
+        assert_eq!(cpu.registers.read16(Register16::SP), 0xFFFF);
+    }
+
+    #[test]
+    fn test_add_sp() {
+        // (sp, offset, expected, H, C)
+        let cases: [(u16, i8, u16, bool, bool); 5] = [
+            (0xFFF8, 2, 0xFFFA, false, false),
+            (0xFFF8, 8, 0x0000, true, true),
+            (0x000F, 1, 0x0010, true, false),
+            (0x00FF, -1, 0x00FE, true, true),
+            (0x0000, -1, 0xFFFF, false, false),
+        ];
+        for (sp, offset, expected, h, c) in cases {
+            for ins in [
+                Instruction::AddSp(offset),
+                Instruction::Ld(LoadType::HlFromSpOffset(offset)),
+            ] {
+                let mut cpu = test_cpu();
+                cpu.registers.write16(Register16::SP, sp);
+                cpu.set_flags(true, true, false, false);
+                cpu.exec(ins);
+                let result = match ins {
+                    Instruction::AddSp(_) => cpu.registers.read16(Register16::SP),
+                    _ => cpu.registers.read_hl(),
+                };
+                assert_eq!(result, expected, "{:?} sp={:#06X}", ins, sp);
+                assert!(!cpu.registers.has_zero_bit());
+                assert!(!cpu.registers.has_subtraction_bit());
+                assert_eq!(cpu.registers.has_half_carry_bit(), h, "H {:?}", ins);
+                assert_eq!(cpu.registers.has_carry_bit(), c, "C {:?}", ins);
+            }
+        }
+    }
+
+    #[test]
+    fn test_push_pop() {
+        let mut cpu = test_cpu();
+        cpu.registers.write16(Register16::SP, 0xFFFE);
+        cpu.registers.write_bc(0x1234);
+        cpu.exec(Instruction::Push(StackTarget::BC));
+        assert_eq!(cpu.registers.read16(Register16::SP), 0xFFFC);
+        assert_eq!(cpu.bus.memory[0xFFFD], 0x12);
+        assert_eq!(cpu.bus.memory[0xFFFC], 0x34);
+        cpu.exec(Instruction::Pop(StackTarget::DE));
+        assert_eq!(cpu.registers.read_de(), 0x1234);
+        assert_eq!(cpu.registers.read16(Register16::SP), 0xFFFE);
+    }
+
+    #[test]
+    fn test_pop_af_masks_flags() {
+        let mut cpu = test_cpu();
+        cpu.registers.write16(Register16::SP, 0xFFFE);
+        cpu.registers.write_bc(0x12FF);
+        cpu.exec(Instruction::Push(StackTarget::BC));
+        cpu.exec(Instruction::Pop(StackTarget::AF));
+        assert_eq!(cpu.registers.read16(Register16::AF), 0x12F0);
+    }
+
+    #[test]
+    fn test_ld_r_r() {
+        let mut cpu = test_cpu();
+        cpu.registers.write_register(Register::B, 0x42);
+        cpu.registers.write_hl(0xC000);
+        let ld = |target, source| Instruction::Ld(LoadType::Byte(target, source));
+        cpu.exec(ld(ByteTarget::D, ArithmeticTarget::B));
+        assert_eq!(cpu.registers.read(Register::D), 0x42);
+        cpu.exec(ld(ByteTarget::HLI, ArithmeticTarget::D));
+        assert_eq!(cpu.bus.memory[0xC000], 0x42);
+        cpu.exec(ld(ByteTarget::HLI, ArithmeticTarget::D8(0x99)));
+        cpu.exec(ld(ByteTarget::A, ArithmeticTarget::HLI));
+        assert_eq!(cpu.registers.read(Register::A), 0x99);
+        assert_eq!(cpu.registers.flags().bits(), 0, "loads don't touch flags");
+    }
+
+    #[test]
+    fn test_ld_word() {
+        let mut cpu = test_cpu();
+        cpu.exec(Instruction::Ld(LoadType::Word(WideTarget::SP, 0xFFFE)));
+        cpu.exec(Instruction::Ld(LoadType::Word(WideTarget::BC, 0x1234)));
+        assert_eq!(cpu.registers.read16(Register16::SP), 0xFFFE);
+        assert_eq!(cpu.registers.read_bc(), 0x1234);
+
+        cpu.registers.write_hl(0xC100);
+        cpu.exec(Instruction::Ld(LoadType::SpFromHl));
+        assert_eq!(cpu.registers.read16(Register16::SP), 0xC100);
+
+        cpu.exec(Instruction::Ld(LoadType::AddressFromSp(0xC000)));
+        assert_eq!(cpu.bus.memory[0xC000], 0x00);
+        assert_eq!(cpu.bus.memory[0xC001], 0xC1);
+    }
+
+    #[test]
+    fn test_ld_indirect() {
+        let mut cpu = test_cpu();
+        cpu.registers.write_bc(0xC000);
+        cpu.registers.write_de(0xC001);
+        cpu.bus.memory[0xC000] = 0x11;
+        cpu.bus.memory[0xC001] = 0x22;
+        cpu.exec(Instruction::Ld(LoadType::AFromIndirect(Indirect::BC)));
+        assert_eq!(cpu.registers.read(Register::A), 0x11);
+        cpu.exec(Instruction::Ld(LoadType::IndirectFromA(Indirect::DE)));
+        assert_eq!(cpu.bus.memory[0xC001], 0x11);
+    }
+
+    #[test]
+    fn test_ld_hl_increment_decrement() {
+        let mut cpu = test_cpu();
+        cpu.registers.write_hl(0xC000);
+        cpu.registers.write_register(Register::A, 0x55);
+        cpu.exec(Instruction::Ld(LoadType::IndirectFromA(Indirect::HLInc)));
+        cpu.exec(Instruction::Ld(LoadType::IndirectFromA(Indirect::HLInc)));
+        assert_eq!(cpu.registers.read_hl(), 0xC002);
+        assert_eq!(&cpu.bus.memory[0xC000..0xC003], &[0x55, 0x55, 0x00]);
+
+        cpu.exec(Instruction::Ld(LoadType::IndirectFromA(Indirect::HLDec)));
+        assert_eq!(cpu.registers.read_hl(), 0xC001);
+        assert_eq!(cpu.bus.memory[0xC002], 0x55);
+
+        cpu.bus.memory[0xC001] = 0x77;
+        cpu.exec(Instruction::Ld(LoadType::AFromIndirect(Indirect::HLDec)));
+        assert_eq!(cpu.registers.read(Register::A), 0x77);
+        assert_eq!(cpu.registers.read_hl(), 0xC000);
+        cpu.exec(Instruction::Ld(LoadType::AFromIndirect(Indirect::HLInc)));
+        assert_eq!(cpu.registers.read(Register::A), 0x55);
+        assert_eq!(cpu.registers.read_hl(), 0xC001);
+    }
+
+    #[test]
+    fn test_ldh() {
+        let mut cpu = test_cpu();
+        cpu.registers.write_register(Register::A, 0xAB);
+        cpu.exec(Instruction::Ld(LoadType::HighPageFromA(0x80)));
+        assert_eq!(cpu.bus.memory[0xFF80], 0xAB);
+
+        cpu.registers.write_register(Register::C, 0x81);
+        cpu.exec(Instruction::Ld(LoadType::HighCFromA));
+        assert_eq!(cpu.bus.memory[0xFF81], 0xAB);
+
+        cpu.bus.memory[0xFF82] = 0x01;
+        cpu.bus.memory[0xFF83] = 0x02;
+        cpu.exec(Instruction::Ld(LoadType::AFromHighPage(0x82)));
+        assert_eq!(cpu.registers.read(Register::A), 0x01);
+        cpu.registers.write_register(Register::C, 0x83);
+        cpu.exec(Instruction::Ld(LoadType::AFromHighC));
+        assert_eq!(cpu.registers.read(Register::A), 0x02);
+    }
+
+    #[test]
+    fn test_ld_absolute() {
+        let mut cpu = test_cpu();
+        cpu.registers.write_register(Register::A, 0x3C);
+        cpu.exec(Instruction::Ld(LoadType::AddressFromA(0xD000)));
+        assert_eq!(cpu.bus.memory[0xD000], 0x3C);
+        cpu.bus.memory[0xD001] = 0x4D;
+        cpu.exec(Instruction::Ld(LoadType::AFromAddress(0xD001)));
+        assert_eq!(cpu.registers.read(Register::A), 0x4D);
+    }
+
+    #[test]
+    fn test_exec_covers_the_decode_table() {
+        // every instruction decode hands out, base and 0xCB, has to run
+        let mut illegal = 0;
+        for op in 0..=0xFF {
+            for (opcode, operands) in [(op, [0x34, 0x12]), (0xCB, [op, 0x12])] {
+                let Ok(decoded) = Instruction::decode(opcode, &operands) else {
+                    illegal += 1;
+                    continue;
+                };
+                let mut cpu = test_cpu();
+                cpu.registers.write16(Register16::SP, 0xFFFE);
+                cpu.exec(decoded.instruction);
+            }
+        }
+        // only the holes in the base table
+        assert_eq!(illegal, 11);
+    }
+
+    // loads `program` at 0x0100, points PC at it and sets up a stack
+    fn cpu_with_program(program: &[u8]) -> Cpu<TestBus> {
+        let mut cpu = test_cpu();
+        cpu.bus.memory[0x0100..0x0100 + program.len()].copy_from_slice(program);
+        cpu.registers.write16(Register16::PC, 0x0100);
+        cpu.registers.write16(Register16::SP, 0xFFFE);
+        cpu
+    }
+
+    fn pc(cpu: &Cpu<TestBus>) -> u16 {
+        cpu.registers.read16(Register16::PC)
+    }
+
+    #[test]
+    fn test_step_advances_pc() {
+        // LD B,0x12 ; INC B ; LD HL,0xC000
+        let mut cpu = cpu_with_program(&[0x06, 0x12, 0x04, 0x21, 0x00, 0xC0]);
+        assert_eq!(cpu.step(), Ok(2));
+        assert_eq!(pc(&cpu), 0x0102);
+        assert_eq!(cpu.step(), Ok(1));
+        assert_eq!(cpu.registers.read(Register::B), 0x13);
+        assert_eq!(cpu.step(), Ok(3));
+        assert_eq!(cpu.registers.read_hl(), 0xC000);
+        assert_eq!(pc(&cpu), 0x0106);
+    }
+
+    #[test]
+    fn test_step_illegal_opcode() {
+        let mut cpu = cpu_with_program(&[0xD3]);
+        assert_eq!(cpu.step(), Err(DecodeError::IllegalOpcode(0xD3)));
+    }
+
+    #[test]
+    fn test_step_runs_every_opcode() {
+        // a decodable opcode runs, a hole in the table is an error, and
+        // neither panics
+        for opcode in 0..=0xFF {
+            for program in [[opcode, 0x34, 0x12], [0xCB, opcode, 0x12]] {
+                let mut cpu = cpu_with_program(&program);
+                match cpu.step() {
+                    Ok(cycles) => {
+                        assert!(cycles > 0, "{:02X?}", program);
+                        // the bus ticked along with it, no more, no less
+                        assert_eq!(cpu.bus.cycles, cycles as u32, "{:02X?}", program);
+                    }
+                    Err(e) => assert_eq!(e, DecodeError::IllegalOpcode(opcode)),
+                }
+            }
+        }
+    }
+
+    // (M-cycle, address), as TestBus logs them
+    type Access = (u32, u16);
+
+    #[test]
+    fn test_accesses_land_on_their_cycle() {
+        // (program, reads, writes), leaving out the fetches from the
+        // program itself and the interrupt check, which is on cycle 0
+        // before anything's ticked
+        let cases: &[(&[u8], &[Access], &[Access])] = &[
+            // LD (HL),A
+            (&[0x77], &[], &[(2, 0xC000)]),
+            // INC (HL)
+            (&[0x34], &[(2, 0xC000)], &[(3, 0xC000)]),
+            // SET 0,(HL)
+            (&[0xCB, 0xC6], &[(3, 0xC000)], &[(4, 0xC000)]),
+            // LD A,(nn)
+            (&[0xFA, 0x00, 0xD0], &[(4, 0xD000)], &[]),
+            // LD (nn),SP
+            (&[0x08, 0x00, 0xD0], &[], &[(4, 0xD000), (5, 0xD001)]),
+            // PUSH BC, high byte first after a cycle moving SP
+            (&[0xC5], &[], &[(3, 0xFFFD), (4, 0xFFFC)]),
+            // CALL nn
+            (&[0xCD, 0x00, 0x02], &[], &[(5, 0xFFFD), (6, 0xFFFC)]),
+            // POP BC
+            (&[0xC1], &[(2, 0xFFFE), (3, 0xFFFF)], &[]),
+            // RET
+            (&[0xC9], &[(2, 0xFFFE), (3, 0xFFFF)], &[]),
+            // RET NZ, taken, checks the condition before popping
+            (&[0xC0], &[(3, 0xFFFE), (4, 0xFFFF)], &[]),
+        ];
+        for &(program, reads, writes) in cases {
+            let mut cpu = cpu_with_program(program);
+            cpu.registers.write_hl(0xC000);
+            let cycles = cpu.step().unwrap();
+            assert_eq!(cpu.bus.cycles, cycles as u32, "{:02X?}", program);
+            let program_bytes = 0x0100..0x0100 + program.len() as u16 + 2;
+            let data_reads: Vec<_> = cpu
+                .bus
+                .reads
+                .borrow()
+                .iter()
+                .filter(|&&(cycle, address)| cycle > 0 && !program_bytes.contains(&address))
+                .copied()
+                .collect();
+            assert_eq!(data_reads, reads, "{:02X?}", program);
+            assert_eq!(cpu.bus.writes, writes, "{:02X?}", program);
+        }
+
+        // an interrupt pushes PC after its two wait states, IF is cleared
+        // up front
+        let mut cpu = cpu_with_program(&[0x00]);
+        cpu.ime = true;
+        raise(&mut cpu, 0x01);
+        assert_eq!(cpu.step(), Ok(5));
+        assert_eq!(cpu.bus.cycles, 5);
+        assert_eq!(cpu.bus.writes, [(0, 0xFF0F), (3, 0xFFFD), (4, 0xFFFC)]);
+    }
+
+    #[test]
+    fn test_jp() {
+        let mut cpu = cpu_with_program(&[0xC3, 0x00, 0x02]);
+        assert_eq!(cpu.step(), Ok(4));
+        assert_eq!(pc(&cpu), 0x0200);
+
+        cpu.registers.write_hl(0x1234);
+        cpu.exec(Instruction::JpHl);
+        assert_eq!(pc(&cpu), 0x1234);
+    }
+
+    #[test]
+    fn test_conditional_jp_cycles() {
+        // JP Z,0x0200 not taken then taken
+        let mut cpu = cpu_with_program(&[0xCA, 0x00, 0x02, 0xCA, 0x00, 0x02]);
+        assert_eq!(cpu.step(), Ok(3));
+        assert_eq!(pc(&cpu), 0x0103);
+        cpu.registers.set_zero_bit(true);
+        assert_eq!(cpu.step(), Ok(4));
+        assert_eq!(pc(&cpu), 0x0200);
+    }
+
+    #[test]
+    fn test_jr() {
+        // JR -2 jumps back onto itself
+        let mut cpu = cpu_with_program(&[0x18, 0xFE]);
+        assert_eq!(cpu.step(), Ok(3));
+        assert_eq!(pc(&cpu), 0x0100);
+
+        // JR NC,+5 taken and JR C,+5 not taken
+        let mut cpu = cpu_with_program(&[0x30, 0x05]);
+        assert_eq!(cpu.step(), Ok(3));
+        assert_eq!(pc(&cpu), 0x0107);
+        let mut cpu = cpu_with_program(&[0x38, 0x05]);
+        assert_eq!(cpu.step(), Ok(2));
+        assert_eq!(pc(&cpu), 0x0102);
+    }
+
+    #[test]
+    fn test_call_ret() {
+        // CALL 0x0200, with RET at 0x0200
+        let mut cpu = cpu_with_program(&[0xCD, 0x00, 0x02]);
+        cpu.bus.memory[0x0200] = 0xC9;
+        assert_eq!(cpu.step(), Ok(6));
+        assert_eq!(pc(&cpu), 0x0200);
+        assert_eq!(cpu.registers.read16(Register16::SP), 0xFFFC);
+        assert_eq!(cpu.bus.read16(0xFFFC), 0x0103);
+        assert_eq!(cpu.step(), Ok(4));
+        assert_eq!(pc(&cpu), 0x0103);
+        assert_eq!(cpu.registers.read16(Register16::SP), 0xFFFE);
+    }
+
+    #[test]
+    fn test_conditional_call_ret_cycles() {
+        // CALL NZ,0x0200 ; at 0x0200: RET Z ; RET NZ
+        let mut cpu = cpu_with_program(&[0xC4, 0x00, 0x02, 0xC4, 0x00, 0x02]);
+        cpu.bus.memory[0x0200..0x0202].copy_from_slice(&[0xC8, 0xC0]);
+        cpu.registers.set_zero_bit(true);
+        assert_eq!(cpu.step(), Ok(3));
+        assert_eq!(pc(&cpu), 0x0103);
+        cpu.registers.set_zero_bit(false);
+        assert_eq!(cpu.step(), Ok(6));
+        assert_eq!(pc(&cpu), 0x0200);
+        assert_eq!(cpu.step(), Ok(2));
+        assert_eq!(pc(&cpu), 0x0201);
+        assert_eq!(cpu.step(), Ok(5));
+        assert_eq!(pc(&cpu), 0x0106);
+    }
+
+    #[test]
+    fn test_reti() {
+        let mut cpu = cpu_with_program(&[0xCD, 0x00, 0x02]);
+        cpu.bus.memory[0x0200] = 0xD9;
+        cpu.step().unwrap();
+        assert_eq!(cpu.step(), Ok(4));
+        assert_eq!(pc(&cpu), 0x0103);
+        assert!(cpu.ime);
+    }
+
+    #[test]
+    fn test_rst() {
+        let mut cpu = cpu_with_program(&[0xEF]);
+        assert_eq!(cpu.step(), Ok(4));
+        assert_eq!(pc(&cpu), 0x0028);
+        assert_eq!(cpu.bus.read16(0xFFFC), 0x0101);
+    }
+
+    #[test]
+    fn test_conditions() {
+        let mut cpu = test_cpu();
+        assert!(cpu.condition_met(None));
+        assert!(cpu.condition_met(Some(Condition::NZ)));
+        assert!(cpu.condition_met(Some(Condition::NC)));
+        cpu.registers.set_zero_bit(true);
+        cpu.registers.set_carry_bit(true);
+        assert!(cpu.condition_met(Some(Condition::Z)));
+        assert!(cpu.condition_met(Some(Condition::C)));
+        assert!(!cpu.condition_met(Some(Condition::NZ)));
+        assert!(!cpu.condition_met(Some(Condition::NC)));
+    }
+
+    // enables every interrupt in IE and raises `flags` in IF
+    fn raise(cpu: &mut Cpu<TestBus>, flags: u8) {
+        cpu.bus.memory[0xFFFF] = 0x1F;
+        cpu.bus.memory[0xFF0F] = flags;
+    }
+
+    #[test]
+    fn test_interrupt_dispatch() {
+        let mut cpu = cpu_with_program(&[0x00]);
+        cpu.ime = true;
+        raise(&mut cpu, 0b0_0100);
+        assert_eq!(cpu.step(), Ok(5));
+        assert_eq!(pc(&cpu), 0x0050);
+        assert_eq!(cpu.bus.read16(0xFFFC), 0x0100);
+        assert!(!cpu.ime);
+        assert_eq!(cpu.bus.memory[0xFF0F], 0, "IF bit is acknowledged");
+    }
+
+    #[test]
+    fn test_interrupt_priority() {
+        let mut cpu = cpu_with_program(&[0x00]);
+        cpu.ime = true;
+        raise(&mut cpu, 0b1_0010);
+        cpu.step().unwrap();
+        assert_eq!(pc(&cpu), 0x0048);
+        assert_eq!(cpu.bus.memory[0xFF0F], 0b1_0000, "joypad still pending");
+    }
+
+    #[test]
+    fn test_interrupt_needs_ie() {
+        let mut cpu = cpu_with_program(&[0x00]);
+        cpu.ime = true;
+        cpu.bus.memory[0xFF0F] = 0x01;
+        assert_eq!(cpu.step(), Ok(1));
+        assert_eq!(pc(&cpu), 0x0101);
+    }
+
+    #[test]
+    fn test_ei_delay() {
+        // EI ; NOP ; NOP
+        let mut cpu = cpu_with_program(&[0xFB, 0x00, 0x00]);
+        raise(&mut cpu, 0x01);
+        cpu.step().unwrap();
+        assert!(!cpu.ime);
+        // the instruction after EI still runs
+        assert_eq!(cpu.step(), Ok(1));
+        assert_eq!(pc(&cpu), 0x0102);
+        assert_eq!(cpu.step(), Ok(5));
+        assert_eq!(pc(&cpu), 0x0040);
+        assert_eq!(cpu.bus.read16(0xFFFC), 0x0102);
+    }
+
+    #[test]
+    fn test_ei_di() {
+        // EI ; DI ; NOP - the interrupt never gets in
+        let mut cpu = cpu_with_program(&[0xFB, 0xF3, 0x00]);
+        raise(&mut cpu, 0x01);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.step(), Ok(1));
+        assert_eq!(pc(&cpu), 0x0103);
+        assert!(!cpu.ime);
+    }
+
+    #[test]
+    fn test_halt_waits_for_interrupt() {
+        // HALT ; INC A
+        let mut cpu = cpu_with_program(&[0x76, 0x3C]);
+        cpu.ime = true;
+        cpu.step().unwrap();
+        assert!(cpu.is_halted());
+        for _ in 0..10 {
+            assert_eq!(cpu.step(), Ok(1));
+        }
+        assert_eq!(pc(&cpu), 0x0101);
+        raise(&mut cpu, 0x01);
+        assert_eq!(cpu.step(), Ok(5));
+        assert!(!cpu.is_halted());
+        assert_eq!(pc(&cpu), 0x0040);
+    }
+
+    #[test]
+    fn test_halt_without_ime_resumes() {
+        // HALT ; INC A - wakes up but carries on without dispatching
+        let mut cpu = cpu_with_program(&[0x76, 0x3C]);
+        cpu.step().unwrap();
+        assert!(cpu.is_halted());
+        raise(&mut cpu, 0x04);
+        cpu.step().unwrap();
+        assert!(!cpu.is_halted());
+        assert_eq!(cpu.registers.read(Register::A), 1);
+        assert_eq!(pc(&cpu), 0x0102);
+        assert_eq!(cpu.bus.memory[0xFF0F], 0x04, "not acknowledged");
+    }
+
+    #[test]
+    fn test_halt_bug() {
+        // HALT ; INC A ; LD B,A with IME off and an interrupt pending:
+        // INC A runs twice because PC doesn't move past it the first time
+        let mut cpu = cpu_with_program(&[0x76, 0x3C, 0x47]);
+        raise(&mut cpu, 0x01);
+        cpu.step().unwrap();
+        assert!(!cpu.is_halted());
+        cpu.step().unwrap();
+        assert_eq!(pc(&cpu), 0x0101);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.read(Register::A), 2);
+        assert_eq!(cpu.registers.read(Register::B), 2);
+    }
+
+    #[test]
+    fn test_halt_bug_operand() {
+        // HALT ; LD A,0x14 - the opcode gets read again as the operand
+        let mut cpu = cpu_with_program(&[0x76, 0x3E, 0x14]);
+        raise(&mut cpu, 0x01);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.read(Register::A), 0x3E);
+        assert_eq!(pc(&cpu), 0x0102);
+    }
+
+    #[test]
+    fn test_stop_waits_for_joypad() {
+        let mut cpu = cpu_with_program(&[0x10, 0x00, 0x3C]);
+        cpu.step().unwrap();
+        assert!(cpu.is_stopped());
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.read(Register::A), 0);
+        cpu.bus.memory[0xFF0F] = 0x10;
+        cpu.step().unwrap();
+        assert!(!cpu.is_stopped());
+        assert_eq!(cpu.registers.read(Register::A), 1);
+    }
+
+    #[test]
+    fn test_daa_reference_table() {
+        // every A against every combination of the four flags
+        let table = include_str!("../testdata/daa.txt");
+        let mut checked = 0;
+        for line in table.lines().filter(|l| !l.starts_with('#')) {
+            let v: Vec<u8> = line
+                .split_whitespace()
+                .map(|x| u8::from_str_radix(x, 16).unwrap())
+                .collect();
+            let mut cpu = test_cpu();
+            cpu.registers.write_register(Register::A, v[0]);
+            cpu.registers.write_register(Register::F, v[1]);
+            cpu.exec(Instruction::Daa);
+            let result = (
+                cpu.registers.read(Register::A),
+                cpu.registers.read(Register::F),
+            );
+            assert_eq!(result, (v[2], v[3]), "DAA A={:#04X} F={:#04X}", v[0], v[1]);
+            checked += 1;
+        }
+        assert_eq!(checked, 256 * 16);
+    }
+
+    #[test]
+    fn test_daa_after_add_and_sub() {
+        // 0x45 + 0x38 = 0x83 in BCD
+        let mut cpu = test_cpu();
+        cpu.registers.write_register(Register::A, 0x45);
+        cpu.exec(Instruction::Add(ArithmeticTarget::D8(0x38)));
+        cpu.exec(Instruction::Daa);
+        assert_eq!(cpu.registers.read(Register::A), 0x83);
+        assert!(!cpu.registers.has_carry_bit());
+        // 0x83 - 0x38 = 0x45 in BCD
+        cpu.exec(Instruction::Sub(ArithmeticTarget::D8(0x38)));
+        cpu.exec(Instruction::Daa);
+        assert_eq!(cpu.registers.read(Register::A), 0x45);
+        // 0x99 + 0x01 = 0x00 carry 1
+        cpu.registers.write_register(Register::A, 0x99);
+        cpu.exec(Instruction::Add(ArithmeticTarget::D8(0x01)));
+        cpu.exec(Instruction::Daa);
+        assert_eq!(cpu.registers.read(Register::A), 0x00);
+        assert!(cpu.registers.has_zero_bit());
+        assert!(cpu.registers.has_carry_bit());
+    }
+
+    #[test]
+    fn test_cpl() {
+        let mut cpu = test_cpu();
+        cpu.registers.write_register(Register::A, 0x35);
+        cpu.registers.set_zero_bit(true);
+        cpu.registers.set_carry_bit(true);
+        cpu.exec(Instruction::Cpl);
+        assert_eq!(cpu.registers.read(Register::A), 0xCA);
+        assert_eq!(cpu.registers.read(Register::F), 0xF0, "Z and C untouched");
+    }
+
+    #[test]
+    fn test_scf_ccf() {
+        let mut cpu = test_cpu();
+        cpu.registers.write_register(Register::F, 0xE0);
+        cpu.exec(Instruction::Scf);
+        assert_eq!(cpu.registers.read(Register::F), 0x90);
+        cpu.exec(Instruction::Ccf);
+        assert_eq!(cpu.registers.read(Register::F), 0x80);
+        cpu.registers.write_register(Register::F, 0x60);
+        cpu.exec(Instruction::Ccf);
+        assert_eq!(cpu.registers.read(Register::F), 0x10);
+    }
+
+    #[test]
+    fn test_rotate_a_clears_zero() {
+        // (op, A, carry in, expected A, expected F)
+        let cases = [
+            (Instruction::Rlca, 0x85, false, 0x0B, 0x10),
+            (Instruction::Rlca, 0x00, false, 0x00, 0x00),
+            (Instruction::Rrca, 0x3B, false, 0x9D, 0x10),
+            (Instruction::Rrca, 0x00, true, 0x00, 0x00),
+            (Instruction::Rla, 0x95, true, 0x2B, 0x10),
+            (Instruction::Rla, 0x80, false, 0x00, 0x10),
+            (Instruction::Rra, 0x81, false, 0x40, 0x10),
+            (Instruction::Rra, 0x01, false, 0x00, 0x10),
+        ];
+        for (ins, a, carry_in, expected, flags) in cases {
+            let mut cpu = test_cpu();
+            cpu.registers.write_register(Register::A, a);
+            // every flag set to start with, including zero
+            cpu.registers
+                .write_register(Register::F, 0xE0 | (carry_in as u8) << 4);
+            cpu.exec(ins);
+            assert_eq!(
+                cpu.registers.read(Register::A),
+                expected,
+                "{:?} {:#04X}",
+                ins,
+                a
+            );
+            assert_eq!(
+                cpu.registers.read(Register::F),
+                flags,
+                "{:?} {:#04X}",
+                ins,
+                a
+            );
+        }
     }
 }
@@ -0,0 +1,480 @@
+/*
+ * ties a cartridge, the memory map and the cpu together, and looks after
+ * the cartridge's battery backed ram and any audio recording
+ */
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::apu::Recording;
+use crate::bus::MemoryMap;
+use crate::cartridge::Cartridge;
+use crate::cartridge::CartridgeError;
+use crate::cpu::Cpu;
+use crate::instruction::DecodeError;
+use crate::joypad::Button;
+use crate::mbc;
+use crate::mbc::CartridgeEvent;
+use crate::mbc::Mbc;
+use crate::ppu::Ppu;
+use crate::ppu::Renderer;
+use crate::ppu::DOTS_PER_LINE;
+use crate::ppu::LINES_PER_FRAME;
+use crate::wav::WavWriter;
+
+// 154 lines of 456 dots, in M-cycles
+pub const CYCLES_PER_FRAME: u32 = LINES_PER_FRAME as u32 * DOTS_PER_LINE as u32 / 4;
+
+// stems are named after their channel, in Recording::stems order
+const STEM_NAMES: [&str; 4] = ["pulse1", "pulse2", "wave", "noise"];
+
+#[derive(Debug)]
+pub struct Emulator {
+    cpu: Cpu<MemoryMap>,
+    // where battery backed ram goes, None for carts without a battery
+    save_path: Option<PathBuf>,
+    // what's on disk, so unchanged ram isn't written again
+    last_saved: Option<Vec<u8>>,
+    ram_was_enabled: bool,
+    // cycles run past the end of the last frame
+    frame_overrun: u32,
+    recorder: Option<Recorder>,
+}
+
+#[derive(Debug)]
+struct Recorder {
+    mix: WavWriter<BufWriter<File>>,
+    // empty unless recording stems
+    stems: Vec<WavWriter<BufWriter<File>>>,
+    // the first write that failed, for finish_recording to report
+    error: Option<io::Error>,
+}
+
+impl Recorder {
+    fn write(&mut self, recording: &Recording) {
+        if self.error.is_some() {
+            return;
+        }
+        let mut result = self.mix.write(&recording.mix);
+        if let Some(stems) = &recording.stems {
+            for (wav, samples) in self.stems.iter_mut().zip(stems) {
+                result = result.and_then(|_| wav.write(samples));
+            }
+        }
+        self.error = result.err();
+    }
+
+    fn finish(self) -> io::Result<()> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        self.mix.finish()?;
+        for wav in self.stems {
+            wav.finish()?;
+        }
+        Ok(())
+    }
+}
+
+impl Emulator {
+    // starts a cartridge with no save file
+    pub fn new(cartridge: Cartridge) -> Result<Emulator, CartridgeError> {
+        Emulator::with_renderer(cartridge, Renderer::default())
+    }
+
+    pub fn with_renderer(
+        cartridge: Cartridge,
+        renderer: Renderer,
+    ) -> Result<Emulator, CartridgeError> {
+        Ok(Emulator::with_mbc(
+            mbc::from_cartridge(cartridge)?,
+            renderer,
+        ))
+    }
+
+    // for cartridges put together in code rather than loaded, like the
+    // one a .gbs file plays from
+    pub fn with_mbc(cartridge: Box<dyn Mbc>, renderer: Renderer) -> Emulator {
+        let bus = MemoryMap::with_ppu(cartridge, Ppu::with_renderer(renderer));
+        let mut cpu = Cpu::new(bus);
+        cpu.skip_boot_rom();
+        Emulator {
+            cpu,
+            save_path: None,
+            last_saved: None,
+            ram_was_enabled: false,
+            frame_overrun: 0,
+            recorder: None,
+        }
+    }
+
+    /*
+     * loads the rom at `path`. if the cartridge has a battery, its ram is
+     * kept in a .sav file next to the rom, which is read back here if
+     * there is one
+     */
+    pub fn load(path: impl AsRef<Path>) -> Result<Emulator, CartridgeError> {
+        Emulator::load_with_renderer(path, Renderer::default())
+    }
+
+    pub fn load_with_renderer(
+        path: impl AsRef<Path>,
+        renderer: Renderer,
+    ) -> Result<Emulator, CartridgeError> {
+        let path = path.as_ref();
+        let cartridge = Cartridge::load(path)?;
+        let battery = cartridge.header().cartridge_type.battery;
+        let mut emulator = Emulator::with_renderer(cartridge, renderer)?;
+        if battery {
+            let save_path = path.with_extension("sav");
+            match fs::read(&save_path) {
+                Ok(data) => {
+                    emulator.cpu.bus_mut().load_save_data(&data);
+                    emulator.last_saved = Some(data);
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+            emulator.save_path = Some(save_path);
+        }
+        Ok(emulator)
+    }
+
+    pub fn cpu(&self) -> &Cpu<MemoryMap> {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu<MemoryMap> {
+        &mut self.cpu
+    }
+
+    pub fn save_path(&self) -> Option<&Path> {
+        self.save_path.as_deref()
+    }
+
+    // runs one instruction, returns the M-cycles it took
+    pub fn step(&mut self) -> Result<u8, DecodeError> {
+        let cycles = self.cpu.step()?;
+        // flush when the game switches cartridge ram off. a failed write
+        // is tried again on the next flush, so it's not fatal here
+        let ram_enabled = self.cpu.bus().cartridge_ram_enabled();
+        if self.ram_was_enabled && !ram_enabled {
+            let _ = self.save();
+        }
+        self.ram_was_enabled = ram_enabled;
+        Ok(cycles)
+    }
+
+    // runs for one frame's worth of cycles
+    pub fn run_frame(&mut self) -> Result<(), DecodeError> {
+        let mut cycles = self.frame_overrun;
+        while cycles < CYCLES_PER_FRAME {
+            cycles += self.step()? as u32;
+        }
+        self.frame_overrun = cycles - CYCLES_PER_FRAME;
+        self.write_recording();
+        Ok(())
+    }
+
+    // for frontends, scripts and input replays. a press the game is
+    // looking at requests the joypad interrupt
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        self.cpu.bus_mut().set_button(button, pressed);
+    }
+
+    // resets the audio buffer, see Apu::set_sample_rate. a recording keeps
+    // the rate it was started at in its header, so set this first
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.cpu.bus_mut().apu_mut().set_sample_rate(sample_rate);
+    }
+
+    // interleaved stereo f32s, left first. returns how many were written
+    pub fn drain_samples(&mut self, out: &mut [f32]) -> usize {
+        self.cpu.bus_mut().apu_mut().buffer_mut().drain(out)
+    }
+
+    /*
+     * records the audio to a 16 bit stereo .wav at `path` until
+     * finish_recording, writing it out at the end of each frame. with
+     * `stems` each channel goes to a mono .wav of its own too, named after
+     * the channel: song.wav, song.pulse1.wav, song.pulse2.wav and so on
+     */
+    pub fn start_recording(&mut self, path: impl AsRef<Path>, stems: bool) -> io::Result<()> {
+        let path = path.as_ref();
+        let sample_rate = self.cpu.bus().apu().sample_rate();
+        let mix = WavWriter::create(path, sample_rate, 2)?;
+        let stems = if stems {
+            STEM_NAMES
+                .iter()
+                .map(|name| {
+                    let stem_path = path.with_extension(format!("{}.wav", name));
+                    WavWriter::create(stem_path, sample_rate, 1)
+                })
+                .collect::<io::Result<_>>()?
+        } else {
+            Vec::new()
+        };
+        self.cpu
+            .bus_mut()
+            .apu_mut()
+            .start_recording(!stems.is_empty());
+        self.recorder = Some(Recorder {
+            mix,
+            stems,
+            error: None,
+        });
+        Ok(())
+    }
+
+    // writes out the rest and closes the files, reporting the first write
+    // that failed along the way
+    pub fn finish_recording(&mut self) -> io::Result<()> {
+        self.write_recording();
+        self.cpu.bus_mut().apu_mut().stop_recording();
+        match self.recorder.take() {
+            Some(recorder) => recorder.finish(),
+            None => Ok(()),
+        }
+    }
+
+    fn write_recording(&mut self) {
+        let Some(recorder) = &mut self.recorder else {
+            return;
+        };
+        if let Some(recording) = self.cpu.bus_mut().apu_mut().take_recording() {
+            recorder.write(&recording);
+        }
+    }
+
+    // the last frame the ppu finished, see Ppu::frame
+    pub fn frame(&self) -> &[u8] {
+        self.cpu.bus().ppu().frame()
+    }
+
+    // writes battery backed ram to the .sav if it changed since last time
+    pub fn save(&mut self) -> io::Result<()> {
+        let Some(path) = &self.save_path else {
+            return Ok(());
+        };
+        let Some(data) = self.cpu.bus_mut().save_data() else {
+            return Ok(());
+        };
+        if self.last_saved.as_ref() == Some(&data) {
+            return Ok(());
+        }
+        fs::write(path, &data)?;
+        self.last_saved = Some(data);
+        Ok(())
+    }
+
+    // see Mbc::poll_event
+    pub fn poll_event(&mut self) -> Option<CartridgeEvent> {
+        self.cpu.bus_mut().poll_event()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    use crate::bus::Bus;
+    use crate::cartridge::tests::fix_checksums;
+    use crate::cartridge::tests::make_rom;
+    use crate::cartridge::Cartridge;
+    use crate::emulator::Emulator;
+    use crate::joypad::Button;
+    use crate::ppu::Renderer;
+    use crate::register_bank::Register;
+
+    // a fresh directory per test so they can run in parallel
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rust_gb_{}_{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // MBC1+RAM+BATTERY running `program` from 0x0100
+    fn battery_rom(program: &[u8]) -> Vec<u8> {
+        let mut rom = make_rom("SAVE", 0x03, 0x00, 0x02);
+        rom[0x0100..0x0100 + program.len()].copy_from_slice(program);
+        fix_checksums(&mut rom);
+        rom
+    }
+
+    #[test]
+    fn test_loads_existing_save() {
+        let dir = temp_dir("load_save");
+        let rom_path = dir.join("game.gb");
+        fs::write(&rom_path, battery_rom(&[])).unwrap();
+        let mut sav = vec![0; 0x2000];
+        sav[0x10] = 0x42;
+        fs::write(dir.join("game.sav"), &sav).unwrap();
+
+        let mut emulator = Emulator::load(&rom_path).unwrap();
+        assert_eq!(emulator.save_path(), Some(dir.join("game.sav").as_path()));
+        let bus = emulator.cpu_mut().bus_mut();
+        bus.write8(0x0000, 0x0A);
+        assert_eq!(bus.read8(0xA010), 0x42);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_flushes_when_ram_is_disabled() {
+        let dir = temp_dir("flush_save");
+        let rom_path = dir.join("game.gb");
+        // enable ram, write 0x99 to 0xA000, disable ram, spin
+        let program = [
+            0x3E, 0x0A, // LD A,0x0A
+            0xEA, 0x00, 0x00, // LD (0x0000),A
+            0x3E, 0x99, // LD A,0x99
+            0xEA, 0x00, 0xA0, // LD (0xA000),A
+            0xAF, // XOR A
+            0xEA, 0x00, 0x00, // LD (0x0000),A
+            0x18, 0xFE, // JR -2
+        ];
+        fs::write(&rom_path, battery_rom(&program)).unwrap();
+        let mut emulator = Emulator::load(&rom_path).unwrap();
+        for _ in 0..5 {
+            emulator.step().unwrap();
+        }
+        assert!(!dir.join("game.sav").exists());
+        emulator.step().unwrap();
+        let sav = fs::read(dir.join("game.sav")).unwrap();
+        assert_eq!(sav.len(), 0x2000);
+        assert_eq!(sav[0], 0x99);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_renderer_choice() {
+        let cartridge = Cartridge::from_bytes(make_rom("FIFO", 0x00, 0x00, 0x00)).unwrap();
+        let emulator = Emulator::with_renderer(cartridge, Renderer::Fifo).unwrap();
+        assert_eq!(emulator.cpu().bus().ppu().renderer(), Renderer::Fifo);
+    }
+
+    #[test]
+    fn test_set_button() {
+        // select the buttons row, then spin reading P1 into B
+        let program = [
+            0x3E, 0x10, // LD A,0x10
+            0xE0, 0x00, // LDH (0x00),A
+            0xF0, 0x00, // LDH A,(0x00)
+            0x47, // LD B,A
+            0x18, 0xFB, // JR -5
+        ];
+        let mut rom = make_rom("JOYPAD", 0x00, 0x00, 0x00);
+        rom[0x0100..0x0100 + program.len()].copy_from_slice(&program);
+        fix_checksums(&mut rom);
+        let mut emulator = Emulator::new(Cartridge::from_bytes(rom).unwrap()).unwrap();
+        for _ in 0..4 {
+            emulator.step().unwrap();
+        }
+        assert_eq!(emulator.cpu().registers().read(Register::B), 0xDF);
+        emulator.set_button(Button::A, true);
+        for _ in 0..4 {
+            emulator.step().unwrap();
+        }
+        assert_eq!(emulator.cpu().registers().read(Register::B), 0xDE);
+        assert_eq!(emulator.cpu().bus().read8(0xFF0F) & 0x10, 0x10);
+    }
+
+    #[test]
+    fn test_recording() {
+        let dir = temp_dir("recording");
+        // sound on, everything panned both ways at full volume, then pulse 2
+        // at 50% duty
+        let program = [
+            0x3E, 0x80, 0xE0, 0x26, // NR52 = 0x80
+            0x3E, 0x77, 0xE0, 0x24, // NR50 = 0x77
+            0x3E, 0xFF, 0xE0, 0x25, // NR51 = 0xFF
+            0x3E, 0x80, 0xE0, 0x16, // NR21 = 0x80
+            0x3E, 0xF0, 0xE0, 0x17, // NR22 = 0xF0
+            0x3E, 0x87, 0xE0, 0x19, // NR24 = 0x87
+            0x18, 0xFE, // JR -2
+        ];
+        let mut rom = make_rom("SOUND", 0x00, 0x00, 0x00);
+        rom[0x0100..0x0100 + program.len()].copy_from_slice(&program);
+        fix_checksums(&mut rom);
+        let mut emulator = Emulator::new(Cartridge::from_bytes(rom).unwrap()).unwrap();
+        emulator
+            .start_recording(dir.join("song.wav"), true)
+            .unwrap();
+        for _ in 0..10 {
+            emulator.run_frame().unwrap();
+        }
+        emulator.finish_recording().unwrap();
+
+        // 16 bit samples past the 44 byte header
+        let samples = |name: &str| -> Vec<i16> {
+            let bytes = fs::read(dir.join(name)).unwrap();
+            assert_eq!(&bytes[36..40], b"data");
+            bytes[44..]
+                .chunks(2)
+                .map(|b| i16::from_le_bytes([b[0], b[1]]))
+                .collect()
+        };
+        let mix = samples("song.wav");
+        // 10 frames at 44100 Hz, in stereo
+        let frames = 10 * 70224 * 44100 / 4194304;
+        assert!(mix.len().abs_diff(frames * 2) <= 2, "{}", mix.len());
+        assert!(mix.iter().any(|&s| s.abs() > 1000));
+        let pulse2 = samples("song.pulse2.wav");
+        assert_eq!(pulse2.len() * 2, mix.len());
+        assert!(pulse2.iter().any(|&s| s.abs() > 1000));
+        for name in ["song.pulse1.wav", "song.wave.wav", "song.noise.wav"] {
+            assert!(samples(name).iter().all(|&s| s == 0), "{}", name);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_no_save_without_battery() {
+        let dir = temp_dir("no_battery");
+        let rom_path = dir.join("game.gb");
+        // MBC1+RAM, no battery
+        fs::write(&rom_path, make_rom("SAVE", 0x02, 0x00, 0x02)).unwrap();
+        let mut emulator = Emulator::load(&rom_path).unwrap();
+        assert_eq!(emulator.save_path(), None);
+        emulator.save().unwrap();
+        assert!(!dir.join("game.sav").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_no_save_without_ram() {
+        // battery carts that say they have no ram: MBC5+RUMBLE+RAM+BATTERY
+        // and MBC3+RAM+BATTERY
+        for cartridge_type in [0x1E, 0x13] {
+            let dir = temp_dir(&format!("no_ram_{:02x}", cartridge_type));
+            let rom_path = dir.join("game.gb");
+            fs::write(&rom_path, make_rom("SAVE", cartridge_type, 0x00, 0x00)).unwrap();
+            let mut emulator = Emulator::load(&rom_path).unwrap();
+            emulator.save().unwrap();
+            assert!(!dir.join("game.sav").exists(), "{:#04X}", cartridge_type);
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_mbc3_save_keeps_rtc_block() {
+        let dir = temp_dir("rtc_save");
+        let rom_path = dir.join("game.gb");
+        // MBC3+TIMER+RAM+BATTERY
+        fs::write(&rom_path, make_rom("SAVE", 0x10, 0x00, 0x02)).unwrap();
+        let mut emulator = Emulator::load(&rom_path).unwrap();
+        emulator.cpu_mut().bus_mut().write8(0x0000, 0x0A);
+        emulator.cpu_mut().bus_mut().write8(0xA000, 0x77);
+        emulator.save().unwrap();
+        let sav = fs::read(dir.join("game.sav")).unwrap();
+        assert_eq!(sav.len(), 0x2000 + 48);
+        assert_eq!(sav[0], 0x77);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
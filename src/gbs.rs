@@ -0,0 +1,433 @@
+/*
+ * .gbs files: a game's music code and data ripped out of its rom, with a
+ * header saying where it loads and which routines set up a song and play
+ * a tick of it. there's no cartridge around it, so we build one: the data
+ * goes at its load address in an MBC5 rom with 8KiB of ram, and a little
+ * driver at 0x0100 calls init with the song in A, then play from the
+ * vblank or timer interrupt.
+ * spec: https://ocremix.org/info/GBS_Format_Specification
+ */
+use std::fmt;
+use std::fs;
+use std::io;
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use crate::apu::CLOCK_RATE;
+use crate::emulator::Emulator;
+use crate::emulator::CYCLES_PER_FRAME;
+use crate::interrupts::Interrupt;
+use crate::mbc::Mbc5;
+use crate::mbc::RAM_BANK_SIZE;
+use crate::mbc::ROM_BANK_SIZE;
+use crate::ppu::Renderer;
+
+pub const HEADER_SIZE: usize = 0x70;
+
+// an MBC5 banks in up to 512 16KiB banks
+const MAX_ROM_SIZE: usize = ROM_BANK_SIZE * 512;
+// below this the data would run over the vectors and the driver, and
+// from 0x8000 up it'd only be reachable by banking it in at 0x4000
+const LOAD_ADDRESSES: RangeInclusive<u16> = 0x0400..=0x7FFF;
+const DRIVER: usize = 0x0100;
+const VBLANK_VECTOR: usize = 0x0040;
+const TIMER_VECTOR: usize = 0x0050;
+
+const TAC_ENABLE: u8 = 1 << 2;
+// the rip wants a CGB in double speed mode, which we can't do
+const TAC_DOUBLE_SPEED: u8 = 1 << 7;
+
+#[derive(Debug)]
+pub enum GbsError {
+    Io(io::Error),
+    // not even long enough to hold the header
+    TooShort(usize),
+    BadMagic,
+    UnsupportedVersion(u8),
+    BadLoadAddress(u16),
+    // more than an MBC5 can bank in
+    TooLarge(usize),
+    // songs count from 1
+    NoSuchSong { song: u8, songs: u8 },
+}
+
+impl fmt::Display for GbsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GbsError::Io(e) => write!(f, "couldn't read gbs: {}", e),
+            GbsError::TooShort(len) => {
+                write!(f, "gbs is {} bytes, too short to hold a header", len)
+            }
+            GbsError::BadMagic => write!(f, "not a gbs file"),
+            GbsError::UnsupportedVersion(v) => write!(f, "unsupported gbs version {}", v),
+            GbsError::BadLoadAddress(address) => {
+                write!(
+                    f,
+                    "load address {:#06X} is outside 0x0400 - 0x7FFF",
+                    address
+                )
+            }
+            GbsError::TooLarge(len) => write!(f, "{} bytes of data won't fit in a rom", len),
+            GbsError::NoSuchSong { song, songs } => {
+                write!(f, "no song {}, there are {}", song, songs)
+            }
+        }
+    }
+}
+
+impl std::error::Error for GbsError {}
+
+impl From<io::Error> for GbsError {
+    fn from(e: io::Error) -> Self {
+        GbsError::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GbsHeader {
+    pub version: u8,
+    pub songs: u8,
+    // counting from 1
+    pub first_song: u8,
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    pub stack_pointer: u16,
+    pub timer_modulo: u8,
+    pub timer_control: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+}
+
+impl GbsHeader {
+    pub fn parse(bytes: &[u8]) -> Result<GbsHeader, GbsError> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(GbsError::TooShort(bytes.len()));
+        }
+        if &bytes[0x00..0x03] != b"GBS" {
+            return Err(GbsError::BadMagic);
+        }
+        if bytes[0x03] != 1 {
+            return Err(GbsError::UnsupportedVersion(bytes[0x03]));
+        }
+        let word = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]);
+        let load_address = word(0x06);
+        if !LOAD_ADDRESSES.contains(&load_address) {
+            return Err(GbsError::BadLoadAddress(load_address));
+        }
+        Ok(GbsHeader {
+            version: bytes[0x03],
+            songs: bytes[0x04],
+            first_song: bytes[0x05],
+            load_address,
+            init_address: word(0x08),
+            play_address: word(0x0A),
+            stack_pointer: word(0x0C),
+            timer_modulo: bytes[0x0E],
+            timer_control: bytes[0x0F],
+            title: text(&bytes[0x10..0x30]),
+            author: text(&bytes[0x30..0x50]),
+            copyright: text(&bytes[0x50..0x70]),
+        })
+    }
+
+    // whether play runs off the timer rather than vblank
+    pub fn uses_timer(&self) -> bool {
+        self.timer_control & TAC_ENABLE != 0
+    }
+
+    // how many times a second play gets called
+    pub fn play_rate(&self) -> f64 {
+        if !self.uses_timer() {
+            return CLOCK_RATE as f64 / 4.0 / CYCLES_PER_FRAME as f64;
+        }
+        let clock = match self.timer_control & 0x03 {
+            0b00 => 4096,
+            0b01 => 262_144,
+            0b10 => 65_536,
+            _ => 16_384,
+        };
+        clock as f64 / (256 - self.timer_modulo as u32) as f64
+    }
+}
+
+// NUL padded, and not always ascii
+fn text(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .take_while(|&&c| c != 0)
+        .map(|&c| {
+            if c.is_ascii_graphic() || c == b' ' {
+                c as char
+            } else {
+                '?'
+            }
+        })
+        .collect()
+}
+
+impl fmt::Display for GbsHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "title:     {}", self.title)?;
+        writeln!(f, "author:    {}", self.author)?;
+        writeln!(f, "copyright: {}", self.copyright)?;
+        writeln!(f, "songs:     {} (first {})", self.songs, self.first_song)?;
+        writeln!(
+            f,
+            "load:      {:#06X}, init {:#06X}, play {:#06X}",
+            self.load_address, self.init_address, self.play_address
+        )?;
+        let driven_by = if self.uses_timer() { "timer" } else { "vblank" };
+        write!(f, "rate:      {:.2} Hz ({})", self.play_rate(), driven_by)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Gbs {
+    header: GbsHeader,
+    // everything after the header, loaded at header.load_address
+    data: Vec<u8>,
+}
+
+impl Gbs {
+    pub fn load(path: impl AsRef<Path>) -> Result<Gbs, GbsError> {
+        Gbs::from_bytes(fs::read(path)?)
+    }
+
+    pub fn from_bytes(mut bytes: Vec<u8>) -> Result<Gbs, GbsError> {
+        let header = GbsHeader::parse(&bytes)?;
+        let data = bytes.split_off(HEADER_SIZE);
+        let len = header.load_address as usize + data.len();
+        if len > MAX_ROM_SIZE {
+            return Err(GbsError::TooLarge(data.len()));
+        }
+        Ok(Gbs { header, data })
+    }
+
+    pub fn header(&self) -> &GbsHeader {
+        &self.header
+    }
+
+    /*
+     * an emulator that's about to start `song` (counting from 1). the
+     * sound comes out like any other game's, so run frames and record or
+     * drain samples as usual
+     */
+    pub fn emulator(&self, song: u8) -> Result<Emulator, GbsError> {
+        if song == 0 || song > self.header.songs {
+            return Err(GbsError::NoSuchSong {
+                song,
+                songs: self.header.songs,
+            });
+        }
+        let rom = self.rom(song - 1);
+        let cartridge = Box::new(Mbc5::new(rom, RAM_BANK_SIZE, false));
+        Ok(Emulator::with_mbc(cartridge, Renderer::default()))
+    }
+
+    // the data at its load address, with our vectors and driver below it
+    fn rom(&self, song: u8) -> Vec<u8> {
+        let header = &self.header;
+        let load = header.load_address as usize;
+        let len = (load + self.data.len()).next_multiple_of(ROM_BANK_SIZE);
+        let mut rom = vec![0; len.max(ROM_BANK_SIZE * 2)];
+        rom[load..load + self.data.len()].copy_from_slice(&self.data);
+
+        // rips expect the RSTs to jump to the same offset from the load
+        // address
+        for rst in (0x00..0x40).step_by(8) {
+            let [lo, hi] = (header.load_address + rst as u16).to_le_bytes();
+            rom[rst..rst + 3].copy_from_slice(&[0xC3, lo, hi]);
+        }
+        let [play_lo, play_hi] = header.play_address.to_le_bytes();
+        let handler = [0xCD, play_lo, play_hi, 0xD9]; // CALL play, RETI
+        rom[VBLANK_VECTOR..VBLANK_VECTOR + 4].copy_from_slice(&handler);
+        rom[TIMER_VECTOR..TIMER_VECTOR + 4].copy_from_slice(&handler);
+
+        let [sp_lo, sp_hi] = header.stack_pointer.to_le_bytes();
+        let [init_lo, init_hi] = header.init_address.to_le_bytes();
+        let interrupt = if header.uses_timer() {
+            Interrupt::Timer
+        } else {
+            Interrupt::VBlank
+        };
+        let tac = header.timer_control & !TAC_DOUBLE_SPEED;
+        let driver: Vec<u8> = [
+            &[0xF3][..],                              // DI
+            &[0x31, sp_lo, sp_hi],                    // LD SP,stack_pointer
+            &[0x3E, 0x0A, 0xEA, 0x00, 0x00],          // cartridge ram on
+            &[0x3E, 0x80, 0xE0, 0x26],                // NR52 = 0x80, sound on
+            &[0x3E, 0x77, 0xE0, 0x24],                // NR50 = 0x77
+            &[0x3E, 0xFF, 0xE0, 0x25],                // NR51 = 0xFF
+            &[0x3E, header.timer_modulo, 0xE0, 0x06], // TMA
+            &[0x3E, tac, 0xE0, 0x07],                 // TAC
+            &[0x3E, song],                            // LD A,song
+            &[0xCD, init_lo, init_hi],                // CALL init
+            &[0x3E, interrupt.bit(), 0xE0, 0xFF],     // IE
+            &[0xAF, 0xE0, 0x0F],                      // IF = 0
+            &[0xFB],                                  // EI
+            &[0x76],                                  // HALT
+            &[0x18, 0xFD],                            // JR -3, back to the HALT
+        ]
+        .concat();
+        rom[DRIVER..DRIVER + driver.len()].copy_from_slice(&driver);
+        rom
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bus::Bus;
+    use crate::emulator::Emulator;
+    use crate::gbs::Gbs;
+    use crate::gbs::GbsError;
+    use crate::gbs::GbsHeader;
+    use crate::gbs::HEADER_SIZE;
+
+    // loads at 0x0400, with init there storing the song from A at 0xC001
+    // and play at 0x0410 counting its calls at 0xC000
+    fn make_gbs(songs: u8, tma: u8, tac: u8) -> Vec<u8> {
+        let mut gbs = vec![0; HEADER_SIZE];
+        gbs[0x00..0x04].copy_from_slice(b"GBS\x01");
+        gbs[0x04] = songs;
+        gbs[0x05] = 1;
+        gbs[0x06..0x08].copy_from_slice(&0x0400u16.to_le_bytes());
+        gbs[0x08..0x0A].copy_from_slice(&0x0400u16.to_le_bytes());
+        gbs[0x0A..0x0C].copy_from_slice(&0x0410u16.to_le_bytes());
+        gbs[0x0C..0x0E].copy_from_slice(&0xDFFFu16.to_le_bytes());
+        gbs[0x0E] = tma;
+        gbs[0x0F] = tac;
+        gbs[0x10..0x15].copy_from_slice(b"TUNES");
+        gbs[0x30..0x33].copy_from_slice(b"ME\xFF");
+
+        let mut code = vec![0; 0x20];
+        let init = [
+            0xEA, 0x01, 0xC0, // LD (0xC001),A
+            0xC9, // RET
+        ];
+        let play = [
+            0xFA, 0x00, 0xC0, // LD A,(0xC000)
+            0x3C, // INC A
+            0xEA, 0x00, 0xC0, // LD (0xC000),A
+            0xC9, // RET
+        ];
+        code[0x00..init.len()].copy_from_slice(&init);
+        code[0x10..0x10 + play.len()].copy_from_slice(&play);
+        gbs.extend(code);
+        gbs
+    }
+
+    fn run_seconds(emulator: &mut Emulator, seconds: u32) {
+        for _ in 0..seconds * 60 {
+            emulator.run_frame().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_header() {
+        let header = GbsHeader::parse(&make_gbs(3, 0x00, 0x00)).unwrap();
+        assert_eq!(header.songs, 3);
+        assert_eq!(header.first_song, 1);
+        assert_eq!(header.load_address, 0x0400);
+        assert_eq!(header.play_address, 0x0410);
+        assert_eq!(header.stack_pointer, 0xDFFF);
+        assert_eq!(header.title, "TUNES");
+        assert_eq!(header.author, "ME?");
+        assert_eq!(header.copyright, "");
+        assert!(!header.uses_timer());
+        assert!((header.play_rate() - 59.73).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_timer_rate() {
+        let header = GbsHeader::parse(&make_gbs(1, 0xC0, 0x04)).unwrap();
+        assert_eq!(header.play_rate(), 4096.0 / 64.0);
+        let header = GbsHeader::parse(&make_gbs(1, 0x00, 0x06)).unwrap();
+        assert_eq!(header.play_rate(), 256.0);
+    }
+
+    #[test]
+    fn test_bad_files() {
+        assert!(matches!(
+            GbsHeader::parse(&[0; 0x20]),
+            Err(GbsError::TooShort(0x20))
+        ));
+        let mut gbs = make_gbs(1, 0, 0);
+        gbs[0] = b'N';
+        assert!(matches!(Gbs::from_bytes(gbs), Err(GbsError::BadMagic)));
+        let mut gbs = make_gbs(1, 0, 0);
+        gbs[0x03] = 2;
+        assert!(matches!(
+            Gbs::from_bytes(gbs),
+            Err(GbsError::UnsupportedVersion(2))
+        ));
+        let mut gbs = make_gbs(1, 0, 0);
+        gbs[0x06..0x08].copy_from_slice(&0x0200u16.to_le_bytes());
+        assert!(matches!(
+            Gbs::from_bytes(gbs),
+            Err(GbsError::BadLoadAddress(0x0200))
+        ));
+        let mut gbs = make_gbs(1, 0, 0);
+        gbs[0x06..0x08].copy_from_slice(&0x8000u16.to_le_bytes());
+        assert!(matches!(
+            Gbs::from_bytes(gbs),
+            Err(GbsError::BadLoadAddress(0x8000))
+        ));
+    }
+
+    #[test]
+    fn test_song_select() {
+        let gbs = Gbs::from_bytes(make_gbs(3, 0x00, 0x00)).unwrap();
+        assert!(matches!(
+            gbs.emulator(0),
+            Err(GbsError::NoSuchSong { song: 0, songs: 3 })
+        ));
+        assert!(gbs.emulator(4).is_err());
+        let mut emulator = gbs.emulator(3).unwrap();
+        emulator.run_frame().unwrap();
+        // init sees it counting from 0
+        assert_eq!(emulator.cpu().bus().read8(0xC001), 2);
+    }
+
+    #[test]
+    fn test_play_on_vblank() {
+        let gbs = Gbs::from_bytes(make_gbs(1, 0x00, 0x00)).unwrap();
+        let mut emulator = gbs.emulator(1).unwrap();
+        run_seconds(&mut emulator, 2);
+        let calls = emulator.cpu().bus().read8(0xC000);
+        assert!((119..=120).contains(&calls), "{}", calls);
+    }
+
+    #[test]
+    fn test_play_on_timer() {
+        // 16384 Hz / 256, 64 times a second
+        let gbs = Gbs::from_bytes(make_gbs(1, 0x00, 0x07)).unwrap();
+        let mut emulator = gbs.emulator(1).unwrap();
+        run_seconds(&mut emulator, 2);
+        let calls = emulator.cpu().bus().read8(0xC000) as f64;
+        let expected = 2.0 * 60.0 / 59.73 * 64.0;
+        assert!((calls - expected).abs() <= 1.0, "{}", calls);
+    }
+
+    #[test]
+    fn test_banked_data() {
+        // a second bank of data, switched in by play through the MBC5
+        let mut bytes = make_gbs(1, 0x00, 0x00);
+        let play = [
+            0x3E, 0x01, 0xEA, 0x00, 0x20, // LD A,1; LD (0x2000),A
+            0xFA, 0x00, 0x40, // LD A,(0x4000)
+            0xEA, 0x00, 0xC0, // LD (0xC000),A
+            0xC9, // RET
+        ];
+        let play_at = HEADER_SIZE + 0x10;
+        bytes[play_at..play_at + play.len()].copy_from_slice(&play);
+        // the data starts at 0x0400, so bank 1 starts 0x3C00 in
+        bytes.resize(HEADER_SIZE + 0x3C00 + 1, 0);
+        bytes[HEADER_SIZE + 0x3C00] = 0x5A;
+        let mut emulator = Gbs::from_bytes(bytes).unwrap().emulator(1).unwrap();
+        emulator.run_frame().unwrap();
+        emulator.run_frame().unwrap();
+        assert_eq!(emulator.cpu().bus().read8(0xC000), 0x5A);
+    }
+}
@@ -1,11 +1,14 @@
 /*
  * pandocs: https://gbdev.io/pandocs/CPU_Registers_and_Flags.html
  * https://rgbds.gbdev.io/docs/v0.8.0/gbz80.7#LD__r16_,A
+ * opcode table: https://gbdev.io/gb-opcodes/optables/
  */
+use std::fmt;
 
 // arithmetic instructions can specify a target register.
-// some instructions will target an 8bit register, others 16bit
-// TODO - do I need the flag register? Probably not.
+// some instructions will target an 8bit register, others 16bit.
+// HLI is the byte in memory pointed at by HL, D8 is an immediate byte
+// that followed the opcode. Loads read their source through this too.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ArithmeticTarget {
     A,
@@ -15,9 +18,568 @@ pub enum ArithmeticTarget {
     E,
     H,
     L,
+    HLI,
+    D8(u8),
+}
+
+// an 8bit location that can be written to: a register or (HL)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteTarget {
+    A,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    HLI,
+}
+
+// 16bit register pairs used by LD rr,n16 / INC rr / DEC rr / ADD HL,rr
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WideTarget {
+    BC,
+    DE,
+    HL,
+    SP,
+}
+
+// PUSH and POP swap SP for AF
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackTarget {
+    BC,
+    DE,
+    HL,
+    AF,
+}
+
+// LD A,(rr) and LD (rr),A can address memory through these.
+// HLInc/HLDec are the (HL+) and (HL-) forms
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Indirect {
+    BC,
+    DE,
+    HLInc,
+    HLDec,
+}
+
+// branch conditions, checked against the flag register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    NZ,
+    Z,
+    NC,
+    C,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadType {
+    Byte(ByteTarget, ArithmeticTarget), // LD r,r / LD r,n8 / LD (HL),r
+    Word(WideTarget, u16),              // LD rr,n16
+    AFromIndirect(Indirect),            // LD A,(BC) / LD A,(HL+) ...
+    IndirectFromA(Indirect),            // LD (BC),A / LD (HL-),A ...
+    AFromAddress(u16),                  // LD A,(a16)
+    AddressFromA(u16),                  // LD (a16),A
+    AFromHighPage(u8),                  // LDH A,(a8)
+    HighPageFromA(u8),                  // LDH (a8),A
+    AFromHighC,                         // LDH A,(C)
+    HighCFromA,                         // LDH (C),A
+    AddressFromSp(u16),                 // LD (a16),SP
+    SpFromHl,                           // LD SP,HL
+    HlFromSpOffset(i8),                 // LD HL,SP+e8
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Instruction {
+    Nop,
+    Stop,
+    Halt,
+    Di,
+    Ei,
+    Ld(LoadType),
+    // 8bit alu, always operates on register A
     Add(ArithmeticTarget), // adds what's in target to register A
+    Adc(ArithmeticTarget),
+    Sub(ArithmeticTarget),
+    Sbc(ArithmeticTarget),
+    And(ArithmeticTarget),
+    Xor(ArithmeticTarget),
+    Or(ArithmeticTarget),
+    Cp(ArithmeticTarget),
+    Inc(ByteTarget),
+    Dec(ByteTarget),
+    // 16bit alu
+    AddHl(WideTarget),
+    AddSp(i8),
+    Inc16(WideTarget),
+    Dec16(WideTarget),
+    // accumulator and flag ops
+    Rlca,
+    Rrca,
+    Rla,
+    Rra,
+    Daa,
+    Cpl,
+    Scf,
+    Ccf,
+    // control flow. None is the unconditional version
+    Jp(Option<Condition>, u16),
+    JpHl,
+    Jr(Option<Condition>, i8),
+    Call(Option<Condition>, u16),
+    Ret(Option<Condition>),
+    Reti,
+    Rst(u8), // holds the address jumped to
+    Push(StackTarget),
+    Pop(StackTarget),
+    // 0xCB prefixed rotates and shifts
+    Rlc(ByteTarget),
+    Rrc(ByteTarget),
+    Rl(ByteTarget),
+    Rr(ByteTarget),
+    Sla(ByteTarget),
+    Sra(ByteTarget),
+    Srl(ByteTarget),
+    Swap(ByteTarget),
+    // 0xCB prefixed bit ops, the u8 is the bit index (0-7)
+    Bit(u8, ByteTarget),
+    Res(u8, ByteTarget),
+    Set(u8, ByteTarget),
+}
+
+// what decode hands back: the instruction, how many bytes it took up
+// (opcode included) and its cost in M-cycles. For conditional branches
+// the cost is the not-taken one, the cpu adds the rest when it branches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decoded {
+    pub instruction: Instruction,
+    pub length: u8,
+    pub cycles: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    // one of the 11 holes in the opcode table, the hardware locks up on these
+    IllegalOpcode(u8),
+    // next_bytes was too short to hold the opcode's operands
+    MissingOperand(u8),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::IllegalOpcode(op) => write!(f, "illegal opcode {:#04X}", op),
+            DecodeError::MissingOperand(op) => write!(f, "missing operand for opcode {:#04X}", op),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl From<ByteTarget> for ArithmeticTarget {
+    fn from(target: ByteTarget) -> Self {
+        match target {
+            ByteTarget::A => ArithmeticTarget::A,
+            ByteTarget::B => ArithmeticTarget::B,
+            ByteTarget::C => ArithmeticTarget::C,
+            ByteTarget::D => ArithmeticTarget::D,
+            ByteTarget::E => ArithmeticTarget::E,
+            ByteTarget::H => ArithmeticTarget::H,
+            ByteTarget::L => ArithmeticTarget::L,
+            ByteTarget::HLI => ArithmeticTarget::HLI,
+        }
+    }
+}
+
+// the opcode table encodes operands in 2 or 3 bit fields, these map them back
+fn byte_target(bits: u8) -> ByteTarget {
+    match bits & 0b111 {
+        0 => ByteTarget::B,
+        1 => ByteTarget::C,
+        2 => ByteTarget::D,
+        3 => ByteTarget::E,
+        4 => ByteTarget::H,
+        5 => ByteTarget::L,
+        6 => ByteTarget::HLI,
+        _ => ByteTarget::A,
+    }
+}
+
+fn wide_target(bits: u8) -> WideTarget {
+    match bits & 0b11 {
+        0 => WideTarget::BC,
+        1 => WideTarget::DE,
+        2 => WideTarget::HL,
+        _ => WideTarget::SP,
+    }
+}
+
+fn stack_target(bits: u8) -> StackTarget {
+    match bits & 0b11 {
+        0 => StackTarget::BC,
+        1 => StackTarget::DE,
+        2 => StackTarget::HL,
+        _ => StackTarget::AF,
+    }
+}
+
+fn indirect(bits: u8) -> Indirect {
+    match bits & 0b11 {
+        0 => Indirect::BC,
+        1 => Indirect::DE,
+        2 => Indirect::HLInc,
+        _ => Indirect::HLDec,
+    }
+}
+
+fn condition(bits: u8) -> Condition {
+    match bits & 0b11 {
+        0 => Condition::NZ,
+        1 => Condition::Z,
+        2 => Condition::NC,
+        _ => Condition::C,
+    }
+}
+
+// ADD, ADC, SUB, SBC, AND, XOR, OR, CP share bits 3-5 in both the
+// register (0x80-0xBF) and immediate (0xC6, 0xCE ..) forms
+fn alu(bits: u8, target: ArithmeticTarget) -> Instruction {
+    match bits & 0b111 {
+        0 => Instruction::Add(target),
+        1 => Instruction::Adc(target),
+        2 => Instruction::Sub(target),
+        3 => Instruction::Sbc(target),
+        4 => Instruction::And(target),
+        5 => Instruction::Xor(target),
+        6 => Instruction::Or(target),
+        _ => Instruction::Cp(target),
+    }
+}
+
+impl Instruction {
+    /*
+     * decodes the instruction starting with `opcode`. next_bytes are the bytes
+     * following it in memory, only as many as the operand needs are looked at.
+     * multi-byte operands are little endian.
+     */
+    pub fn decode(opcode: u8, next_bytes: &[u8]) -> Result<Decoded, DecodeError> {
+        let d8 = || {
+            next_bytes
+                .first()
+                .copied()
+                .ok_or(DecodeError::MissingOperand(opcode))
+        };
+        let d16 = || match next_bytes {
+            [lo, hi, ..] => Ok(u16::from_le_bytes([*lo, *hi])),
+            _ => Err(DecodeError::MissingOperand(opcode)),
+        };
+        // most of the table is laid out as xx_yyy_zzz
+        let y = (opcode >> 3) & 0b111;
+        let z = opcode & 0b111;
+        let p = y >> 1;
+
+        let (instruction, length, cycles) = match opcode {
+            0x00 => (Instruction::Nop, 1, 1),
+            0x10 => (Instruction::Stop, 2, 1), // STOP is followed by a padding byte
+            0x76 => (Instruction::Halt, 1, 1),
+            0xF3 => (Instruction::Di, 1, 1),
+            0xFB => (Instruction::Ei, 1, 1),
+
+            // 0x00 - 0x3F
+            0x01 | 0x11 | 0x21 | 0x31 => {
+                let load = LoadType::Word(wide_target(p), d16()?);
+                (Instruction::Ld(load), 3, 3)
+            }
+            0x02 | 0x12 | 0x22 | 0x32 => {
+                (Instruction::Ld(LoadType::IndirectFromA(indirect(p))), 1, 2)
+            }
+            0x0A | 0x1A | 0x2A | 0x3A => {
+                (Instruction::Ld(LoadType::AFromIndirect(indirect(p))), 1, 2)
+            }
+            0x03 | 0x13 | 0x23 | 0x33 => (Instruction::Inc16(wide_target(p)), 1, 2),
+            0x0B | 0x1B | 0x2B | 0x3B => (Instruction::Dec16(wide_target(p)), 1, 2),
+            0x09 | 0x19 | 0x29 | 0x39 => (Instruction::AddHl(wide_target(p)), 1, 2),
+            0x08 => (Instruction::Ld(LoadType::AddressFromSp(d16()?)), 3, 5),
+            0x18 => (Instruction::Jr(None, d8()? as i8), 2, 3),
+            0x20 | 0x28 | 0x30 | 0x38 => {
+                (Instruction::Jr(Some(condition(y - 4)), d8()? as i8), 2, 2)
+            }
+            0x00..=0x3F if z == 4 || z == 5 => {
+                let target = byte_target(y);
+                let cycles = if target == ByteTarget::HLI { 3 } else { 1 };
+                let ins = if z == 4 {
+                    Instruction::Inc(target)
+                } else {
+                    Instruction::Dec(target)
+                };
+                (ins, 1, cycles)
+            }
+            0x00..=0x3F if z == 6 => {
+                let target = byte_target(y);
+                let cycles = if target == ByteTarget::HLI { 3 } else { 2 };
+                let load = LoadType::Byte(target, ArithmeticTarget::D8(d8()?));
+                (Instruction::Ld(load), 2, cycles)
+            }
+            0x07 => (Instruction::Rlca, 1, 1),
+            0x0F => (Instruction::Rrca, 1, 1),
+            0x17 => (Instruction::Rla, 1, 1),
+            0x1F => (Instruction::Rra, 1, 1),
+            0x27 => (Instruction::Daa, 1, 1),
+            0x2F => (Instruction::Cpl, 1, 1),
+            0x37 => (Instruction::Scf, 1, 1),
+            0x3F => (Instruction::Ccf, 1, 1),
+
+            // 0x40 - 0x7F, LD r,r. 0x76 (LD (HL),(HL)) is HALT and matched above
+            0x40..=0x7F => {
+                let target = byte_target(y);
+                let source = byte_target(z);
+                let cycles = if target == ByteTarget::HLI || source == ByteTarget::HLI {
+                    2
+                } else {
+                    1
+                };
+                (
+                    Instruction::Ld(LoadType::Byte(target, source.into())),
+                    1,
+                    cycles,
+                )
+            }
+
+            // 0x80 - 0xBF, ALU A,r
+            0x80..=0xBF => {
+                let source = byte_target(z);
+                let cycles = if source == ByteTarget::HLI { 2 } else { 1 };
+                (alu(y, source.into()), 1, cycles)
+            }
+
+            // 0xC0 - 0xFF
+            0xC0 | 0xC8 | 0xD0 | 0xD8 => (Instruction::Ret(Some(condition(y))), 1, 2),
+            0xC9 => (Instruction::Ret(None), 1, 4),
+            0xD9 => (Instruction::Reti, 1, 4),
+            0xC1 | 0xD1 | 0xE1 | 0xF1 => (Instruction::Pop(stack_target(p)), 1, 3),
+            0xC5 | 0xD5 | 0xE5 | 0xF5 => (Instruction::Push(stack_target(p)), 1, 4),
+            0xC2 | 0xCA | 0xD2 | 0xDA => (Instruction::Jp(Some(condition(y)), d16()?), 3, 3),
+            0xC3 => (Instruction::Jp(None, d16()?), 3, 4),
+            0xE9 => (Instruction::JpHl, 1, 1),
+            0xC4 | 0xCC | 0xD4 | 0xDC => (Instruction::Call(Some(condition(y)), d16()?), 3, 3),
+            0xCD => (Instruction::Call(None, d16()?), 3, 6),
+            0xC6 | 0xCE | 0xD6 | 0xDE | 0xE6 | 0xEE | 0xF6 | 0xFE => {
+                (alu(y, ArithmeticTarget::D8(d8()?)), 2, 2)
+            }
+            0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => {
+                (Instruction::Rst(y * 8), 1, 4)
+            }
+            0xCB => return Ok(Instruction::decode_prefixed(d8()?)),
+            0xE0 => (Instruction::Ld(LoadType::HighPageFromA(d8()?)), 2, 3),
+            0xF0 => (Instruction::Ld(LoadType::AFromHighPage(d8()?)), 2, 3),
+            0xE2 => (Instruction::Ld(LoadType::HighCFromA), 1, 2),
+            0xF2 => (Instruction::Ld(LoadType::AFromHighC), 1, 2),
+            0xEA => (Instruction::Ld(LoadType::AddressFromA(d16()?)), 3, 4),
+            0xFA => (Instruction::Ld(LoadType::AFromAddress(d16()?)), 3, 4),
+            0xE8 => (Instruction::AddSp(d8()? as i8), 2, 4),
+            0xF8 => (Instruction::Ld(LoadType::HlFromSpOffset(d8()? as i8)), 2, 3),
+            0xF9 => (Instruction::Ld(LoadType::SpFromHl), 1, 2),
+
+            // 0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD
+            _ => return Err(DecodeError::IllegalOpcode(opcode)),
+        };
+
+        Ok(Decoded {
+            instruction,
+            length,
+            cycles,
+        })
+    }
+
+    /*
+     * the 0xCB table is completely regular: bits 6-7 pick the group,
+     * bits 3-5 the operation or bit index and bits 0-2 the operand.
+     * the length covers the prefix byte too.
+     */
+    fn decode_prefixed(opcode: u8) -> Decoded {
+        let y = (opcode >> 3) & 0b111;
+        let target = byte_target(opcode);
+        let instruction = match opcode >> 6 {
+            0 => match y {
+                0 => Instruction::Rlc(target),
+                1 => Instruction::Rrc(target),
+                2 => Instruction::Rl(target),
+                3 => Instruction::Rr(target),
+                4 => Instruction::Sla(target),
+                5 => Instruction::Sra(target),
+                6 => Instruction::Swap(target),
+                _ => Instruction::Srl(target),
+            },
+            1 => Instruction::Bit(y, target),
+            2 => Instruction::Res(y, target),
+            _ => Instruction::Set(y, target),
+        };
+        // (HL) costs two extra reads/writes, BIT only reads it back once
+        let cycles = match (instruction, target) {
+            (Instruction::Bit(..), ByteTarget::HLI) => 3,
+            (_, ByteTarget::HLI) => 4,
+            _ => 2,
+        };
+        Decoded {
+            instruction,
+            length: 2,
+            cycles,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::instruction::*;
+
+    fn decode(bytes: &[u8]) -> Decoded {
+        Instruction::decode(bytes[0], &bytes[1..]).unwrap()
+    }
+
+    #[test]
+    fn test_decode_every_opcode() {
+        let illegal = [
+            0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD,
+        ];
+        for opcode in 0..=0xFFu8 {
+            let result = Instruction::decode(opcode, &[0x00, 0x00]);
+            if illegal.contains(&opcode) {
+                assert_eq!(result, Err(DecodeError::IllegalOpcode(opcode)));
+            } else {
+                let decoded = result.unwrap();
+                assert!((1..=3).contains(&decoded.length), "{:#04X}", opcode);
+                assert!((1..=6).contains(&decoded.cycles), "{:#04X}", opcode);
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_ld_r_r() {
+        let decoded = decode(&[0x41]);
+        assert_eq!(
+            decoded.instruction,
+            Instruction::Ld(LoadType::Byte(ByteTarget::B, ArithmeticTarget::C))
+        );
+        assert_eq!((decoded.length, decoded.cycles), (1, 1));
+
+        let decoded = decode(&[0x7E]);
+        assert_eq!(
+            decoded.instruction,
+            Instruction::Ld(LoadType::Byte(ByteTarget::A, ArithmeticTarget::HLI))
+        );
+        assert_eq!((decoded.length, decoded.cycles), (1, 2));
+    }
+
+    #[test]
+    fn test_decode_alu() {
+        assert_eq!(
+            decode(&[0x80]).instruction,
+            Instruction::Add(ArithmeticTarget::B)
+        );
+        assert_eq!(
+            decode(&[0x8E]).instruction,
+            Instruction::Adc(ArithmeticTarget::HLI)
+        );
+        assert_eq!(
+            decode(&[0x97]).instruction,
+            Instruction::Sub(ArithmeticTarget::A)
+        );
+        assert_eq!(
+            decode(&[0xBC]).instruction,
+            Instruction::Cp(ArithmeticTarget::H)
+        );
+        let decoded = decode(&[0xEE, 0x42]);
+        assert_eq!(
+            decoded.instruction,
+            Instruction::Xor(ArithmeticTarget::D8(0x42))
+        );
+        assert_eq!((decoded.length, decoded.cycles), (2, 2));
+    }
+
+    #[test]
+    fn test_decode_immediates_are_little_endian() {
+        let decoded = decode(&[0x21, 0x34, 0x12]);
+        assert_eq!(
+            decoded.instruction,
+            Instruction::Ld(LoadType::Word(WideTarget::HL, 0x1234))
+        );
+        assert_eq!((decoded.length, decoded.cycles), (3, 3));
+        assert_eq!(
+            decode(&[0xC3, 0x50, 0x01]).instruction,
+            Instruction::Jp(None, 0x0150)
+        );
+    }
+
+    #[test]
+    fn test_decode_control_flow() {
+        let decoded = decode(&[0x20, 0xFE]);
+        assert_eq!(
+            decoded.instruction,
+            Instruction::Jr(Some(Condition::NZ), -2)
+        );
+        assert_eq!((decoded.length, decoded.cycles), (2, 2));
+        assert_eq!(
+            decode(&[0xD8]).instruction,
+            Instruction::Ret(Some(Condition::C))
+        );
+        assert_eq!(decode(&[0xFF]).instruction, Instruction::Rst(0x38));
+        assert_eq!(
+            decode(&[0xF5]).instruction,
+            Instruction::Push(StackTarget::AF)
+        );
+        assert_eq!(decode(&[0xCD, 0x00, 0x40]).cycles, 6);
+    }
+
+    #[test]
+    fn test_decode_prefixed() {
+        for opcode in 0..=0xFFu8 {
+            let decoded = decode(&[0xCB, opcode]);
+            assert_eq!(decoded.length, 2);
+        }
+        assert_eq!(
+            decode(&[0xCB, 0x00]).instruction,
+            Instruction::Rlc(ByteTarget::B)
+        );
+        assert_eq!(
+            decode(&[0xCB, 0x37]).instruction,
+            Instruction::Swap(ByteTarget::A)
+        );
+        assert_eq!(
+            decode(&[0xCB, 0x3E]).instruction,
+            Instruction::Srl(ByteTarget::HLI)
+        );
+        assert_eq!(
+            decode(&[0xCB, 0x7C]).instruction,
+            Instruction::Bit(7, ByteTarget::H)
+        );
+        assert_eq!(
+            decode(&[0xCB, 0x86]).instruction,
+            Instruction::Res(0, ByteTarget::HLI)
+        );
+        assert_eq!(
+            decode(&[0xCB, 0xFF]).instruction,
+            Instruction::Set(7, ByteTarget::A)
+        );
+        assert_eq!(decode(&[0xCB, 0x46]).cycles, 3);
+        assert_eq!(decode(&[0xCB, 0x06]).cycles, 4);
+        assert_eq!(decode(&[0xCB, 0xC6]).cycles, 4);
+        assert_eq!(decode(&[0xCB, 0x11]).cycles, 2);
+        assert_eq!(
+            Instruction::decode(0xCB, &[]),
+            Err(DecodeError::MissingOperand(0xCB))
+        );
+    }
+
+    #[test]
+    fn test_decode_missing_operand() {
+        assert_eq!(
+            Instruction::decode(0x01, &[0x00]),
+            Err(DecodeError::MissingOperand(0x01))
+        );
+        assert_eq!(
+            Instruction::decode(0x3E, &[]),
+            Err(DecodeError::MissingOperand(0x3E))
+        );
+        assert!(Instruction::decode(0x00, &[]).is_ok());
+    }
 }
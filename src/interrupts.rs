@@ -0,0 +1,78 @@
+/*
+ * pandocs: https://gbdev.io/pandocs/Interrupts.html
+ * IE (0xFFFF) and IF (0xFF0F) share a layout, one bit per source.
+ * when several are pending the lowest bit wins.
+ */
+
+pub const IE_ADDRESS: u16 = 0xFFFF;
+pub const IF_ADDRESS: u16 = 0xFF0F;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    VBlank,
+    LcdStat,
+    Timer,
+    Serial,
+    Joypad,
+}
+
+impl Interrupt {
+    // highest priority first
+    pub const ALL: [Interrupt; 5] = [
+        Interrupt::VBlank,
+        Interrupt::LcdStat,
+        Interrupt::Timer,
+        Interrupt::Serial,
+        Interrupt::Joypad,
+    ];
+
+    pub fn bit(self) -> u8 {
+        match self {
+            Interrupt::VBlank => 1 << 0,
+            Interrupt::LcdStat => 1 << 1,
+            Interrupt::Timer => 1 << 2,
+            Interrupt::Serial => 1 << 3,
+            Interrupt::Joypad => 1 << 4,
+        }
+    }
+
+    // where the cpu jumps to when it services the interrupt
+    pub fn vector(self) -> u16 {
+        match self {
+            Interrupt::VBlank => 0x40,
+            Interrupt::LcdStat => 0x48,
+            Interrupt::Timer => 0x50,
+            Interrupt::Serial => 0x58,
+            Interrupt::Joypad => 0x60,
+        }
+    }
+
+    // `pending` is IE & IF
+    pub fn highest_priority(pending: u8) -> Option<Interrupt> {
+        Interrupt::ALL.into_iter().find(|i| pending & i.bit() != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interrupts::Interrupt;
+
+    #[test]
+    fn test_highest_priority() {
+        assert_eq!(Interrupt::highest_priority(0), None);
+        assert_eq!(
+            Interrupt::highest_priority(0b1_1111),
+            Some(Interrupt::VBlank)
+        );
+        assert_eq!(
+            Interrupt::highest_priority(0b1_0100),
+            Some(Interrupt::Timer)
+        );
+        assert_eq!(
+            Interrupt::highest_priority(0b1_0000),
+            Some(Interrupt::Joypad)
+        );
+        // the unused top bits never count
+        assert_eq!(Interrupt::highest_priority(0b1110_0000), None);
+    }
+}
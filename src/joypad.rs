@@ -0,0 +1,201 @@
+/*
+ * P1/JOYP at 0xFF00. the eight buttons sit in a 2x4 matrix, the game picks
+ * a row by writing 0 to bit 4 (the d-pad) or bit 5 (the buttons) and reads
+ * the low nibble back, 0 meaning pressed. a line going from high to low
+ * requests the joypad interrupt.
+ * pandocs: https://gbdev.io/pandocs/Joypad_Input.html
+ */
+use crate::interrupts::Interrupt;
+
+pub const P1_ADDRESS: u16 = 0xFF00;
+
+const SELECT_DPAD: u8 = 1 << 4;
+const SELECT_BUTTONS: u8 = 1 << 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    pub const ALL: [Button; 8] = [
+        Button::Right,
+        Button::Left,
+        Button::Up,
+        Button::Down,
+        Button::A,
+        Button::B,
+        Button::Select,
+        Button::Start,
+    ];
+
+    // the d-pad in the low nibble, the rest in the high one, each in the
+    // order its row reads back in
+    fn bit(self) -> u8 {
+        match self {
+            Button::Right => 1 << 0,
+            Button::Left => 1 << 1,
+            Button::Up => 1 << 2,
+            Button::Down => 1 << 3,
+            Button::A => 1 << 4,
+            Button::B => 1 << 5,
+            Button::Select => 1 << 6,
+            Button::Start => 1 << 7,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Joypad {
+    // bits 4 and 5 as last written
+    select: u8,
+    // a set bit is a held button, see Button::bit
+    pressed: u8,
+    // IF bits raised since the last take_interrupts
+    interrupts: u8,
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Joypad::new()
+    }
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Joypad {
+            select: SELECT_DPAD | SELECT_BUTTONS,
+            pressed: 0,
+            interrupts: 0,
+        }
+    }
+
+    pub fn read8(&self) -> u8 {
+        0xC0 | self.select | self.lines()
+    }
+
+    pub fn write8(&mut self, v: u8) {
+        let before = self.lines();
+        self.select = v & (SELECT_DPAD | SELECT_BUTTONS);
+        self.check_falling(before);
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        let before = self.lines();
+        if pressed {
+            self.pressed |= button.bit();
+        } else {
+            self.pressed &= !button.bit();
+        }
+        self.check_falling(before);
+    }
+
+    pub fn is_pressed(&self, button: Button) -> bool {
+        self.pressed & button.bit() != 0
+    }
+
+    pub fn take_interrupts(&mut self) -> u8 {
+        std::mem::take(&mut self.interrupts)
+    }
+
+    // the low nibble as read, active low
+    fn lines(&self) -> u8 {
+        let mut low = 0;
+        if self.select & SELECT_DPAD == 0 {
+            low |= self.pressed & 0x0F;
+        }
+        if self.select & SELECT_BUTTONS == 0 {
+            low |= self.pressed >> 4;
+        }
+        !low & 0x0F
+    }
+
+    fn check_falling(&mut self, before: u8) {
+        if before & !self.lines() != 0 {
+            self.interrupts |= Interrupt::Joypad.bit();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interrupts::Interrupt;
+    use crate::joypad::Button;
+    use crate::joypad::Joypad;
+
+    #[test]
+    fn test_nothing_selected() {
+        let mut joypad = Joypad::new();
+        assert_eq!(joypad.read8(), 0xFF);
+        joypad.set_button(Button::A, true);
+        joypad.set_button(Button::Down, true);
+        assert_eq!(joypad.read8(), 0xFF);
+        assert_eq!(joypad.take_interrupts(), 0);
+    }
+
+    #[test]
+    fn test_rows() {
+        let mut joypad = Joypad::new();
+        joypad.set_button(Button::Left, true);
+        joypad.set_button(Button::Start, true);
+        joypad.write8(0x20);
+        assert_eq!(joypad.read8(), 0xED);
+        joypad.write8(0x10);
+        assert_eq!(joypad.read8(), 0xD7);
+        // both rows at once
+        joypad.write8(0x00);
+        assert_eq!(joypad.read8(), 0xC5);
+        joypad.set_button(Button::Left, false);
+        assert_eq!(joypad.read8(), 0xC7);
+        assert!(joypad.is_pressed(Button::Start));
+        assert!(!joypad.is_pressed(Button::Left));
+    }
+
+    #[test]
+    fn test_every_button() {
+        let mut joypad = Joypad::new();
+        for (i, button) in Button::ALL.into_iter().enumerate() {
+            joypad.set_button(button, true);
+            joypad.write8(if i < 4 { 0x20 } else { 0x10 });
+            assert_eq!(
+                joypad.read8() & 0x0F,
+                !(1 << (i % 4)) & 0x0F,
+                "{:?}",
+                button
+            );
+            joypad.set_button(button, false);
+        }
+    }
+
+    #[test]
+    fn test_press_raises_interrupt() {
+        let mut joypad = Joypad::new();
+        joypad.write8(0x10);
+        joypad.set_button(Button::B, true);
+        assert_eq!(joypad.take_interrupts(), Interrupt::Joypad.bit());
+        assert_eq!(joypad.take_interrupts(), 0);
+        // releasing is a rising edge
+        joypad.set_button(Button::B, false);
+        assert_eq!(joypad.take_interrupts(), 0);
+        // a d-pad press with only buttons selected doesn't show
+        joypad.set_button(Button::Up, true);
+        assert_eq!(joypad.take_interrupts(), 0);
+    }
+
+    #[test]
+    fn test_select_raises_interrupt() {
+        let mut joypad = Joypad::new();
+        joypad.set_button(Button::Up, true);
+        assert_eq!(joypad.take_interrupts(), 0);
+        // selecting the d-pad pulls the up line low
+        joypad.write8(0x20);
+        assert_eq!(joypad.take_interrupts(), Interrupt::Joypad.bit());
+    }
+}
@@ -0,0 +1,14 @@
+pub mod apu;
+pub mod bus;
+pub mod cartridge;
+pub mod cpu;
+pub mod emulator;
+pub mod gbs;
+pub mod instruction;
+pub mod interrupts;
+pub mod joypad;
+pub mod mbc;
+pub mod ppu;
+pub mod register_bank;
+pub mod timer;
+pub mod wav;
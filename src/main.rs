@@ -1,7 +1,126 @@
-mod cpu;
-mod instruction;
-mod register_bank;
+use std::env;
+use std::process;
+use std::str::FromStr;
+
+use rust_gb::apu::CLOCK_RATE;
+use rust_gb::cartridge::Cartridge;
+use rust_gb::emulator::Emulator;
+use rust_gb::emulator::CYCLES_PER_FRAME;
+use rust_gb::gbs::Gbs;
+
+const USAGE: &str = "usage: rust_gb <rom.gb> [--frames N [--wav out.wav [--stems]]]
+       rust_gb gbs <music.gbs> <out.wav> [--track N] [--seconds N] [--stems]";
 
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.split_first() {
+        Some((command, rest)) if command == "gbs" => play_gbs(rest),
+        _ => run_rom(&args),
+    }
+}
+
+fn run_rom(args: &[String]) {
+    let Some((path, rest)) = args.split_first() else {
+        usage();
+    };
+    let path = path.as_str();
+    let mut frames = None;
+    let mut wav = None;
+    let mut stems = false;
+    let mut rest = rest.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--frames" => frames = Some(value(&mut rest)),
+            "--wav" => wav = Some(value::<String>(&mut rest)),
+            "--stems" => stems = true,
+            _ => usage(),
+        }
+    }
+    // recording only makes sense running headless
+    if (wav.is_some() && frames.is_none()) || (stems && wav.is_none()) {
+        usage();
+    }
+    match Cartridge::load(path) {
+        Ok(cartridge) => println!("{}", cartridge),
+        Err(e) => fail(path, e),
+    }
+    let Some(frames) = frames else {
+        return;
+    };
+
+    // run headless, then write the save back out
+    let mut emulator = Emulator::load(path).unwrap_or_else(|e| fail(path, e));
+    if let Some(wav) = &wav {
+        if let Err(e) = emulator.start_recording(wav, stems) {
+            fail(wav, e);
+        }
+    }
+    run_frames(&mut emulator, path, frames);
+    if let Some(wav) = &wav {
+        if let Err(e) = emulator.finish_recording() {
+            fail(wav, e);
+        }
+    }
+    if let Err(e) = emulator.save() {
+        fail(path, e);
+    }
+}
+
+// renders one song to a .wav, the header's first song unless --track says
+fn play_gbs(args: &[String]) {
+    let [path, wav, rest @ ..] = args else {
+        usage();
+    };
+    let mut track = None;
+    let mut seconds: u32 = 60;
+    let mut stems = false;
+    let mut rest = rest.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--track" => track = Some(value(&mut rest)),
+            "--seconds" => seconds = value(&mut rest),
+            "--stems" => stems = true,
+            _ => usage(),
+        }
+    }
+
+    let gbs = Gbs::load(path).unwrap_or_else(|e| fail(path, e));
+    println!("{}", gbs.header());
+    let track = track.unwrap_or(gbs.header().first_song);
+    let mut emulator = gbs.emulator(track).unwrap_or_else(|e| fail(path, e));
+    if let Err(e) = emulator.start_recording(wav, stems) {
+        fail(wav, e);
+    }
+    let frames = seconds as f64 * CLOCK_RATE as f64 / 4.0 / CYCLES_PER_FRAME as f64;
+    run_frames(&mut emulator, path, frames.ceil() as u32);
+    if let Err(e) = emulator.finish_recording() {
+        fail(wav, e);
+    }
+}
+
+fn run_frames(emulator: &mut Emulator, path: &str, frames: u32) {
+    for _ in 0..frames {
+        if let Err(e) = emulator.run_frame() {
+            eprintln!("{}: stopped: {}", path, e);
+            break;
+        }
+    }
+}
+
+// the argument after a flag
+fn value<T: FromStr>(args: &mut std::slice::Iter<String>) -> T {
+    match args.next().map(|arg| arg.parse()) {
+        Some(Ok(v)) => v,
+        _ => usage(),
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn fail(path: &str, e: impl std::fmt::Display) -> ! {
+    eprintln!("{}: {}", path, e);
+    process::exit(1);
 }
@@ -0,0 +1,175 @@
+/*
+ * memory bank controllers sit between the bus and the cartridge's rom and
+ * ram, swapping banks in and out when the game writes to the rom area.
+ * pandocs: https://gbdev.io/pandocs/MBCs.html
+ */
+use std::fmt;
+
+use crate::cartridge::Cartridge;
+use crate::cartridge::CartridgeError;
+use crate::cartridge::MbcType;
+
+mod mbc1;
+mod mbc2;
+mod mbc3;
+mod mbc5;
+mod rtc;
+
+pub use mbc1::Mbc1;
+pub use mbc2::Mbc2;
+pub use mbc3::Mbc3;
+pub use mbc5::Mbc5;
+pub use rtc::Clock;
+pub use rtc::SystemClock;
+pub use rtc::RTC_SAVE_SIZE;
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+pub const RAM_BANK_SIZE: usize = 0x2000;
+
+// things happening on the cartridge a frontend might want to act on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CartridgeEvent {
+    // the rumble motor turned on (true) or off (false)
+    Rumble(bool),
+}
+
+pub trait Mbc: fmt::Debug {
+    // 0x0000 - 0x7FFF
+    fn read_rom(&self, address: u16) -> u8;
+    // writes to rom are how games talk to the controller
+    fn write_rom(&mut self, address: u16, v: u8);
+    // 0xA000 - 0xBFFF, address is relative to 0xA000
+    fn read_ram(&self, address: u16) -> u8;
+    fn write_ram(&mut self, address: u16, v: u8);
+
+    // battery backed state, laid out the way .sav files store it.
+    // None if there's nothing worth keeping
+    fn save_data(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn load_save_data(&mut self, _data: &[u8]) {}
+
+    // whether the game currently has cartridge ram switched on. games turn
+    // it off once they're done writing, which makes that a good time to save
+    fn ram_enabled(&self) -> bool {
+        false
+    }
+
+    // only the latest state of anything that changed since the last poll,
+    // so a game toggling the motor every frame can't pile them up. None if
+    // nothing has
+    fn poll_event(&mut self) -> Option<CartridgeEvent> {
+        None
+    }
+}
+
+pub fn from_cartridge(cartridge: Cartridge) -> Result<Box<dyn Mbc>, CartridgeError> {
+    from_cartridge_with_clock(cartridge, Box::new(SystemClock))
+}
+
+// like from_cartridge, but an MBC3 rtc reads the time from `clock`
+pub fn from_cartridge_with_clock(
+    cartridge: Cartridge,
+    clock: Box<dyn Clock>,
+) -> Result<Box<dyn Mbc>, CartridgeError> {
+    let header = cartridge.header().clone();
+    let ram_size = if header.cartridge_type.ram {
+        header.ram_size
+    } else {
+        0
+    };
+    let rom = cartridge.into_rom();
+    match header.cartridge_type.mbc {
+        MbcType::RomOnly => Ok(Box::new(RomOnly::new(rom, ram_size))),
+        MbcType::Mbc1 => Ok(Box::new(Mbc1::new(rom, ram_size))),
+        MbcType::Mbc2 => Ok(Box::new(Mbc2::new(rom))),
+        MbcType::Mbc3 => {
+            let clock = header.cartridge_type.timer.then_some(clock);
+            Ok(Box::new(Mbc3::new(rom, ram_size, clock)))
+        }
+        MbcType::Mbc5 => {
+            let rumble = header.cartridge_type.rumble;
+            Ok(Box::new(Mbc5::new(rom, ram_size, rumble)))
+        }
+        mbc => Err(CartridgeError::UnsupportedMbc(mbc)),
+    }
+}
+
+// reads byte `offset` of `bank`, banks past the end of the rom wrap around
+fn banked(data: &[u8], bank: usize, bank_size: usize, offset: u16) -> u8 {
+    if data.is_empty() {
+        return 0xFF;
+    }
+    data[(bank * bank_size + offset as usize) % data.len()]
+}
+
+// copies as much of a .sav into `ram` as fits, files from other
+// emulators aren't always the size the header says
+fn load_ram(ram: &mut [u8], data: &[u8]) {
+    let len = ram.len().min(data.len());
+    ram[..len].copy_from_slice(&data[..len]);
+}
+
+// 32KiB of rom mapped straight in, optionally with up to 8KiB of ram
+#[derive(Debug, Clone)]
+pub struct RomOnly {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+}
+
+impl RomOnly {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> Self {
+        RomOnly {
+            rom,
+            ram: vec![0; ram_size.min(RAM_BANK_SIZE)],
+        }
+    }
+}
+
+impl Mbc for RomOnly {
+    fn read_rom(&self, address: u16) -> u8 {
+        // reads past the end of a short rom float high
+        self.rom.get(address as usize).copied().unwrap_or(0xFF)
+    }
+
+    fn write_rom(&mut self, _address: u16, _v: u8) {}
+
+    fn read_ram(&self, address: u16) -> u8 {
+        self.ram.get(address as usize).copied().unwrap_or(0xFF)
+    }
+
+    fn write_ram(&mut self, address: u16, v: u8) {
+        if let Some(b) = self.ram.get_mut(address as usize) {
+            *b = v;
+        }
+    }
+
+    fn save_data(&mut self) -> Option<Vec<u8>> {
+        (!self.ram.is_empty()).then(|| self.ram.clone())
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        load_ram(&mut self.ram, data);
+    }
+
+    // there's no enable register, it's always on
+    fn ram_enabled(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::mbc::ROM_BANK_SIZE;
+
+    // every bank starts with its bank number, low byte then high byte
+    pub fn numbered_rom(banks: usize) -> Vec<u8> {
+        let mut rom = vec![0; banks * ROM_BANK_SIZE];
+        for bank in 0..banks {
+            rom[bank * ROM_BANK_SIZE] = bank as u8;
+            rom[bank * ROM_BANK_SIZE + 1] = (bank >> 8) as u8;
+        }
+        rom
+    }
+}
@@ -0,0 +1,249 @@
+/*
+ * pandocs: https://gbdev.io/pandocs/MBC1.html
+ * 0x0000 - 0x1FFF  ram enable, 0x0A in the low nibble turns it on
+ * 0x2000 - 0x3FFF  BANK1, low 5 bits of the rom bank. 0 reads as 1
+ * 0x4000 - 0x5FFF  BANK2, 2 more bits: the ram bank or rom bits 5-6
+ * 0x6000 - 0x7FFF  mode. in mode 1 BANK2 also applies to 0x0000 - 0x3FFF
+ *                  and to ram, in mode 0 both of those stay on bank 0
+ */
+use crate::mbc::banked;
+use crate::mbc::load_ram;
+use crate::mbc::Mbc;
+use crate::mbc::RAM_BANK_SIZE;
+use crate::mbc::ROM_BANK_SIZE;
+
+// every header in a multicart has the logo, a normal 1MiB rom only has one
+const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+#[derive(Debug, Clone)]
+pub struct Mbc1 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    bank1: u8,
+    bank2: u8,
+    advanced_mode: bool,
+    // MBC1M wires BANK2 to rom bits 4-5 instead of 5-6, so each of the
+    // four games gets 16 banks
+    multicart: bool,
+}
+
+impl Mbc1 {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> Self {
+        let multicart = is_multicart(&rom);
+        Mbc1 {
+            rom,
+            ram: vec![0; ram_size],
+            ram_enabled: false,
+            bank1: 1,
+            bank2: 0,
+            advanced_mode: false,
+            multicart,
+        }
+    }
+
+    pub fn is_multicart(&self) -> bool {
+        self.multicart
+    }
+
+    fn bank2_shift(&self) -> u8 {
+        if self.multicart {
+            4
+        } else {
+            5
+        }
+    }
+
+    fn low_rom_bank(&self) -> usize {
+        if self.advanced_mode {
+            (self.bank2 << self.bank2_shift()) as usize
+        } else {
+            0
+        }
+    }
+
+    fn high_rom_bank(&self) -> usize {
+        let bank1_mask = if self.multicart { 0x0F } else { 0x1F };
+        ((self.bank2 << self.bank2_shift()) | (self.bank1 & bank1_mask)) as usize
+    }
+
+    fn ram_bank(&self) -> usize {
+        if self.advanced_mode {
+            self.bank2 as usize
+        } else {
+            0
+        }
+    }
+}
+
+// MBC1M carts are 1MiB with a second header at the start of bank 0x10
+fn is_multicart(rom: &[u8]) -> bool {
+    let logo = 0x10 * ROM_BANK_SIZE + 0x0104;
+    rom.len() == 64 * ROM_BANK_SIZE && rom[logo..logo + NINTENDO_LOGO.len()] == NINTENDO_LOGO
+}
+
+impl Mbc for Mbc1 {
+    fn read_rom(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x3FFF => banked(&self.rom, self.low_rom_bank(), ROM_BANK_SIZE, address),
+            _ => banked(
+                &self.rom,
+                self.high_rom_bank(),
+                ROM_BANK_SIZE,
+                address - 0x4000,
+            ),
+        }
+    }
+
+    fn write_rom(&mut self, address: u16, v: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = v & 0x0F == 0x0A,
+            0x2000..=0x3FFF => {
+                // the zero check only looks at the 5 bits written, which is
+                // why banks 0x20, 0x40 and 0x60 can't be reached this way
+                self.bank1 = v & 0x1F;
+                if self.bank1 == 0 {
+                    self.bank1 = 1;
+                }
+            }
+            0x4000..=0x5FFF => self.bank2 = v & 0x03,
+            _ => self.advanced_mode = v & 0x01 != 0,
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        banked(&self.ram, self.ram_bank(), RAM_BANK_SIZE, address)
+    }
+
+    fn write_ram(&mut self, address: u16, v: u8) {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return;
+        }
+        let i = (self.ram_bank() * RAM_BANK_SIZE + address as usize) % self.ram.len();
+        self.ram[i] = v;
+    }
+
+    fn save_data(&mut self) -> Option<Vec<u8>> {
+        (!self.ram.is_empty()).then(|| self.ram.clone())
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        load_ram(&mut self.ram, data);
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.ram_enabled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mbc::mbc1::Mbc1;
+    use crate::mbc::mbc1::NINTENDO_LOGO;
+    use crate::mbc::tests::numbered_rom;
+    use crate::mbc::Mbc;
+    use crate::mbc::ROM_BANK_SIZE;
+
+    #[test]
+    fn test_rom_bank_switching() {
+        let mut mbc = Mbc1::new(numbered_rom(32), 0);
+        assert_eq!(mbc.read_rom(0x0000), 0);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+        mbc.write_rom(0x2000, 0x05);
+        assert_eq!(mbc.read_rom(0x4000), 5);
+        // only the low 5 bits count
+        mbc.write_rom(0x3FFF, 0xE7);
+        assert_eq!(mbc.read_rom(0x4000), 7);
+    }
+
+    #[test]
+    fn test_bank_zero_maps_to_one() {
+        let mut mbc = Mbc1::new(numbered_rom(128), 0);
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+        // 0x20 has zero in the low 5 bits, so it becomes 0x21
+        mbc.write_rom(0x4000, 0x01);
+        assert_eq!(mbc.read_rom(0x4000), 0x21);
+        mbc.write_rom(0x2000, 0x02);
+        assert_eq!(mbc.read_rom(0x4000), 0x22);
+    }
+
+    #[test]
+    fn test_bank_number_wraps_to_rom_size() {
+        let mut mbc = Mbc1::new(numbered_rom(4), 0);
+        mbc.write_rom(0x2000, 0x06);
+        assert_eq!(mbc.read_rom(0x4000), 2);
+    }
+
+    #[test]
+    fn test_advanced_mode_rom() {
+        let mut mbc = Mbc1::new(numbered_rom(128), 0);
+        mbc.write_rom(0x4000, 0x02);
+        // mode 0 keeps bank 0 at the bottom
+        assert_eq!(mbc.read_rom(0x0000), 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 0x41);
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_rom(0x0000), 0x40);
+        assert_eq!(mbc.read_rom(0x4000), 0x41);
+    }
+
+    #[test]
+    fn test_ram_enable() {
+        let mut mbc = Mbc1::new(numbered_rom(2), 0x2000);
+        mbc.write_ram(0x0000, 0x42);
+        assert_eq!(mbc.read_ram(0x0000), 0xFF);
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_ram(0x0000, 0x42);
+        assert_eq!(mbc.read_ram(0x0000), 0x42);
+        // any other value turns it back off
+        mbc.write_rom(0x1FFF, 0x00);
+        assert_eq!(mbc.read_ram(0x0000), 0xFF);
+        mbc.write_rom(0x0000, 0x1A);
+        assert_eq!(mbc.read_ram(0x0000), 0x42);
+    }
+
+    #[test]
+    fn test_ram_banks_need_advanced_mode() {
+        let mut mbc = Mbc1::new(numbered_rom(2), 0x8000);
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_ram(0x0000, 0x11);
+        mbc.write_rom(0x4000, 0x02);
+        // mode 0 still uses ram bank 0
+        assert_eq!(mbc.read_ram(0x0000), 0x11);
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_ram(0x0000), 0x00);
+        mbc.write_ram(0x0000, 0x22);
+        mbc.write_rom(0x4000, 0x00);
+        assert_eq!(mbc.read_ram(0x0000), 0x11);
+        mbc.write_rom(0x4000, 0x02);
+        assert_eq!(mbc.read_ram(0x0000), 0x22);
+    }
+
+    #[test]
+    fn test_multicart() {
+        let mut rom = numbered_rom(64);
+        for game in 0..4 {
+            let logo = game * 0x10 * ROM_BANK_SIZE + 0x0104;
+            rom[logo..logo + NINTENDO_LOGO.len()].copy_from_slice(&NINTENDO_LOGO);
+        }
+        let mut mbc = Mbc1::new(rom, 0);
+        assert!(mbc.is_multicart());
+        // BANK2 selects the game, BANK1 only uses 4 bits
+        mbc.write_rom(0x4000, 0x01);
+        mbc.write_rom(0x2000, 0x12);
+        assert_eq!(mbc.read_rom(0x4000), 0x12);
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_rom(0x0000), 0x10);
+        mbc.write_rom(0x4000, 0x03);
+        assert_eq!(mbc.read_rom(0x0000), 0x30);
+
+        assert!(!Mbc1::new(numbered_rom(64), 0).is_multicart());
+    }
+}
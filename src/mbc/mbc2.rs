@@ -0,0 +1,126 @@
+/*
+ * pandocs: https://gbdev.io/pandocs/MBC2.html
+ * everything under 0x4000 is one register, address bit 8 picks which:
+ * clear for ram enable, set for the 4 bit rom bank.
+ * the 512 half-byte ram only decodes 9 address bits, so it repeats
+ * all the way through 0xA000 - 0xBFFF.
+ */
+use crate::mbc::banked;
+use crate::mbc::Mbc;
+use crate::mbc::ROM_BANK_SIZE;
+
+const RAM_SIZE: usize = 512;
+
+#[derive(Debug, Clone)]
+pub struct Mbc2 {
+    rom: Vec<u8>,
+    ram: [u8; RAM_SIZE],
+    ram_enabled: bool,
+    rom_bank: u8,
+}
+
+impl Mbc2 {
+    pub fn new(rom: Vec<u8>) -> Self {
+        Mbc2 {
+            rom,
+            ram: [0; RAM_SIZE],
+            ram_enabled: false,
+            rom_bank: 1,
+        }
+    }
+}
+
+impl Mbc for Mbc2 {
+    fn read_rom(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x3FFF => banked(&self.rom, 0, ROM_BANK_SIZE, address),
+            _ => banked(
+                &self.rom,
+                self.rom_bank as usize,
+                ROM_BANK_SIZE,
+                address - 0x4000,
+            ),
+        }
+    }
+
+    fn write_rom(&mut self, address: u16, v: u8) {
+        match address {
+            0x0000..=0x3FFF if address & 0x0100 == 0 => self.ram_enabled = v & 0x0F == 0x0A,
+            0x0000..=0x3FFF => self.rom_bank = (v & 0x0F).max(1),
+            _ => {}
+        }
+    }
+
+    // only the low nibble is stored, the top one floats high
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        self.ram[address as usize % RAM_SIZE] | 0xF0
+    }
+
+    fn write_ram(&mut self, address: u16, v: u8) {
+        if self.ram_enabled {
+            self.ram[address as usize % RAM_SIZE] = v & 0x0F;
+        }
+    }
+
+    fn save_data(&mut self) -> Option<Vec<u8>> {
+        Some(self.ram.to_vec())
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        for (b, v) in self.ram.iter_mut().zip(data) {
+            *b = v & 0x0F;
+        }
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.ram_enabled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mbc::mbc2::Mbc2;
+    use crate::mbc::Mbc;
+    use crate::mbc::ROM_BANK_SIZE;
+
+    #[test]
+    fn test_address_bit_8_selects_register() {
+        let mut rom = vec![0; 16 * ROM_BANK_SIZE];
+        rom[5 * ROM_BANK_SIZE] = 5;
+        let mut mbc = Mbc2::new(rom);
+        // bit 8 clear: ram enable, doesn't touch the bank
+        mbc.write_rom(0x2005, 0x0A);
+        assert_eq!(mbc.read_rom(0x4000), 0);
+        assert_eq!(mbc.read_ram(0x0000), 0xF0);
+        // bit 8 set: rom bank
+        mbc.write_rom(0x2105, 0x05);
+        assert_eq!(mbc.read_rom(0x4000), 5);
+        mbc.write_rom(0x0100, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 0, "bank 0 becomes 1");
+        assert_eq!(mbc.read_ram(0x0000), 0xF0, "ram still enabled");
+    }
+
+    #[test]
+    fn test_half_byte_ram_repeats() {
+        let mut mbc = Mbc2::new(vec![0; 2 * ROM_BANK_SIZE]);
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_ram(0x0001, 0xAB);
+        assert_eq!(mbc.read_ram(0x0001), 0xFB);
+        assert_eq!(mbc.read_ram(0x0201), 0xFB);
+        assert_eq!(mbc.read_ram(0x1E01), 0xFB);
+        mbc.write_ram(0x1FFF, 0x03);
+        assert_eq!(mbc.read_ram(0x01FF), 0xF3);
+    }
+
+    #[test]
+    fn test_ram_disabled() {
+        let mut mbc = Mbc2::new(vec![0; 2 * ROM_BANK_SIZE]);
+        mbc.write_ram(0x0000, 0x05);
+        assert_eq!(mbc.read_ram(0x0000), 0xFF);
+        mbc.write_rom(0x0000, 0x0A);
+        assert_eq!(mbc.read_ram(0x0000), 0xF0);
+    }
+}
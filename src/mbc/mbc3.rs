@@ -0,0 +1,206 @@
+/*
+ * pandocs: https://gbdev.io/pandocs/MBC3.html
+ * 0x0000 - 0x1FFF  ram and rtc enable
+ * 0x2000 - 0x3FFF  7 bit rom bank, 0 reads as 1
+ * 0x4000 - 0x5FFF  0x00 - 0x07 picks a ram bank, 0x08 - 0x0C an rtc register
+ * 0x6000 - 0x7FFF  latch the clock with 0x00 then 0x01
+ */
+use crate::mbc::banked;
+use crate::mbc::load_ram;
+use crate::mbc::rtc::Clock;
+use crate::mbc::rtc::Rtc;
+use crate::mbc::Mbc;
+use crate::mbc::RAM_BANK_SIZE;
+use crate::mbc::ROM_BANK_SIZE;
+
+#[derive(Debug)]
+pub struct Mbc3 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    // None on carts without the TIMER chip
+    rtc: Option<Rtc>,
+    ram_enabled: bool,
+    rom_bank: u8,
+    // ram bank or rtc register
+    ram_select: u8,
+}
+
+impl Mbc3 {
+    pub fn new(rom: Vec<u8>, ram_size: usize, clock: Option<Box<dyn Clock>>) -> Self {
+        Mbc3 {
+            rom,
+            ram: vec![0; ram_size],
+            rtc: clock.map(Rtc::new),
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_select: 0,
+        }
+    }
+}
+
+impl Mbc for Mbc3 {
+    fn read_rom(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x3FFF => banked(&self.rom, 0, ROM_BANK_SIZE, address),
+            _ => banked(
+                &self.rom,
+                self.rom_bank as usize,
+                ROM_BANK_SIZE,
+                address - 0x4000,
+            ),
+        }
+    }
+
+    fn write_rom(&mut self, address: u16, v: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = v & 0x0F == 0x0A,
+            0x2000..=0x3FFF => self.rom_bank = (v & 0x7F).max(1),
+            0x4000..=0x5FFF => self.ram_select = v,
+            _ => {
+                if let Some(rtc) = &mut self.rtc {
+                    rtc.write_latch(v);
+                }
+            }
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        match (self.ram_select, &self.rtc) {
+            (0x00..=0x07, _) => banked(&self.ram, self.ram_select as usize, RAM_BANK_SIZE, address),
+            (0x08..=0x0C, Some(rtc)) => rtc.read(self.ram_select),
+            _ => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, v: u8) {
+        if !self.ram_enabled {
+            return;
+        }
+        match (self.ram_select, &mut self.rtc) {
+            (0x00..=0x07, _) if !self.ram.is_empty() => {
+                let i =
+                    (self.ram_select as usize * RAM_BANK_SIZE + address as usize) % self.ram.len();
+                self.ram[i] = v;
+            }
+            (0x08..=0x0C, Some(rtc)) => rtc.write(self.ram_select, v),
+            _ => {}
+        }
+    }
+
+    // ram followed by the 48 byte rtc block, when there's a clock
+    fn save_data(&mut self) -> Option<Vec<u8>> {
+        let mut data = self.ram.clone();
+        match &mut self.rtc {
+            Some(rtc) => data.extend_from_slice(&rtc.save()),
+            // nothing to keep
+            None if data.is_empty() => return None,
+            None => {}
+        }
+        Some(data)
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        load_ram(&mut self.ram, data);
+        if let (Some(rtc), Some(block)) = (&mut self.rtc, data.get(self.ram.len()..)) {
+            rtc.load(block);
+        }
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.ram_enabled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mbc::mbc3::Mbc3;
+    use crate::mbc::rtc::tests::FakeClock;
+    use crate::mbc::tests::numbered_rom;
+    use crate::mbc::Mbc;
+
+    #[test]
+    fn test_rom_banks() {
+        let mut mbc = Mbc3::new(numbered_rom(128), 0, None);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+        // unlike MBC1, 0x20 is reachable
+        mbc.write_rom(0x2000, 0x20);
+        assert_eq!(mbc.read_rom(0x4000), 0x20);
+        mbc.write_rom(0x2000, 0x7F);
+        assert_eq!(mbc.read_rom(0x4000), 0x7F);
+        assert_eq!(mbc.read_rom(0x0000), 0);
+    }
+
+    #[test]
+    fn test_ram_banks() {
+        let mut mbc = Mbc3::new(numbered_rom(2), 0x8000, None);
+        mbc.write_rom(0x0000, 0x0A);
+        for bank in 0..4 {
+            mbc.write_rom(0x4000, bank);
+            mbc.write_ram(0x0010, bank + 0x10);
+        }
+        for bank in 0..4 {
+            mbc.write_rom(0x4000, bank);
+            assert_eq!(mbc.read_ram(0x0010), bank + 0x10);
+        }
+    }
+
+    #[test]
+    fn test_rtc_registers() {
+        let clock = FakeClock::default();
+        let mut mbc = Mbc3::new(numbered_rom(2), 0x2000, Some(Box::new(clock.clone())));
+        mbc.write_rom(0x0000, 0x0A);
+        clock.advance(3 * 60 + 7);
+        mbc.write_rom(0x6000, 0x00);
+        mbc.write_rom(0x6000, 0x01);
+        mbc.write_rom(0x4000, 0x08);
+        assert_eq!(mbc.read_ram(0x0000), 7);
+        mbc.write_rom(0x4000, 0x09);
+        assert_eq!(mbc.read_ram(0x1FFF), 3);
+
+        // writing a register goes to the live clock, not the latch
+        mbc.write_ram(0x0000, 30);
+        assert_eq!(mbc.read_ram(0x0000), 3);
+        mbc.write_rom(0x6000, 0x00);
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_ram(0x0000), 30);
+
+        // ram bank 0 is untouched
+        mbc.write_rom(0x4000, 0x00);
+        assert_eq!(mbc.read_ram(0x0000), 0);
+    }
+
+    #[test]
+    fn test_no_rtc() {
+        let mut mbc = Mbc3::new(numbered_rom(2), 0x2000, None);
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_rom(0x4000, 0x08);
+        assert_eq!(mbc.read_ram(0x0000), 0xFF);
+    }
+
+    #[test]
+    fn test_save_data_has_rtc_block() {
+        let clock = FakeClock::default();
+        let mut mbc = Mbc3::new(numbered_rom(2), 0x2000, Some(Box::new(clock.clone())));
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_ram(0x0000, 0x99);
+        mbc.write_rom(0x4000, 0x0A);
+        mbc.write_ram(0x0000, 12);
+        let save = mbc.save_data().unwrap();
+        assert_eq!(save.len(), 0x2000 + 48);
+
+        clock.advance(3600);
+        let mut mbc = Mbc3::new(numbered_rom(2), 0x2000, Some(Box::new(clock.clone())));
+        mbc.load_save_data(&save);
+        mbc.write_rom(0x0000, 0x0A);
+        assert_eq!(mbc.read_ram(0x0000), 0x99);
+        mbc.write_rom(0x6000, 0x00);
+        mbc.write_rom(0x6000, 0x01);
+        mbc.write_rom(0x4000, 0x0A);
+        assert_eq!(mbc.read_ram(0x0000), 13, "time kept going while saved");
+    }
+}
@@ -0,0 +1,200 @@
+/*
+ * pandocs: https://gbdev.io/pandocs/MBC5.html
+ * 0x0000 - 0x1FFF  ram enable
+ * 0x2000 - 0x2FFF  low 8 bits of the rom bank, bank 0 is allowed here
+ * 0x3000 - 0x3FFF  bit 8 of the rom bank
+ * 0x4000 - 0x5FFF  ram bank 0x00 - 0x0F. on rumble carts bit 3 drives
+ *                  the motor instead
+ */
+use crate::mbc::banked;
+use crate::mbc::load_ram;
+use crate::mbc::CartridgeEvent;
+use crate::mbc::Mbc;
+use crate::mbc::RAM_BANK_SIZE;
+use crate::mbc::ROM_BANK_SIZE;
+
+const RUMBLE: u8 = 1 << 3;
+
+#[derive(Debug, Clone)]
+pub struct Mbc5 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    rom_bank: u16,
+    ram_bank: u8,
+    has_rumble: bool,
+    rumbling: bool,
+    // what the last poll_event said, so toggles in between cancel out
+    reported_rumbling: bool,
+}
+
+impl Mbc5 {
+    pub fn new(rom: Vec<u8>, ram_size: usize, has_rumble: bool) -> Self {
+        Mbc5 {
+            rom,
+            ram: vec![0; ram_size],
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            has_rumble,
+            rumbling: false,
+            reported_rumbling: false,
+        }
+    }
+
+    pub fn rumbling(&self) -> bool {
+        self.rumbling
+    }
+}
+
+impl Mbc for Mbc5 {
+    fn read_rom(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x3FFF => banked(&self.rom, 0, ROM_BANK_SIZE, address),
+            _ => banked(
+                &self.rom,
+                self.rom_bank as usize,
+                ROM_BANK_SIZE,
+                address - 0x4000,
+            ),
+        }
+    }
+
+    fn write_rom(&mut self, address: u16, v: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = v & 0x0F == 0x0A,
+            0x2000..=0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | v as u16,
+            0x3000..=0x3FFF => self.rom_bank = (self.rom_bank & 0xFF) | (v as u16 & 0x01) << 8,
+            0x4000..=0x5FFF => {
+                if self.has_rumble {
+                    self.rumbling = v & RUMBLE != 0;
+                    self.ram_bank = v & 0x07;
+                } else {
+                    self.ram_bank = v & 0x0F;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        banked(&self.ram, self.ram_bank as usize, RAM_BANK_SIZE, address)
+    }
+
+    fn write_ram(&mut self, address: u16, v: u8) {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return;
+        }
+        let i = (self.ram_bank as usize * RAM_BANK_SIZE + address as usize) % self.ram.len();
+        self.ram[i] = v;
+    }
+
+    fn save_data(&mut self) -> Option<Vec<u8>> {
+        (!self.ram.is_empty()).then(|| self.ram.clone())
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        load_ram(&mut self.ram, data);
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.ram_enabled
+    }
+
+    fn poll_event(&mut self) -> Option<CartridgeEvent> {
+        if self.rumbling == self.reported_rumbling {
+            return None;
+        }
+        self.reported_rumbling = self.rumbling;
+        Some(CartridgeEvent::Rumble(self.rumbling))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mbc::mbc5::Mbc5;
+    use crate::mbc::tests::numbered_rom;
+    use crate::mbc::CartridgeEvent;
+    use crate::mbc::Mbc;
+
+    #[test]
+    fn test_nine_bit_rom_bank() {
+        let mut mbc = Mbc5::new(numbered_rom(512), 0, false);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 0, "bank 0 isn't remapped");
+        mbc.write_rom(0x2000, 0xFF);
+        mbc.write_rom(0x3000, 0x01);
+        assert_eq!(mbc.read_rom(0x4000), 0xFF);
+        assert_eq!(mbc.read_rom(0x4001), 0x01);
+        mbc.write_rom(0x2000, 0x23);
+        assert_eq!(mbc.read_rom(0x4000), 0x23);
+        assert_eq!(mbc.read_rom(0x4001), 0x01, "bit 8 is kept");
+        mbc.write_rom(0x3000, 0x00);
+        assert_eq!(mbc.read_rom(0x4001), 0x00);
+    }
+
+    #[test]
+    fn test_sixteen_ram_banks() {
+        let mut mbc = Mbc5::new(numbered_rom(2), 16 * 0x2000, false);
+        mbc.write_rom(0x0000, 0x0A);
+        for bank in 0..16 {
+            mbc.write_rom(0x4000, bank);
+            mbc.write_ram(0x0000, bank);
+        }
+        for bank in 0..16 {
+            mbc.write_rom(0x4000, bank);
+            assert_eq!(mbc.read_ram(0x0000), bank);
+        }
+    }
+
+    #[test]
+    fn test_rumble_events() {
+        let mut mbc = Mbc5::new(numbered_rom(2), 4 * 0x2000, true);
+        assert_eq!(mbc.poll_event(), None);
+        mbc.write_rom(0x4000, 0x0A);
+        mbc.write_rom(0x4000, 0x09);
+        assert!(mbc.rumbling());
+        assert_eq!(mbc.poll_event(), Some(CartridgeEvent::Rumble(true)));
+        assert_eq!(mbc.poll_event(), None);
+        mbc.write_rom(0x4000, 0x01);
+        assert!(!mbc.rumbling());
+        assert_eq!(mbc.poll_event(), Some(CartridgeEvent::Rumble(false)));
+        assert_eq!(mbc.poll_event(), None);
+
+        // bit 3 isn't part of the ram bank on rumble carts
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_rom(0x4000, 0x01);
+        mbc.write_ram(0x0000, 0x77);
+        mbc.write_rom(0x4000, 0x09);
+        assert_eq!(mbc.read_ram(0x0000), 0x77);
+    }
+
+    #[test]
+    fn test_rumble_toggles_between_polls() {
+        let mut mbc = Mbc5::new(numbered_rom(2), 0, true);
+        // games pulse the motor to set its strength, nothing's kept but the
+        // latest state
+        for _ in 0..10_000 {
+            mbc.write_rom(0x4000, 0x08);
+            mbc.write_rom(0x4000, 0x00);
+        }
+        assert_eq!(mbc.poll_event(), None);
+        for _ in 0..10_000 {
+            mbc.write_rom(0x4000, 0x00);
+            mbc.write_rom(0x4000, 0x08);
+        }
+        assert_eq!(mbc.poll_event(), Some(CartridgeEvent::Rumble(true)));
+        assert_eq!(mbc.poll_event(), None);
+    }
+
+    #[test]
+    fn test_no_rumble_events_without_motor() {
+        let mut mbc = Mbc5::new(numbered_rom(2), 16 * 0x2000, false);
+        mbc.write_rom(0x4000, 0x08);
+        assert_eq!(mbc.poll_event(), None);
+    }
+}
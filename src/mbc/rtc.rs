@@ -0,0 +1,308 @@
+/*
+ * the MBC3 real time clock.
+ * pandocs: https://gbdev.io/pandocs/MBC3.html#the-clock-counter-registers
+ * 0x08 seconds  0x09 minutes  0x0A hours  0x0B low 8 bits of the day counter
+ * 0x0C bit 0: day counter bit 8, bit 6: halt, bit 7: day counter carry
+ */
+use std::fmt;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+// the rtc block other emulators append to the .sav: five live registers,
+// five latched registers (all as u32 little endian) and a u64 unix timestamp
+pub const RTC_SAVE_SIZE: usize = 48;
+
+const HALT: u8 = 1 << 6;
+const DAY_CARRY: u8 = 1 << 7;
+
+// where the rtc gets the time from, so tests can wind it forward
+pub trait Clock: fmt::Debug {
+    // seconds since the unix epoch
+    fn now(&self) -> u64;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Registers {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    days_low: u8,
+    days_high: u8,
+}
+
+impl Registers {
+    fn read(&self, register: u8) -> u8 {
+        match register {
+            0x08 => self.seconds,
+            0x09 => self.minutes,
+            0x0A => self.hours,
+            0x0B => self.days_low,
+            // unused bits read high
+            _ => self.days_high | 0x3E,
+        }
+    }
+
+    fn days(&self) -> u16 {
+        (self.days_high as u16 & 0x01) << 8 | self.days_low as u16
+    }
+
+    fn advance(&mut self, seconds: u64) {
+        let total = self.seconds as u64 + seconds;
+        self.seconds = (total % 60) as u8;
+        let total = self.minutes as u64 + total / 60;
+        self.minutes = (total % 60) as u8;
+        let total = self.hours as u64 + total / 60;
+        self.hours = (total % 24) as u8;
+        let days = self.days() as u64 + total / 24;
+        // the carry sticks until the game clears it
+        if days > 0x1FF {
+            self.days_high |= DAY_CARRY;
+        }
+        self.days_low = days as u8;
+        self.days_high = (self.days_high & !0x01) | ((days >> 8) & 0x01) as u8;
+    }
+
+    fn to_bytes(self) -> [u8; 20] {
+        let mut bytes = [0; 20];
+        let values = [
+            self.seconds,
+            self.minutes,
+            self.hours,
+            self.days_low,
+            self.days_high,
+        ];
+        for (i, v) in values.into_iter().enumerate() {
+            bytes[i * 4..i * 4 + 4].copy_from_slice(&(v as u32).to_le_bytes());
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Registers {
+        let v = |i: usize| bytes[i * 4];
+        Registers {
+            seconds: v(0),
+            minutes: v(1),
+            hours: v(2),
+            days_low: v(3),
+            days_high: v(4),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Rtc {
+    clock: Box<dyn Clock>,
+    live: Registers,
+    latched: Registers,
+    // when `live` was last brought up to date
+    last_update: u64,
+    // the latch fires on a 0x00 then 0x01 write
+    latch_armed: bool,
+}
+
+impl Rtc {
+    pub fn new(clock: Box<dyn Clock>) -> Self {
+        let last_update = clock.now();
+        Rtc {
+            clock,
+            live: Registers::default(),
+            latched: Registers::default(),
+            last_update,
+            latch_armed: false,
+        }
+    }
+
+    // catches the live registers up with the clock
+    fn update(&mut self) {
+        let now = self.clock.now();
+        if self.live.days_high & HALT == 0 {
+            self.live.advance(now.saturating_sub(self.last_update));
+        }
+        self.last_update = now;
+    }
+
+    pub fn write_latch(&mut self, v: u8) {
+        if self.latch_armed && v == 0x01 {
+            self.update();
+            self.latched = self.live;
+        }
+        self.latch_armed = v == 0x00;
+    }
+
+    // reads come from the latched copy
+    pub fn read(&self, register: u8) -> u8 {
+        self.latched.read(register)
+    }
+
+    pub fn write(&mut self, register: u8, v: u8) {
+        self.update();
+        match register {
+            0x08 => self.live.seconds = v & 0x3F,
+            0x09 => self.live.minutes = v & 0x3F,
+            0x0A => self.live.hours = v & 0x1F,
+            0x0B => self.live.days_low = v,
+            _ => self.live.days_high = v & (DAY_CARRY | HALT | 0x01),
+        }
+    }
+
+    pub fn save(&mut self) -> [u8; RTC_SAVE_SIZE] {
+        self.update();
+        let mut bytes = [0; RTC_SAVE_SIZE];
+        bytes[0..20].copy_from_slice(&self.live.to_bytes());
+        bytes[20..40].copy_from_slice(&self.latched.to_bytes());
+        bytes[40..48].copy_from_slice(&self.last_update.to_le_bytes());
+        bytes
+    }
+
+    /*
+     * restores a saved block and catches up on the time that passed while
+     * the emulator wasn't running. some emulators only write a 32bit
+     * timestamp (44 bytes), that's accepted too.
+     */
+    pub fn load(&mut self, bytes: &[u8]) {
+        if bytes.len() < 44 {
+            return;
+        }
+        self.live = Registers::from_bytes(&bytes[0..20]);
+        self.latched = Registers::from_bytes(&bytes[20..40]);
+        self.last_update = match bytes.get(40..48) {
+            Some(t) => u64::from_le_bytes(t.try_into().unwrap()),
+            None => u32::from_le_bytes(bytes[40..44].try_into().unwrap()) as u64,
+        };
+        self.update();
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use crate::mbc::rtc::Clock;
+    use crate::mbc::rtc::Rtc;
+
+    // a clock the test winds by hand
+    #[derive(Debug, Clone, Default)]
+    pub struct FakeClock(pub Rc<Cell<u64>>);
+
+    impl FakeClock {
+        pub fn advance(&self, seconds: u64) {
+            self.0.set(self.0.get() + seconds);
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> u64 {
+            self.0.get()
+        }
+    }
+
+    fn latch(rtc: &mut Rtc) {
+        rtc.write_latch(0x00);
+        rtc.write_latch(0x01);
+    }
+
+    // (seconds, minutes, hours, days)
+    fn time(rtc: &Rtc) -> (u8, u8, u8, u16) {
+        let days = (rtc.read(0x0C) as u16 & 0x01) << 8 | rtc.read(0x0B) as u16;
+        (rtc.read(0x08), rtc.read(0x09), rtc.read(0x0A), days)
+    }
+
+    #[test]
+    fn test_counts_time() {
+        let clock = FakeClock::default();
+        let mut rtc = Rtc::new(Box::new(clock.clone()));
+        clock.advance(61);
+        latch(&mut rtc);
+        assert_eq!(time(&rtc), (1, 1, 0, 0));
+        clock.advance(2 * 86400 + 3 * 3600);
+        // nothing changes until the next latch
+        assert_eq!(time(&rtc), (1, 1, 0, 0));
+        latch(&mut rtc);
+        assert_eq!(time(&rtc), (1, 1, 3, 2));
+    }
+
+    #[test]
+    fn test_latch_needs_zero_then_one() {
+        let clock = FakeClock::default();
+        let mut rtc = Rtc::new(Box::new(clock.clone()));
+        clock.advance(5);
+        rtc.write_latch(0x01);
+        assert_eq!(time(&rtc), (0, 0, 0, 0));
+        rtc.write_latch(0x00);
+        rtc.write_latch(0x02);
+        rtc.write_latch(0x01);
+        assert_eq!(time(&rtc), (0, 0, 0, 0));
+        latch(&mut rtc);
+        assert_eq!(time(&rtc), (5, 0, 0, 0));
+    }
+
+    #[test]
+    fn test_day_carry() {
+        let clock = FakeClock::default();
+        let mut rtc = Rtc::new(Box::new(clock.clone()));
+        rtc.write(0x0B, 0xFF);
+        rtc.write(0x0C, 0x01);
+        clock.advance(86400);
+        latch(&mut rtc);
+        assert_eq!(time(&rtc).3, 0);
+        assert_ne!(rtc.read(0x0C) & 0x80, 0);
+        // stays set as time goes on, until it's written
+        clock.advance(86400);
+        latch(&mut rtc);
+        assert_eq!(time(&rtc).3, 1);
+        assert_ne!(rtc.read(0x0C) & 0x80, 0);
+        rtc.write(0x0C, 0x00);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x0C) & 0x80, 0);
+    }
+
+    #[test]
+    fn test_halt() {
+        let clock = FakeClock::default();
+        let mut rtc = Rtc::new(Box::new(clock.clone()));
+        rtc.write(0x0C, 0x40);
+        rtc.write(0x08, 30);
+        clock.advance(100);
+        latch(&mut rtc);
+        assert_eq!(time(&rtc), (30, 0, 0, 0));
+        rtc.write(0x0C, 0x00);
+        clock.advance(10);
+        latch(&mut rtc);
+        assert_eq!(time(&rtc), (40, 0, 0, 0));
+    }
+
+    #[test]
+    fn test_save_and_load_catch_up() {
+        let clock = FakeClock::default();
+        clock.advance(1_000_000);
+        let mut rtc = Rtc::new(Box::new(clock.clone()));
+        rtc.write(0x0A, 5);
+        let saved = rtc.save();
+
+        // an hour passes with the emulator closed
+        clock.advance(3600);
+        let mut rtc = Rtc::new(Box::new(clock.clone()));
+        rtc.load(&saved);
+        latch(&mut rtc);
+        assert_eq!(time(&rtc), (0, 0, 6, 0));
+
+        // the 44 byte variant with a 32bit timestamp
+        let mut rtc = Rtc::new(Box::new(clock.clone()));
+        rtc.load(&saved[..44]);
+        latch(&mut rtc);
+        assert_eq!(time(&rtc), (0, 0, 6, 0));
+    }
+}
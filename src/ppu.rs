@@ -0,0 +1,963 @@
+/*
+ * the picture processing unit. each line takes 456 dots (4 per M-cycle):
+ * 80 in mode 2 scanning oam, 172 in mode 3 drawing and the rest in mode 0.
+ * lines 144 - 153 are mode 1, vblank, for 154 lines a frame.
+ * the scanline renderer draws a whole line at once when mode 3 ends, the
+ * pixel fifo renderer (see fifo.rs) draws dot by dot like the hardware.
+ * pandocs: https://gbdev.io/pandocs/Rendering.html
+ */
+use crate::interrupts::Interrupt;
+
+mod fifo;
+
+use fifo::Fifo;
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+pub const DOTS_PER_LINE: u16 = 456;
+pub const LINES_PER_FRAME: u8 = 154;
+const OAM_SCAN_DOTS: u16 = 80;
+const DRAWING_DOTS: u16 = 172;
+
+const VRAM_SIZE: usize = 0x2000;
+const OAM_SIZE: usize = 0xA0;
+const SPRITES_PER_LINE: usize = 10;
+
+// register addresses
+pub const LCDC: u16 = 0xFF40;
+pub const STAT: u16 = 0xFF41;
+pub const SCY: u16 = 0xFF42;
+pub const SCX: u16 = 0xFF43;
+pub const LY: u16 = 0xFF44;
+pub const LYC: u16 = 0xFF45;
+pub const BGP: u16 = 0xFF47;
+pub const OBP0: u16 = 0xFF48;
+pub const OBP1: u16 = 0xFF49;
+pub const WY: u16 = 0xFF4A;
+pub const WX: u16 = 0xFF4B;
+
+// LCDC bits
+const LCD_ENABLE: u8 = 1 << 7;
+const WINDOW_TILE_MAP: u8 = 1 << 6;
+const WINDOW_ENABLE: u8 = 1 << 5;
+const TILE_DATA_UNSIGNED: u8 = 1 << 4;
+const BG_TILE_MAP: u8 = 1 << 3;
+const OBJ_TALL: u8 = 1 << 2;
+const OBJ_ENABLE: u8 = 1 << 1;
+// on the DMG this blanks the window too
+const BG_ENABLE: u8 = 1 << 0;
+
+// STAT interrupt selects, each adds a condition to the STAT line
+const STAT_LYC: u8 = 1 << 6;
+const STAT_OAM: u8 = 1 << 5;
+const STAT_VBLANK: u8 = 1 << 4;
+const STAT_HBLANK: u8 = 1 << 3;
+const STAT_COINCIDENCE: u8 = 1 << 2;
+
+// sprite attribute bits
+const OBJ_BEHIND_BG: u8 = 1 << 7;
+const OBJ_Y_FLIP: u8 = 1 << 6;
+const OBJ_X_FLIP: u8 = 1 << 5;
+const OBJ_PALETTE_1: u8 = 1 << 4;
+
+// how mode 3 gets drawn, picked when the ppu is made
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Renderer {
+    // a whole line at once with mode 3 always 172 dots. fast, but
+    // register writes during mode 3 only show from the next line
+    #[default]
+    Scanline,
+    // the hardware's pixel fifo. mode 3 stretches with SCX, the window and
+    // sprites, and mid-line writes land on the pixel they happen at
+    Fifo,
+}
+
+// the low two bits of STAT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    HBlank = 0,
+    VBlank = 1,
+    OamScan = 2,
+    Drawing = 3,
+}
+
+// one 4 byte oam entry, positions are as stored (y + 16, x + 8)
+#[derive(Debug, Clone, Copy)]
+struct Sprite {
+    y: u8,
+    x: u8,
+    tile: u8,
+    attributes: u8,
+    // LCDC.2 as it was for the scan, it can change before the line's drawn
+    height: u8,
+}
+
+#[derive(Debug, Clone)]
+pub struct Ppu {
+    renderer: Renderer,
+    fifo: Fifo,
+    vram: [u8; VRAM_SIZE],
+    oam: [u8; OAM_SIZE],
+    lcdc: u8,
+    // only the interrupt select bits 3-6, the rest is worked out on read
+    stat: u8,
+    scy: u8,
+    scx: u8,
+    ly: u8,
+    lyc: u8,
+    bgp: u8,
+    obp0: u8,
+    obp1: u8,
+    wy: u8,
+    wx: u8,
+    mode: Mode,
+    // dots into the current line
+    dot: u16,
+    // set once LY has matched WY this frame, the window can't show before
+    wy_triggered: bool,
+    // the window keeps its own line count, it only moves on lines where
+    // the window was actually drawn
+    window_line: u8,
+    // what the oam scan found for this line, at most 10
+    line_sprites: Vec<Sprite>,
+    frame: Vec<u8>,
+    frame_ready: bool,
+    /*
+     * the STAT interrupt sources are ORed into one line and the interrupt
+     * is only requested when it goes from low to high. while one source
+     * holds it high, the others can't raise another ("STAT blocking")
+     */
+    stat_line: bool,
+    // IF bits raised since the last tick returned
+    interrupts: u8,
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Ppu::new()
+    }
+}
+
+impl Ppu {
+    pub fn new() -> Self {
+        Ppu::with_renderer(Renderer::default())
+    }
+
+    pub fn with_renderer(renderer: Renderer) -> Self {
+        Ppu {
+            renderer,
+            fifo: Fifo::default(),
+            vram: [0; VRAM_SIZE],
+            oam: [0; OAM_SIZE],
+            lcdc: 0,
+            stat: 0,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            bgp: 0,
+            obp0: 0,
+            obp1: 0,
+            wy: 0,
+            wx: 0,
+            mode: Mode::HBlank,
+            dot: 0,
+            wy_triggered: false,
+            window_line: 0,
+            line_sprites: Vec::with_capacity(SPRITES_PER_LINE),
+            frame: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            frame_ready: false,
+            stat_line: false,
+            interrupts: 0,
+        }
+    }
+
+    // 160x144 shades, 0 is lightest and 3 darkest, row by row
+    pub fn frame(&self) -> &[u8] {
+        &self.frame
+    }
+
+    // true once per frame, when vblank starts
+    pub fn take_frame_ready(&mut self) -> bool {
+        std::mem::take(&mut self.frame_ready)
+    }
+
+    pub fn renderer(&self) -> Renderer {
+        self.renderer
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn ly(&self) -> u8 {
+        self.ly
+    }
+
+    fn lcd_enabled(&self) -> bool {
+        self.lcdc & LCD_ENABLE != 0
+    }
+
+    // vram, oam and the lcd registers, by bus address
+    pub fn read8(&self, address: u16) -> u8 {
+        match address {
+            0x8000..=0x9FFF => self.vram[(address - 0x8000) as usize],
+            0xFE00..=0xFE9F => self.oam[(address - 0xFE00) as usize],
+            LCDC => self.lcdc,
+            STAT => {
+                let coincidence = if self.ly == self.lyc {
+                    STAT_COINCIDENCE
+                } else {
+                    0
+                };
+                0x80 | self.stat | coincidence | self.mode as u8
+            }
+            SCY => self.scy,
+            SCX => self.scx,
+            LY => self.ly,
+            LYC => self.lyc,
+            BGP => self.bgp,
+            OBP0 => self.obp0,
+            OBP1 => self.obp1,
+            WY => self.wy,
+            WX => self.wx,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write8(&mut self, address: u16, v: u8) {
+        match address {
+            0x8000..=0x9FFF => self.vram[(address - 0x8000) as usize] = v,
+            0xFE00..=0xFE9F => self.oam[(address - 0xFE00) as usize] = v,
+            LCDC => self.write_lcdc(v),
+            STAT => self.write_stat(v),
+            SCY => self.scy = v,
+            SCX => self.scx = v,
+            // LY is read only
+            LY => {}
+            LYC => {
+                self.lyc = v;
+                self.update_stat_line(self.stat_sources());
+            }
+            BGP => self.bgp = v,
+            OBP0 => self.obp0 = v,
+            OBP1 => self.obp1 = v,
+            WY => self.wy = v,
+            WX => self.wx = v,
+            _ => {}
+        }
+    }
+
+    fn write_lcdc(&mut self, v: u8) {
+        let was_enabled = self.lcd_enabled();
+        self.lcdc = v;
+        if was_enabled && !self.lcd_enabled() {
+            // switching off parks the ppu at the start of line 0
+            self.ly = 0;
+            self.dot = 0;
+            self.mode = Mode::HBlank;
+        } else if !was_enabled && self.lcd_enabled() {
+            self.start_frame();
+        }
+        self.update_stat_line(self.stat_sources());
+    }
+
+    /*
+     * on the DMG a write to STAT sets every select bit for a moment before
+     * the real value lands, so writing in hblank, vblank or with LY = LYC
+     * requests a STAT interrupt whatever's written. some games rely on it.
+     * pandocs: https://gbdev.io/pandocs/STAT.html#spurious-stat-interrupts
+     */
+    fn write_stat(&mut self, v: u8) {
+        let all = STAT_LYC | STAT_VBLANK | STAT_HBLANK;
+        self.stat = all;
+        self.update_stat_line(self.stat_sources());
+        self.stat = v & 0x78;
+        // going back down here isn't an edge anyone sees
+        self.stat_line = self.stat_sources();
+    }
+
+    // whether any selected STAT source is active right now
+    fn stat_sources(&self) -> bool {
+        if !self.lcd_enabled() {
+            return false;
+        }
+        let mode = match self.mode {
+            Mode::HBlank => STAT_HBLANK,
+            Mode::VBlank => STAT_VBLANK,
+            Mode::OamScan => STAT_OAM,
+            Mode::Drawing => 0,
+        };
+        self.stat & mode != 0 || (self.stat & STAT_LYC != 0 && self.ly == self.lyc)
+    }
+
+    fn update_stat_line(&mut self, line: bool) {
+        if line && !self.stat_line {
+            self.interrupts |= Interrupt::LcdStat.bit();
+        }
+        self.stat_line = line;
+    }
+
+    // runs for `cycles` M-cycles, returns the IF bits to raise
+    pub fn tick(&mut self, cycles: u8) -> u8 {
+        if self.lcd_enabled() {
+            for _ in 0..cycles as u16 * 4 {
+                self.step_dot();
+            }
+        }
+        std::mem::take(&mut self.interrupts)
+    }
+
+    fn step_dot(&mut self) {
+        self.dot += 1;
+        match self.mode {
+            Mode::OamScan if self.dot == OAM_SCAN_DOTS => {
+                self.scan_oam();
+                self.mode = Mode::Drawing;
+                if self.renderer == Renderer::Fifo {
+                    self.fifo.start_line(self.scx);
+                }
+            }
+            Mode::Drawing => {
+                let done = match self.renderer {
+                    Renderer::Scanline if self.dot == OAM_SCAN_DOTS + DRAWING_DOTS => {
+                        self.render_line();
+                        true
+                    }
+                    Renderer::Scanline => false,
+                    Renderer::Fifo => self.fifo_dot(),
+                };
+                if done {
+                    self.mode = Mode::HBlank;
+                }
+            }
+            _ => {}
+        }
+        if self.dot == DOTS_PER_LINE {
+            self.next_line();
+        }
+        self.update_stat_line(self.stat_sources());
+    }
+
+    fn next_line(&mut self) {
+        self.dot = 0;
+        self.ly += 1;
+        if self.ly == SCREEN_HEIGHT as u8 {
+            self.mode = Mode::VBlank;
+            self.frame_ready = true;
+            self.interrupts |= Interrupt::VBlank.bit();
+            // the mode 2 select fires going into vblank too, as if line
+            // 144 started with an oam scan
+            let oam = self.stat & STAT_OAM != 0;
+            self.update_stat_line(oam || self.stat_sources());
+        } else if self.ly == LINES_PER_FRAME {
+            self.start_frame();
+        } else if self.ly < SCREEN_HEIGHT as u8 {
+            self.start_line();
+        }
+    }
+
+    fn start_frame(&mut self) {
+        self.ly = 0;
+        self.dot = 0;
+        self.wy_triggered = false;
+        self.window_line = 0;
+        self.start_line();
+    }
+
+    fn start_line(&mut self) {
+        self.mode = Mode::OamScan;
+        if self.ly == self.wy {
+            self.wy_triggered = true;
+        }
+    }
+
+    fn sprite_height(&self) -> u8 {
+        if self.lcdc & OBJ_TALL != 0 {
+            16
+        } else {
+            8
+        }
+    }
+
+    // the first 10 sprites in oam order that overlap this line
+    fn scan_oam(&mut self) {
+        let height = self.sprite_height();
+        let ly = self.ly as i16;
+        self.line_sprites.clear();
+        for entry in self.oam.chunks_exact(4) {
+            let top = entry[0] as i16 - 16;
+            if ly < top || ly >= top + height as i16 {
+                continue;
+            }
+            self.line_sprites.push(Sprite {
+                y: entry[0],
+                x: entry[1],
+                tile: entry[2],
+                attributes: entry[3],
+                height,
+            });
+            if self.line_sprites.len() == SPRITES_PER_LINE {
+                break;
+            }
+        }
+        // on the DMG the leftmost sprite wins, oam order breaks ties.
+        // sort_by_key is stable so that comes for free
+        self.line_sprites.sort_by_key(|s| s.x);
+    }
+
+    // vram offset of row `y % 8` of tile `index` in the bg/window tile data
+    fn bg_tile_row(&self, index: u8, y: u8) -> usize {
+        let base = if self.lcdc & TILE_DATA_UNSIGNED != 0 {
+            index as usize * 16
+        } else {
+            // 0x9000 with a signed index
+            (0x1000 + index as i8 as isize * 16) as usize
+        };
+        base + (y as usize % 8) * 2
+    }
+
+    // colour index 0-3 of a pixel in tile `index` from the bg/window tile data
+    fn tile_pixel(&self, index: u8, x: u8, y: u8) -> u8 {
+        self.tile_row_pixel(self.bg_tile_row(index, y), x % 8)
+    }
+
+    // vram offset of the 32x32 tile map at 0x9800 or 0x9C00
+    fn tile_map(high_map: bool) -> usize {
+        if high_map {
+            0x1C00
+        } else {
+            0x1800
+        }
+    }
+
+    // `row` is the vram offset of the row's two bytes, bit 7 is the left pixel
+    fn tile_row_pixel(&self, row: usize, x: u8) -> u8 {
+        let bit = 7 - x;
+        let lo = (self.vram[row] >> bit) & 1;
+        let hi = (self.vram[row + 1] >> bit) & 1;
+        hi << 1 | lo
+    }
+
+    // colour index at (x, y) of the 256x256 map at 0x9800 or 0x9C00
+    fn map_pixel(&self, high_map: bool, x: u8, y: u8) -> u8 {
+        let index = self.vram[Ppu::tile_map(high_map) + (y as usize / 8) * 32 + x as usize / 8];
+        self.tile_pixel(index, x, y)
+    }
+
+    fn render_line(&mut self) {
+        let ly = self.ly;
+        let mut bg = [0u8; SCREEN_WIDTH];
+
+        if self.lcdc & BG_ENABLE != 0 {
+            let window = self.lcdc & WINDOW_ENABLE != 0 && self.wy_triggered && self.wx <= 166;
+            let mut drew_window = false;
+            for (x, pixel) in bg.iter_mut().enumerate() {
+                // WX is the window's left edge plus 7
+                let x = x as u8;
+                *pixel = if window && x as u16 + 7 >= self.wx as u16 {
+                    drew_window = true;
+                    let wx = x + 7 - self.wx;
+                    self.map_pixel(self.lcdc & WINDOW_TILE_MAP != 0, wx, self.window_line)
+                } else {
+                    let bx = x.wrapping_add(self.scx);
+                    let by = ly.wrapping_add(self.scy);
+                    self.map_pixel(self.lcdc & BG_TILE_MAP != 0, bx, by)
+                };
+            }
+            if drew_window {
+                self.window_line += 1;
+            }
+        }
+
+        let row = &mut self.frame[ly as usize * SCREEN_WIDTH..][..SCREEN_WIDTH];
+        for (shade, &color) in row.iter_mut().zip(bg.iter()) {
+            *shade = shade_of(self.bgp, color);
+        }
+
+        if self.lcdc & OBJ_ENABLE != 0 {
+            self.render_sprites(&bg);
+        }
+    }
+
+    fn render_sprites(&mut self, bg: &[u8; SCREEN_WIDTH]) {
+        let ly = self.ly;
+        for x in 0..SCREEN_WIDTH as u8 {
+            // the highest priority opaque sprite pixel here, even if it then
+            // loses to the background it still hides the sprites below it
+            let found = self.line_sprites.iter().find_map(|sprite| {
+                let left = sprite.x as i16 - 8;
+                if (x as i16) < left || x as i16 >= left + 8 {
+                    return None;
+                }
+                let color = self.sprite_pixel(sprite, sprite.height, (x as i16 - left) as u8, ly);
+                (color != 0).then_some((sprite, color))
+            });
+            let Some((sprite, color)) = found else {
+                continue;
+            };
+            if sprite.attributes & OBJ_BEHIND_BG != 0 && bg[x as usize] != 0 {
+                continue;
+            }
+            let palette = if sprite.attributes & OBJ_PALETTE_1 != 0 {
+                self.obp1
+            } else {
+                self.obp0
+            };
+            self.frame[ly as usize * SCREEN_WIDTH + x as usize] = shade_of(palette, color);
+        }
+    }
+
+    // colour index of a sprite's pixel, `x` from its left edge, `ly` the line
+    fn sprite_pixel(&self, sprite: &Sprite, height: u8, x: u8, ly: u8) -> u8 {
+        let x = if sprite.attributes & OBJ_X_FLIP != 0 {
+            7 - x
+        } else {
+            x
+        };
+        self.tile_row_pixel(self.sprite_row(sprite, height, ly), x)
+    }
+
+    // vram offset of the row of `sprite` that's on line `ly`. the row is
+    // wrapped to `height` first, a tall sprite drawn 8x8 after LCDC.2 was
+    // cleared mid-line can be past the end of it
+    fn sprite_row(&self, sprite: &Sprite, height: u8, ly: u8) -> usize {
+        let mut row = (ly + 16 - sprite.y) & (height - 1);
+        if sprite.attributes & OBJ_Y_FLIP != 0 {
+            row = height - 1 - row;
+        }
+        // 8x16 sprites ignore the tile's low bit, the bottom half is the next one
+        let tile = if height == 16 {
+            sprite.tile & 0xFE
+        } else {
+            sprite.tile
+        };
+        tile as usize * 16 + row as usize * 2
+    }
+}
+
+// maps a colour index through BGP/OBP0/OBP1
+fn shade_of(palette: u8, color: u8) -> u8 {
+    (palette >> (color * 2)) & 0x03
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::interrupts::Interrupt;
+    use crate::ppu::Mode;
+    use crate::ppu::Ppu;
+    use crate::ppu::Renderer;
+    use crate::ppu::SCREEN_WIDTH;
+
+    // identity palette, colour index n shows as shade n
+    const PALETTE: u8 = 0b11_10_01_00;
+
+    fn ppu_with_lcdc(lcdc: u8) -> Ppu {
+        ppu_with_renderer(Renderer::Scanline, lcdc)
+    }
+
+    // palettes set up, then the lcd switched on with `lcdc`
+    pub fn ppu_with_renderer(renderer: Renderer, lcdc: u8) -> Ppu {
+        let mut ppu = Ppu::with_renderer(renderer);
+        ppu.write8(0xFF47, PALETTE);
+        ppu.write8(0xFF48, PALETTE);
+        ppu.write8(0xFF49, !PALETTE);
+        ppu.write8(0xFF40, lcdc);
+        ppu
+    }
+
+    // fills tile `index` (in the 0x8000 block) with colour `color`
+    pub fn solid_tile(ppu: &mut Ppu, index: u16, color: u8) {
+        let lo = if color & 1 != 0 { 0xFF } else { 0x00 };
+        let hi = if color & 2 != 0 { 0xFF } else { 0x00 };
+        for row in 0..8 {
+            ppu.write8(0x8000 + index * 16 + row * 2, lo);
+            ppu.write8(0x8000 + index * 16 + row * 2 + 1, hi);
+        }
+    }
+
+    pub fn set_sprite(ppu: &mut Ppu, n: u16, y: u8, x: u8, tile: u8, attributes: u8) {
+        for (i, v) in [y, x, tile, attributes].into_iter().enumerate() {
+            ppu.write8(0xFE00 + n * 4 + i as u16, v);
+        }
+    }
+
+    pub fn run_frame(ppu: &mut Ppu) {
+        for _ in 0..154 * 456 / 4 {
+            ppu.tick(1);
+        }
+    }
+
+    pub fn pixel(ppu: &Ppu, x: usize, y: usize) -> u8 {
+        ppu.frame()[y * SCREEN_WIDTH + x]
+    }
+
+    #[test]
+    fn test_mode_timing() {
+        let mut ppu = ppu_with_lcdc(0x80);
+        assert_eq!(ppu.mode(), Mode::OamScan);
+        ppu.tick(19);
+        assert_eq!(ppu.mode(), Mode::OamScan);
+        ppu.tick(1);
+        assert_eq!(ppu.mode(), Mode::Drawing);
+        ppu.tick(43);
+        assert_eq!(ppu.mode(), Mode::HBlank);
+        assert_eq!(ppu.read8(0xFF41) & 0x03, 0);
+        ppu.tick(51);
+        assert_eq!(ppu.ly(), 1);
+        assert_eq!(ppu.mode(), Mode::OamScan);
+    }
+
+    #[test]
+    fn test_vblank() {
+        let mut ppu = ppu_with_lcdc(0x80);
+        let mut raised = 0;
+        for _ in 0..144 * 114 - 1 {
+            raised |= ppu.tick(1);
+        }
+        assert_eq!(raised, 0);
+        assert_eq!(ppu.tick(1), Interrupt::VBlank.bit());
+        assert_eq!(ppu.ly(), 144);
+        assert_eq!(ppu.mode(), Mode::VBlank);
+        assert!(ppu.take_frame_ready());
+        assert!(!ppu.take_frame_ready());
+        for _ in 0..10 * 114 {
+            ppu.tick(1);
+        }
+        assert_eq!(ppu.ly(), 0);
+        assert_eq!(ppu.mode(), Mode::OamScan);
+    }
+
+    #[test]
+    fn test_lcd_off_resets_ly() {
+        let mut ppu = ppu_with_lcdc(0x80);
+        ppu.tick(200);
+        assert_ne!(ppu.ly(), 0);
+        ppu.write8(0xFF40, 0x00);
+        assert_eq!(ppu.ly(), 0);
+        assert_eq!(ppu.tick(200), 0);
+        assert_eq!(ppu.ly(), 0);
+        assert_eq!(ppu.read8(0xFF41) & 0x03, 0);
+    }
+
+    #[test]
+    fn test_stat_read() {
+        let mut ppu = ppu_with_lcdc(0x80);
+        ppu.write8(0xFF41, 0xFF);
+        ppu.write8(0xFF44, 0x12);
+        // LY = LYC = 0, mode 2
+        assert_eq!(ppu.read8(0xFF41), 0xFE);
+        ppu.write8(0xFF45, 1);
+        assert_eq!(ppu.read8(0xFF41), 0xFA);
+    }
+
+    // STAT interrupts raised over the next `cycles` M-cycles
+    fn stat_interrupts(ppu: &mut Ppu, cycles: u32) -> u32 {
+        (0..cycles)
+            .filter(|_| ppu.tick(1) & Interrupt::LcdStat.bit() != 0)
+            .count() as u32
+    }
+
+    #[test]
+    fn test_stat_hblank_source() {
+        let mut ppu = ppu_with_lcdc(0x80);
+        // out of the way so the STAT write bug doesn't fire
+        ppu.write8(0xFF45, 0x99);
+        ppu.write8(0xFF41, 0x08);
+        // mode 0 starts 63 M-cycles into the line
+        assert_eq!(stat_interrupts(&mut ppu, 62), 0);
+        assert_eq!(stat_interrupts(&mut ppu, 1), 1);
+        assert_eq!(stat_interrupts(&mut ppu, 114 * 10), 10);
+    }
+
+    #[test]
+    fn test_stat_lyc_source() {
+        let mut ppu = ppu_with_lcdc(0x80);
+        ppu.write8(0xFF45, 5);
+        ppu.write8(0xFF41, 0x40);
+        assert_eq!(stat_interrupts(&mut ppu, 114 * 5 - 1), 0);
+        assert_eq!(ppu.read8(0xFF41) & 0x04, 0);
+        assert_eq!(stat_interrupts(&mut ppu, 1), 1);
+        assert_eq!(ppu.ly(), 5);
+        assert_eq!(ppu.read8(0xFF41) & 0x04, 0x04);
+        assert_eq!(stat_interrupts(&mut ppu, 154 * 114 - 1), 0);
+        assert_eq!(stat_interrupts(&mut ppu, 1), 1);
+    }
+
+    #[test]
+    fn test_lyc_write_raises_stat() {
+        let mut ppu = ppu_with_lcdc(0x80);
+        ppu.write8(0xFF45, 1);
+        ppu.write8(0xFF41, 0x40);
+        assert_eq!(stat_interrupts(&mut ppu, 1), 0);
+        ppu.write8(0xFF45, 0);
+        assert_eq!(stat_interrupts(&mut ppu, 1), 1);
+    }
+
+    #[test]
+    fn test_stat_blocking() {
+        let mut ppu = ppu_with_lcdc(0x80);
+        ppu.write8(0xFF45, 5);
+        ppu.write8(0xFF41, 0x48);
+        assert_eq!(stat_interrupts(&mut ppu, 114 * 4), 4);
+        // line 4's hblank is still holding the line high when LY hits LYC,
+        // and LYC holds it through line 5's hblank
+        assert_eq!(stat_interrupts(&mut ppu, 114), 1);
+        assert_eq!(stat_interrupts(&mut ppu, 114), 0);
+        assert_eq!(stat_interrupts(&mut ppu, 114), 1);
+    }
+
+    #[test]
+    fn test_stat_vblank_source() {
+        let mut ppu = ppu_with_lcdc(0x80);
+        ppu.write8(0xFF45, 0x99);
+        ppu.write8(0xFF41, 0x10);
+        assert_eq!(stat_interrupts(&mut ppu, 144 * 114 - 1), 0);
+        let raised = ppu.tick(1);
+        assert_eq!(raised, Interrupt::VBlank.bit() | Interrupt::LcdStat.bit());
+        assert_eq!(stat_interrupts(&mut ppu, 10 * 114 - 1), 0);
+    }
+
+    #[test]
+    fn test_stat_oam_source() {
+        let mut ppu = ppu_with_lcdc(0x00);
+        ppu.write8(0xFF41, 0x20);
+        ppu.write8(0xFF40, 0x80);
+        // the first line's oam scan, then one for each line after it and
+        // one more going into vblank
+        assert_eq!(stat_interrupts(&mut ppu, 1), 1);
+        assert_eq!(stat_interrupts(&mut ppu, 144 * 114 - 2), 143);
+        assert_eq!(stat_interrupts(&mut ppu, 1), 1);
+        assert_eq!(stat_interrupts(&mut ppu, 10 * 114 - 1), 0);
+    }
+
+    #[test]
+    fn test_stat_write_bug() {
+        let mut ppu = ppu_with_lcdc(0x80);
+        ppu.write8(0xFF45, 0x99);
+        // mode 2 and 3 are safe
+        ppu.write8(0xFF41, 0x00);
+        assert_eq!(stat_interrupts(&mut ppu, 30), 0);
+        ppu.write8(0xFF41, 0x00);
+        assert_eq!(stat_interrupts(&mut ppu, 1), 0);
+        // hblank
+        assert_eq!(stat_interrupts(&mut ppu, 40), 0);
+        assert_eq!(ppu.mode(), Mode::HBlank);
+        ppu.write8(0xFF41, 0x00);
+        assert_eq!(stat_interrupts(&mut ppu, 1), 1);
+        // LY = LYC, in mode 2
+        ppu.write8(0xFF45, 1);
+        assert_eq!(stat_interrupts(&mut ppu, 50), 0);
+        assert_eq!(ppu.mode(), Mode::OamScan);
+        ppu.write8(0xFF41, 0x00);
+        assert_eq!(stat_interrupts(&mut ppu, 1), 1);
+    }
+
+    #[test]
+    fn test_background_scroll() {
+        // lcd, bg on, unsigned tile data, map at 0x9800
+        let mut ppu = ppu_with_lcdc(0x91);
+        solid_tile(&mut ppu, 1, 3);
+        // tile (1, 1) of the map
+        ppu.write8(0x9800 + 32 + 1, 1);
+        run_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 7, 8), 0);
+        assert_eq!(pixel(&ppu, 8, 8), 3);
+        assert_eq!(pixel(&ppu, 15, 15), 3);
+        assert_eq!(pixel(&ppu, 16, 8), 0);
+
+        ppu.write8(0xFF43, 4);
+        ppu.write8(0xFF42, 2);
+        run_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 4, 6), 3);
+        assert_eq!(pixel(&ppu, 3, 6), 0);
+        assert_eq!(pixel(&ppu, 4, 5), 0);
+    }
+
+    #[test]
+    fn test_signed_tile_data() {
+        // tile data at 0x8800, index 0 is at 0x9000
+        let mut ppu = ppu_with_lcdc(0x81);
+        solid_tile(&mut ppu, 0x100, 2);
+        run_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 0, 0), 2);
+        // index 0xFF is the tile just below 0x9000
+        solid_tile(&mut ppu, 0xFF, 1);
+        ppu.write8(0x9800, 0xFF);
+        run_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 0, 0), 1);
+        assert_eq!(pixel(&ppu, 8, 0), 2);
+    }
+
+    #[test]
+    fn test_palette() {
+        let mut ppu = ppu_with_lcdc(0x91);
+        solid_tile(&mut ppu, 0, 1);
+        ppu.write8(0xFF47, 0b00_00_11_00);
+        run_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 0, 0), 3);
+    }
+
+    #[test]
+    fn test_window() {
+        // window on with its map at 0x9C00
+        let mut ppu = ppu_with_lcdc(0xF1);
+        solid_tile(&mut ppu, 1, 2);
+        for i in 0..0x400 {
+            ppu.write8(0x9C00 + i, 1);
+        }
+        ppu.write8(0xFF4A, 10);
+        ppu.write8(0xFF4B, 7 + 20);
+        // WY was still 0 when the first frame started
+        run_frame(&mut ppu);
+        run_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 20, 9), 0);
+        assert_eq!(pixel(&ppu, 19, 10), 0);
+        assert_eq!(pixel(&ppu, 20, 10), 2);
+        assert_eq!(pixel(&ppu, 159, 143), 2);
+    }
+
+    #[test]
+    fn test_window_line_counter_pauses() {
+        let mut ppu = ppu_with_lcdc(0xF1);
+        // window row 0 colour 1, row 8 colour 2
+        solid_tile(&mut ppu, 1, 1);
+        solid_tile(&mut ppu, 2, 2);
+        ppu.write8(0x9C00, 1);
+        ppu.write8(0x9C00 + 32, 2);
+        ppu.write8(0xFF4B, 7);
+        // draw 4 lines of window, hide it for 20, then bring it back
+        for _ in 0..4 * 114 {
+            ppu.tick(1);
+        }
+        ppu.write8(0xFF40, 0xD1);
+        for _ in 0..20 * 114 {
+            ppu.tick(1);
+        }
+        ppu.write8(0xFF40, 0xF1);
+        for _ in 0..20 * 114 {
+            ppu.tick(1);
+        }
+        // line 24 shows window line 4, line 28 window line 8
+        assert_eq!(pixel(&ppu, 0, 27), 1);
+        assert_eq!(pixel(&ppu, 0, 28), 2);
+    }
+
+    #[test]
+    fn test_bg_disable_blanks() {
+        let mut ppu = ppu_with_lcdc(0xF0);
+        solid_tile(&mut ppu, 0, 3);
+        run_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 0, 0), 0);
+        assert_eq!(pixel(&ppu, 100, 100), 0);
+    }
+
+    #[test]
+    fn test_sprite() {
+        // lcd, bg and sprites on
+        let mut ppu = ppu_with_lcdc(0x93);
+        solid_tile(&mut ppu, 1, 1);
+        set_sprite(&mut ppu, 0, 16 + 4, 8 + 10, 1, 0);
+        run_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 10, 4), 1);
+        assert_eq!(pixel(&ppu, 17, 11), 1);
+        assert_eq!(pixel(&ppu, 9, 4), 0);
+        assert_eq!(pixel(&ppu, 10, 12), 0);
+        // OBP1 is the inverted palette
+        set_sprite(&mut ppu, 0, 16 + 4, 8 + 10, 1, 0x10);
+        run_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 10, 4), 2);
+    }
+
+    #[test]
+    fn test_sprite_flips() {
+        let mut ppu = ppu_with_lcdc(0x93);
+        // only the top left pixel of tile 1 is set
+        ppu.write8(0x8010, 0x80);
+        set_sprite(&mut ppu, 0, 16, 8, 1, 0);
+        set_sprite(&mut ppu, 1, 16, 8 + 20, 1, 0x20);
+        set_sprite(&mut ppu, 2, 16, 8 + 40, 1, 0x40);
+        set_sprite(&mut ppu, 3, 16, 8 + 60, 1, 0x60);
+        run_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 0, 0), 1);
+        assert_eq!(pixel(&ppu, 27, 0), 1);
+        assert_eq!(pixel(&ppu, 40, 7), 1);
+        assert_eq!(pixel(&ppu, 67, 7), 1);
+    }
+
+    #[test]
+    fn test_tall_sprites() {
+        let mut ppu = ppu_with_lcdc(0x97);
+        solid_tile(&mut ppu, 2, 1);
+        solid_tile(&mut ppu, 3, 2);
+        // the low bit of the index is ignored
+        set_sprite(&mut ppu, 0, 16, 8, 3, 0);
+        set_sprite(&mut ppu, 1, 16, 8 + 20, 3, 0x40);
+        run_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 0, 0), 1);
+        assert_eq!(pixel(&ppu, 0, 15), 2);
+        assert_eq!(pixel(&ppu, 0, 16), 0);
+        assert_eq!(pixel(&ppu, 20, 0), 2);
+        assert_eq!(pixel(&ppu, 20, 15), 1);
+    }
+
+    #[test]
+    fn test_obj_size_change_after_scan() {
+        let mut ppu = ppu_with_lcdc(0x97);
+        solid_tile(&mut ppu, 2, 1);
+        solid_tile(&mut ppu, 3, 2);
+        // y flipped, so line 10 is row 5 of the top tile
+        set_sprite(&mut ppu, 0, 16, 8, 2, 0x40);
+        for _ in 0..10 * 114 + 30 {
+            ppu.tick(1);
+        }
+        assert_eq!(ppu.mode(), Mode::Drawing);
+        // back to 8x8 between the scan and the line being drawn
+        ppu.write8(0xFF40, 0x93);
+        run_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 0, 10), 1);
+    }
+
+    #[test]
+    fn test_sprite_priority() {
+        let mut ppu = ppu_with_lcdc(0x93);
+        solid_tile(&mut ppu, 1, 1);
+        solid_tile(&mut ppu, 2, 2);
+        // lower x wins even though it's later in oam
+        set_sprite(&mut ppu, 0, 16, 8 + 4, 1, 0);
+        set_sprite(&mut ppu, 1, 16, 8, 2, 0);
+        // same x, earlier in oam wins
+        set_sprite(&mut ppu, 2, 16 + 20, 8, 2, 0);
+        set_sprite(&mut ppu, 3, 16 + 20, 8, 1, 0);
+        run_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 4, 0), 2);
+        assert_eq!(pixel(&ppu, 8, 0), 1);
+        assert_eq!(pixel(&ppu, 0, 20), 2);
+    }
+
+    #[test]
+    fn test_sprite_behind_background() {
+        let mut ppu = ppu_with_lcdc(0x93);
+        // background is colour 0 except tile (1, 0)
+        solid_tile(&mut ppu, 1, 3);
+        ppu.write8(0x9801, 1);
+        solid_tile(&mut ppu, 2, 1);
+        set_sprite(&mut ppu, 0, 16, 8 + 4, 2, 0x80);
+        run_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 4, 0), 1);
+        assert_eq!(pixel(&ppu, 8, 0), 3);
+    }
+
+    #[test]
+    fn test_ten_sprites_per_line() {
+        let mut ppu = ppu_with_lcdc(0x93);
+        solid_tile(&mut ppu, 1, 1);
+        for n in 0..11 {
+            set_sprite(&mut ppu, n, 16, 8 + n as u8 * 8, 1, 0);
+        }
+        run_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 72, 0), 1);
+        assert_eq!(pixel(&ppu, 80, 0), 0);
+    }
+}
@@ -0,0 +1,444 @@
+/*
+ * mode 3 the way the hardware does it: a fetcher reads the background or
+ * window one tile at a time into a pixel fifo, which shifts one pixel out
+ * to the lcd every dot. sprites pause the fifo while their row is fetched
+ * and mixed into a second fifo.
+ * mode 3 takes 172 dots plus:
+ *   SCX % 8, the pixels fetched and thrown away to scroll finely
+ *   6 dots when the window starts, the fetcher starts over
+ *   6 dots per sprite, plus however long the fetcher needs to finish the
+ *   background tile it's on
+ * registers are read as the fetcher and fifo get to them, so writes in the
+ * middle of a line show from the pixel they land on.
+ * pandocs: https://gbdev.io/pandocs/pixel_fifo.html
+ */
+use std::collections::VecDeque;
+
+use crate::ppu::shade_of;
+use crate::ppu::Ppu;
+use crate::ppu::BG_ENABLE;
+use crate::ppu::BG_TILE_MAP;
+use crate::ppu::OBJ_BEHIND_BG;
+use crate::ppu::OBJ_ENABLE;
+use crate::ppu::OBJ_PALETTE_1;
+use crate::ppu::OBJ_X_FLIP;
+use crate::ppu::SCREEN_WIDTH;
+use crate::ppu::WINDOW_ENABLE;
+use crate::ppu::WINDOW_TILE_MAP;
+
+// the first fetch of each line is done and thrown away
+const STARTUP_DOTS: u8 = 6;
+const SPRITE_FETCH_DOTS: u8 = 6;
+
+// each step but Push takes 2 dots, Push waits for the fifo to empty
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum FetchStep {
+    #[default]
+    Tile,
+    DataLow,
+    DataHigh,
+    Push,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct ObjPixel {
+    color: u8,
+    palette_1: bool,
+    behind_bg: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(super) struct Fifo {
+    bg: VecDeque<u8>,
+    obj: VecDeque<ObjPixel>,
+    step: FetchStep,
+    // dots spent on the current step
+    step_dots: u8,
+    // tile column the fetcher is on, counted from the left of the line or
+    // of the window
+    tile_x: u8,
+    tile_index: u8,
+    data_low: u8,
+    data_high: u8,
+    // pixels sent to the lcd so far this line
+    x: u8,
+    // pixels still to drop off the front of the fifo
+    discard: u8,
+    startup: u8,
+    in_window: bool,
+    // line_sprites before this have been fetched
+    next_sprite: usize,
+    // dots left on the sprite fetch in progress
+    sprite_dots: u8,
+}
+
+impl Fifo {
+    pub(super) fn start_line(&mut self, scx: u8) {
+        *self = Fifo {
+            discard: scx % 8,
+            startup: STARTUP_DOTS,
+            ..Fifo::default()
+        };
+    }
+}
+
+impl Ppu {
+    // one dot of mode 3, true once the line's last pixel is out
+    pub(super) fn fifo_dot(&mut self) -> bool {
+        if self.fifo.startup > 0 {
+            self.fifo.startup -= 1;
+            return false;
+        }
+
+        if !self.fifo.in_window && self.window_starts() {
+            let fifo = &mut self.fifo;
+            fifo.in_window = true;
+            fifo.bg.clear();
+            fifo.step = FetchStep::Tile;
+            fifo.step_dots = 0;
+            fifo.tile_x = 0;
+            // WX below 7 puts the window's left edge off screen
+            fifo.discard = 7u8.saturating_sub(self.wx);
+        }
+
+        if self.fifo.sprite_dots > 0 {
+            self.fifo.sprite_dots -= 1;
+            if self.fifo.sprite_dots == 0 {
+                self.merge_sprite();
+            }
+            return false;
+        }
+
+        if self.sprite_waiting() {
+            // the fetcher finishes the tile it's on before the sprite is
+            // fetched, and the fifo holds still all the while
+            if self.fifo.step == FetchStep::Push {
+                self.push_tile();
+                self.fifo.sprite_dots = SPRITE_FETCH_DOTS - 1;
+            } else {
+                self.fetch_dot();
+            }
+            return false;
+        }
+
+        self.fetch_dot();
+        self.shift_pixel();
+        if self.fifo.x as usize == SCREEN_WIDTH {
+            if self.fifo.in_window {
+                self.window_line += 1;
+            }
+            return true;
+        }
+        false
+    }
+
+    fn window_starts(&self) -> bool {
+        self.lcdc & WINDOW_ENABLE != 0
+            && self.lcdc & BG_ENABLE != 0
+            && self.wy_triggered
+            && self.fifo.x as u16 + 7 >= self.wx as u16
+    }
+
+    // the next sprite, by x, has reached the fifo's output
+    fn sprite_waiting(&self) -> bool {
+        self.lcdc & OBJ_ENABLE != 0
+            && self
+                .line_sprites
+                .get(self.fifo.next_sprite)
+                .is_some_and(|s| s.x <= self.fifo.x + 8)
+    }
+
+    fn fetch_dot(&mut self) {
+        if self.fifo.step == FetchStep::Push {
+            self.push_tile();
+            return;
+        }
+        self.fifo.step_dots += 1;
+        if self.fifo.step_dots < 2 {
+            return;
+        }
+        self.fifo.step_dots = 0;
+        let fifo = &self.fifo;
+        let (map_x, y, high_map) = if fifo.in_window {
+            (
+                fifo.tile_x,
+                self.window_line,
+                self.lcdc & WINDOW_TILE_MAP != 0,
+            )
+        } else {
+            (
+                (self.scx / 8).wrapping_add(fifo.tile_x) & 31,
+                self.ly.wrapping_add(self.scy),
+                self.lcdc & BG_TILE_MAP != 0,
+            )
+        };
+        match fifo.step {
+            FetchStep::Tile => {
+                let map = Ppu::tile_map(high_map);
+                self.fifo.tile_index = self.vram[map + (y as usize / 8) * 32 + map_x as usize];
+                self.fifo.step = FetchStep::DataLow;
+            }
+            FetchStep::DataLow => {
+                let row = self.bg_tile_row(self.fifo.tile_index, y);
+                self.fifo.data_low = self.vram[row];
+                self.fifo.step = FetchStep::DataHigh;
+            }
+            FetchStep::DataHigh => {
+                let row = self.bg_tile_row(self.fifo.tile_index, y);
+                self.fifo.data_high = self.vram[row + 1];
+                self.fifo.step = FetchStep::Push;
+            }
+            FetchStep::Push => unreachable!(),
+        }
+    }
+
+    // the fetched tile goes in only once the fifo has run dry
+    fn push_tile(&mut self) {
+        let fifo = &mut self.fifo;
+        if !fifo.bg.is_empty() {
+            return;
+        }
+        for bit in (0..8).rev() {
+            let lo = (fifo.data_low >> bit) & 1;
+            let hi = (fifo.data_high >> bit) & 1;
+            fifo.bg.push_back(hi << 1 | lo);
+        }
+        fifo.tile_x = fifo.tile_x.wrapping_add(1);
+        fifo.step = FetchStep::Tile;
+    }
+
+    // mixes the next sprite's row into the sprite fifo. pixels already
+    // there came from higher priority sprites, so only transparent ones
+    // get replaced
+    fn merge_sprite(&mut self) {
+        let sprite = self.line_sprites[self.fifo.next_sprite];
+        self.fifo.next_sprite += 1;
+        let row = self.sprite_row(&sprite, sprite.height, self.ly);
+        let x = self.fifo.x as i16;
+        while self.fifo.obj.len() < 8 {
+            self.fifo.obj.push_back(ObjPixel::default());
+        }
+        for i in 0..8 {
+            // pixels left of where the lcd has got to are never shown
+            let screen_x = sprite.x as i16 - 8 + i;
+            if screen_x < x {
+                continue;
+            }
+            let column = if sprite.attributes & OBJ_X_FLIP != 0 {
+                7 - i as u8
+            } else {
+                i as u8
+            };
+            let color = self.tile_row_pixel(row, column);
+            let slot = &mut self.fifo.obj[(screen_x - x) as usize];
+            if slot.color != 0 {
+                continue;
+            }
+            *slot = ObjPixel {
+                color,
+                palette_1: sprite.attributes & OBJ_PALETTE_1 != 0,
+                behind_bg: sprite.attributes & OBJ_BEHIND_BG != 0,
+            };
+        }
+    }
+
+    // sends one pixel to the lcd if the fifo has one
+    fn shift_pixel(&mut self) {
+        let Some(color) = self.fifo.bg.pop_front() else {
+            return;
+        };
+        if self.fifo.discard > 0 {
+            self.fifo.discard -= 1;
+            return;
+        }
+        let bg = if self.lcdc & BG_ENABLE != 0 { color } else { 0 };
+        let obj = self.fifo.obj.pop_front().unwrap_or_default();
+        let shade = if self.lcdc & OBJ_ENABLE != 0 && obj.color != 0 && !(obj.behind_bg && bg != 0)
+        {
+            let palette = if obj.palette_1 { self.obp1 } else { self.obp0 };
+            shade_of(palette, obj.color)
+        } else {
+            shade_of(self.bgp, bg)
+        };
+        self.frame[self.ly as usize * SCREEN_WIDTH + self.fifo.x as usize] = shade;
+        self.fifo.x += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ppu::tests::pixel;
+    use crate::ppu::tests::ppu_with_renderer;
+    use crate::ppu::tests::run_frame;
+    use crate::ppu::tests::set_sprite;
+    use crate::ppu::tests::solid_tile;
+    use crate::ppu::Mode;
+    use crate::ppu::Ppu;
+    use crate::ppu::Renderer;
+
+    // dots spent in mode 3 on line 0, starting from the top of a frame
+    fn mode3_length(ppu: &mut Ppu) -> u16 {
+        let mut dots = 0;
+        while ppu.ly() != 0 || ppu.mode() != Mode::OamScan {
+            ppu.tick(1);
+        }
+        while ppu.mode() != Mode::Drawing {
+            ppu.tick(1);
+        }
+        while ppu.mode() == Mode::Drawing {
+            ppu.tick(1);
+            dots += 4;
+        }
+        dots
+    }
+
+    // a frame drawn by both renderers
+    fn render_both(setup: impl Fn(&mut Ppu), lcdc: u8) -> (Ppu, Ppu) {
+        let mut scanline = ppu_with_renderer(Renderer::Scanline, lcdc);
+        let mut fifo = ppu_with_renderer(Renderer::Fifo, lcdc);
+        setup(&mut scanline);
+        setup(&mut fifo);
+        for ppu in [&mut scanline, &mut fifo] {
+            run_frame(ppu);
+            run_frame(ppu);
+        }
+        (scanline, fifo)
+    }
+
+    // a tile with a different colour in each column pair, so scrolling
+    // by a pixel changes the picture
+    fn striped_tile(ppu: &mut Ppu, index: u16) {
+        for row in 0..8 {
+            ppu.write8(0x8000 + index * 16 + row * 2, 0b0101_0101);
+            ppu.write8(0x8000 + index * 16 + row * 2 + 1, 0b0011_0011);
+        }
+    }
+
+    #[test]
+    fn test_mode3_length() {
+        let mut ppu = ppu_with_renderer(Renderer::Fifo, 0x91);
+        // tick granularity is 4 dots, so lengths round up to a multiple of 4
+        assert_eq!(mode3_length(&mut ppu), 172);
+        ppu.write8(0xFF43, 4);
+        assert_eq!(mode3_length(&mut ppu), 176);
+
+        // the window restarting the fetcher costs 6 dots
+        let mut ppu = ppu_with_renderer(Renderer::Fifo, 0xB1);
+        ppu.write8(0xFF4B, 7 + 80);
+        assert_eq!(mode3_length(&mut ppu), 180);
+    }
+
+    #[test]
+    fn test_sprites_lengthen_mode3() {
+        let mut ppu = ppu_with_renderer(Renderer::Fifo, 0x93);
+        let base = mode3_length(&mut ppu);
+        set_sprite(&mut ppu, 0, 16, 8 + 40, 0, 0);
+        let one = mode3_length(&mut ppu);
+        assert!(one >= base + 6, "{} {}", base, one);
+        for n in 1..10 {
+            set_sprite(&mut ppu, n, 16, 8 + 40, 0, 0);
+        }
+        let ten = mode3_length(&mut ppu);
+        assert!(ten >= base + 60, "{} {}", base, ten);
+        // with sprites off the fetches don't happen
+        ppu.write8(0xFF40, 0x91);
+        assert_eq!(mode3_length(&mut ppu), base);
+    }
+
+    #[test]
+    fn test_matches_scanline_renderer() {
+        let setup = |ppu: &mut Ppu| {
+            striped_tile(ppu, 1);
+            solid_tile(ppu, 2, 3);
+            solid_tile(ppu, 3, 1);
+            for i in 0..0x400 {
+                ppu.write8(0x9800 + i, (i % 3) as u8);
+                ppu.write8(0x9C00 + i, 2 - (i % 2) as u8);
+            }
+            ppu.write8(0xFF42, 5);
+            ppu.write8(0xFF43, 3);
+            ppu.write8(0xFF4A, 40);
+            ppu.write8(0xFF4B, 7 + 100);
+            set_sprite(ppu, 0, 16 + 10, 8 + 20, 1, 0x00);
+            set_sprite(ppu, 1, 16 + 12, 8 + 24, 3, 0x20);
+            set_sprite(ppu, 2, 16 + 50, 8 + 98, 1, 0x90);
+            set_sprite(ppu, 3, 16 + 60, 3, 1, 0x40);
+            set_sprite(ppu, 4, 16 + 70, 8 + 156, 3, 0x00);
+        };
+        let (scanline, fifo) = render_both(setup, 0xF3);
+        for y in 0..144 {
+            for x in 0..160 {
+                assert_eq!(pixel(&fifo, x, y), pixel(&scanline, x, y), "({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn test_mid_line_scroll() {
+        let mut ppu = ppu_with_renderer(Renderer::Fifo, 0x91);
+        // striped and solid tiles taking turns across the map
+        striped_tile(&mut ppu, 1);
+        solid_tile(&mut ppu, 2, 3);
+        for i in 0..32 {
+            ppu.write8(0x9800 + i, 1 + (i % 2) as u8);
+        }
+        // part way through mode 3 of line 0, scroll by a tile
+        run_frame(&mut ppu);
+        while ppu.mode() != Mode::Drawing {
+            ppu.tick(1);
+        }
+        ppu.tick(20);
+        ppu.write8(0xFF43, 8);
+        while ppu.mode() == Mode::Drawing {
+            ppu.tick(1);
+        }
+        assert_eq!(pixel(&ppu, 0, 0), 0);
+        assert_eq!(pixel(&ppu, 1, 0), 1);
+        assert_eq!(pixel(&ppu, 8, 0), 3);
+        // column 12 is striped in the map, but the scroll moved column 13 there
+        assert_eq!(pixel(&ppu, 96, 0), 3);
+        assert_eq!(pixel(&ppu, 97, 0), 3);
+    }
+
+    #[test]
+    fn test_mid_line_obj_size() {
+        let mut ppu = ppu_with_renderer(Renderer::Fifo, 0x97);
+        solid_tile(&mut ppu, 2, 1);
+        solid_tile(&mut ppu, 3, 2);
+        // y flipped, so line 10 is row 5 of the top tile, which is tile 2
+        // since 8x16 ignores the low bit
+        set_sprite(&mut ppu, 0, 16, 8 + 100, 3, 0x40);
+        for _ in 0..10 * 114 {
+            ppu.tick(1);
+        }
+        while ppu.mode() != Mode::Drawing {
+            ppu.tick(1);
+        }
+        // back to 8x8 before the fetcher gets to the sprite, it's still
+        // drawn the way the scan found it
+        ppu.tick(5);
+        ppu.write8(0xFF40, 0x93);
+        while ppu.mode() == Mode::Drawing {
+            ppu.tick(1);
+        }
+        assert_eq!(pixel(&ppu, 100, 10), 1);
+        assert_eq!(pixel(&ppu, 107, 10), 1);
+    }
+
+    #[test]
+    fn test_mid_line_palette() {
+        let mut ppu = ppu_with_renderer(Renderer::Fifo, 0x91);
+        solid_tile(&mut ppu, 0, 1);
+        run_frame(&mut ppu);
+        while ppu.mode() != Mode::Drawing {
+            ppu.tick(1);
+        }
+        ppu.tick(20);
+        ppu.write8(0xFF47, 0b00_00_11_00);
+        while ppu.mode() == Mode::Drawing {
+            ppu.tick(1);
+        }
+        assert_eq!(pixel(&ppu, 0, 0), 1);
+        assert_eq!(pixel(&ppu, 159, 0), 3);
+    }
+}
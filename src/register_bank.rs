@@ -1,3 +1,78 @@
+use std::ops::BitOr;
+
+/*
+ * the flag register. only the top nibble exists in hardware:
+ * zero is the uppermost bit (bit 7)
+ * subtraction the second-upper (bit 6)
+ * half-carry the third-upper (bit 5)
+ * carry the fourth-upper (bit 4)
+ * the low nibble always reads back as zero, so anything
+ * written there is dropped (see from_bits_truncate)
+ */
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Flags(u8);
+
+impl Flags {
+    pub const ZERO: Flags = Flags(1 << 7);
+    pub const SUBTRACTION: Flags = Flags(1 << 6);
+    pub const HALF_CARRY: Flags = Flags(1 << 5);
+    pub const CARRY: Flags = Flags(1 << 4);
+
+    pub const fn empty() -> Flags {
+        Flags(0)
+    }
+
+    pub const fn all() -> Flags {
+        Flags(0xF0)
+    }
+
+    pub const fn bits(&self) -> u8 {
+        self.0
+    }
+
+    // None if any bit outside the top nibble is set
+    pub const fn from_bits(bits: u8) -> Option<Flags> {
+        if bits & !Flags::all().0 == 0 {
+            Some(Flags(bits))
+        } else {
+            None
+        }
+    }
+
+    // drops the bits that don't exist, like writing to F does
+    pub const fn from_bits_truncate(bits: u8) -> Flags {
+        Flags(bits & Flags::all().0)
+    }
+
+    pub const fn contains(&self, other: Flags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: Flags) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: Flags) {
+        self.0 &= !other.0;
+    }
+
+    pub fn set(&mut self, other: Flags, v: bool) {
+        if v {
+            self.insert(other);
+        } else {
+            self.remove(other);
+        }
+    }
+}
+
+impl BitOr for Flags {
+    type Output = Flags;
+
+    fn bitor(self, rhs: Flags) -> Flags {
+        Flags(self.0 | rhs.0)
+    }
+}
+
 #[derive(Default, Copy, Clone, Debug)]
 pub struct RegisterBank {
     // registers
@@ -6,9 +81,12 @@ pub struct RegisterBank {
     c: u8,
     d: u8,
     e: u8,
-    f: u8,
+    f: Flags,
     h: u8,
     l: u8,
+    // stack pointer and program counter are only ever used as 16 bits
+    sp: u16,
+    pc: u16,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -23,6 +101,17 @@ pub enum Register {
     L,
 }
 
+// register pairs, plus the two registers that are 16bit to begin with
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Register16 {
+    AF,
+    BC,
+    DE,
+    HL,
+    SP,
+    PC,
+}
+
 impl RegisterBank {
     pub fn read(&self, register: Register) -> u8 {
         match register {
@@ -31,31 +120,49 @@ impl RegisterBank {
             Register::C => self.c,
             Register::D => self.d,
             Register::E => self.e,
-            Register::F => self.f,
+            Register::F => self.f.bits(),
             Register::H => self.h,
             Register::L => self.l,
         }
     }
 
-    pub fn write_register(&mut self, register: Register, val: u8) -> Result<(), &str> {
+    pub fn write_register(&mut self, register: Register, val: u8) {
         match register {
             Register::A => self.a = val,
             Register::B => self.b = val,
             Register::C => self.c = val,
             Register::D => self.d = val,
             Register::E => self.e = val,
-            Register::F => {
-                // do we validate? only the top 4 bits are writable
-                if val & 0xF0 > 0 {
-                    return Err("Invalid register F value");
-                }
-                // TODO: maybe just use explicit setters
-                self.f = val;
-            }
+            Register::F => self.f = Flags::from_bits_truncate(val),
             Register::H => self.h = val,
             Register::L => self.l = val,
         };
-        Ok(())
+    }
+
+    pub fn read16(&self, register: Register16) -> u16 {
+        match register {
+            Register16::AF => (self.a as u16) << 8 | (self.f.bits() as u16),
+            Register16::BC => self.read_bc(),
+            Register16::DE => self.read_de(),
+            Register16::HL => self.read_hl(),
+            Register16::SP => self.sp,
+            Register16::PC => self.pc,
+        }
+    }
+
+    pub fn write16(&mut self, register: Register16, value: u16) {
+        match register {
+            Register16::AF => {
+                self.a = (value >> 8) as u8;
+                // the low nibble of F doesn't exist in hardware, POP AF drops it
+                self.f = Flags::from_bits_truncate(value as u8);
+            }
+            Register16::BC => self.write_bc(value),
+            Register16::DE => self.write_de(value),
+            Register16::HL => self.write_hl(value),
+            Register16::SP => self.sp = value,
+            Register16::PC => self.pc = value,
+        }
     }
 
     // special handlers for two-byte registers
@@ -88,66 +195,53 @@ impl RegisterBank {
         self.l = value as u8; // just truncate top bits
     }
 
-    /*
-     * special rules for handling flag register
-     * zero is the uppermost bit (bit 7)
-     * subtraction the second-upper (bit 6)
-     * half-carry the third-upper (bit 5)
-     * carry the fourth-upper (bit 4)
-     * therefore we allow strict accessors/setters
-     */
+    pub fn flags(&self) -> Flags {
+        self.f
+    }
+
+    pub fn set_flags(&mut self, flags: Flags) {
+        self.f = flags;
+    }
+
+    // strict accessors/setters for each flag
     pub fn set_zero_bit(&mut self, v: bool) {
-        if v {
-            self.f |= 1 << 7;
-        } else {
-            self.f &= !(1 << 7); // 0111_1111 sets 7th bit to 0, doesn't touch others
-        }
+        self.f.set(Flags::ZERO, v);
     }
 
     pub fn has_zero_bit(&self) -> bool {
-        self.f & 1 << 7 != 0
+        self.f.contains(Flags::ZERO)
     }
 
     pub fn set_subtraction_bit(&mut self, v: bool) {
-        if v {
-            self.f |= 1 << 6
-        } else {
-            self.f &= !(1 << 6)
-        }
+        self.f.set(Flags::SUBTRACTION, v);
     }
 
     pub fn has_subtraction_bit(&self) -> bool {
-        self.f & 0b0100_0000 != 0
+        self.f.contains(Flags::SUBTRACTION)
     }
 
     pub fn set_half_carry_bit(&mut self, v: bool) {
-        if v {
-            self.f |= 1 << 5
-        } else {
-            self.f &= !(1 << 5)
-        }
+        self.f.set(Flags::HALF_CARRY, v);
     }
 
     pub fn has_half_carry_bit(&self) -> bool {
-        self.f & 0b0010_0000 != 0
+        self.f.contains(Flags::HALF_CARRY)
     }
 
     pub fn set_carry_bit(&mut self, v: bool) {
-        if v {
-            self.f |= 1 << 4
-        } else {
-            self.f &= !(1 << 4)
-        }
+        self.f.set(Flags::CARRY, v);
     }
 
     pub fn has_carry_bit(&self) -> bool {
-        self.f & 0b0001_0000 != 0
+        self.f.contains(Flags::CARRY)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::register_bank::Flags;
     use crate::register_bank::Register;
+    use crate::register_bank::Register16;
     use crate::register_bank::RegisterBank;
     #[test]
     fn test_read() {
@@ -158,56 +252,97 @@ mod tests {
     #[test]
     fn test_write_should_update_register_bank() {
         let mut register_bank = RegisterBank::default();
-        register_bank.write_register(Register::A, 5).unwrap();
+        register_bank.write_register(Register::A, 5);
         assert_eq!(register_bank.read(Register::A), 5);
     }
 
     #[test]
-    fn test_write_register_valid() {
+    fn test_write_register_f_keeps_flag_bits() {
         let mut register_bank = RegisterBank::default();
-        assert!(
-            register_bank.write_register(Register::A, 5).is_ok(),
-            "Expected ok"
-        );
+        register_bank.write_register(Register::F, 0xA0);
+        assert_eq!(register_bank.read(Register::F), 0xA0);
+        assert!(register_bank.has_zero_bit());
+        assert!(!register_bank.has_subtraction_bit());
+        assert!(register_bank.has_half_carry_bit());
+        assert!(!register_bank.has_carry_bit());
     }
 
     #[test]
-    fn test_write_register_invalid() {
+    fn test_write_register_f_drops_low_nibble() {
         let mut register_bank = RegisterBank::default();
-        let invalid_input: u8 = 0xFF.into();
-        assert!(
-            register_bank
-                .write_register(Register::F, invalid_input)
-                .is_err(),
-            "Expected an error for writing 0xFF to Register F",
-        );
+        let input: u8 = 0xFF;
+        register_bank.write_register(Register::F, input);
+        assert_eq!(register_bank.read(Register::F), 0xF0);
+        assert_eq!(register_bank.flags(), Flags::all());
+    }
+
+    #[test]
+    fn test_flags() {
+        assert_eq!(Flags::from_bits(0x0F), None);
+        assert_eq!(Flags::from_bits(0x90), Some(Flags::ZERO | Flags::CARRY));
+        assert_eq!(Flags::from_bits_truncate(0x9F), Flags::ZERO | Flags::CARRY);
+        let mut flags = Flags::empty();
+        flags.insert(Flags::HALF_CARRY);
+        flags.set(Flags::SUBTRACTION, true);
+        assert!(flags.contains(Flags::HALF_CARRY | Flags::SUBTRACTION));
+        flags.remove(Flags::HALF_CARRY);
+        assert!(!flags.contains(Flags::HALF_CARRY));
+        assert_eq!(flags.bits(), 0x40);
     }
 
     #[test]
     fn test_bc_register() {
         let mut bank = RegisterBank::default();
-        bank.write_bc(0xFFFF as u16);
-        assert_eq!(bank.read_bc(), 0xFFFF as u16);
-        assert_eq!(bank.read(Register::B), 0xFF as u8);
-        assert_eq!(bank.read(Register::C), 0xFF as u8);
+        bank.write_bc(0xFFFF_u16);
+        assert_eq!(bank.read_bc(), 0xFFFF_u16);
+        assert_eq!(bank.read(Register::B), 0xFF_u8);
+        assert_eq!(bank.read(Register::C), 0xFF_u8);
     }
 
     #[test]
     fn test_de_register() {
         let mut bank = RegisterBank::default();
-        bank.write_de(0x00FF as u16);
-        assert_eq!(bank.read_de(), 0x00FF as u16);
-        assert_eq!(bank.read(Register::D), 0x00 as u8);
-        assert_eq!(bank.read(Register::E), 0xFF as u8);
+        bank.write_de(0x00FF_u16);
+        assert_eq!(bank.read_de(), 0x00FF_u16);
+        assert_eq!(bank.read(Register::D), 0x00_u8);
+        assert_eq!(bank.read(Register::E), 0xFF_u8);
     }
 
     #[test]
     fn test_hl_register() {
         let mut bank = RegisterBank::default();
-        bank.write_hl(0x11AA as u16);
-        assert_eq!(bank.read_hl(), 0x11AA as u16);
-        assert_eq!(bank.read(Register::H), 0x11 as u8);
-        assert_eq!(bank.read(Register::L), 0xAA as u8);
+        bank.write_hl(0x11AA_u16);
+        assert_eq!(bank.read_hl(), 0x11AA_u16);
+        assert_eq!(bank.read(Register::H), 0x11_u8);
+        assert_eq!(bank.read(Register::L), 0xAA_u8);
+    }
+
+    #[test]
+    fn test_read16_write16() {
+        let mut bank = RegisterBank::default();
+        for (reg, value) in [
+            (Register16::BC, 0x1234),
+            (Register16::DE, 0x5678),
+            (Register16::HL, 0x9ABC),
+            (Register16::SP, 0xFFFE),
+            (Register16::PC, 0x0100),
+        ] {
+            bank.write16(reg, value);
+            assert_eq!(bank.read16(reg), value, "{:?}", reg);
+        }
+        assert_eq!(bank.read_bc(), 0x1234);
+        assert_eq!(bank.read(Register::D), 0x56);
+        assert_eq!(bank.read(Register::L), 0xBC);
+    }
+
+    #[test]
+    fn test_af_register_masks_low_nibble() {
+        let mut bank = RegisterBank::default();
+        bank.write16(Register16::AF, 0x12FF);
+        assert_eq!(bank.read16(Register16::AF), 0x12F0);
+        assert_eq!(bank.read(Register::A), 0x12);
+        assert!(bank.has_zero_bit());
+        assert!(bank.has_carry_bit());
     }
 
     #[test]
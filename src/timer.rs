@@ -0,0 +1,369 @@
+/*
+ * DIV, TIMA, TMA and TAC, all hanging off one 16 bit counter that goes up
+ * every T-cycle. DIV is its top byte. TAC picks one of its bits, and TIMA
+ * goes up whenever that bit (ANDed with the enable bit) falls from 1 to 0,
+ * which is why writing DIV or TAC can tick TIMA too.
+ * pandocs: https://gbdev.io/pandocs/Timer_and_Divider_Registers.html
+ */
+use crate::interrupts::Interrupt;
+
+pub const DIV: u16 = 0xFF04;
+pub const TIMA: u16 = 0xFF05;
+pub const TMA: u16 = 0xFF06;
+pub const TAC: u16 = 0xFF07;
+
+const TAC_ENABLE: u8 = 1 << 2;
+
+#[derive(Debug, Clone, Default)]
+pub struct Timer {
+    counter: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    // TIMA overflowed last M-cycle and reads 0, it gets TMA and the
+    // interrupt fires this M-cycle unless TIMA is written first
+    overflow: bool,
+    // TIMA was loaded from TMA this M-cycle. writes to TIMA lose to the
+    // reload, writes to TMA go through to TIMA as well
+    reloading: bool,
+    // IF bits raised since the last tick returned
+    interrupts: u8,
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Timer::default()
+    }
+
+    // the whole internal counter, DIV is the top 8 bits
+    pub fn counter(&self) -> u16 {
+        self.counter
+    }
+
+    pub fn read8(&self, address: u16) -> u8 {
+        match address {
+            DIV => (self.counter >> 8) as u8,
+            TIMA => self.tima,
+            TMA => self.tma,
+            // only the low 3 bits exist
+            TAC => 0xF8 | self.tac,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write8(&mut self, address: u16, v: u8) {
+        match address {
+            DIV => {
+                let before = self.signal();
+                self.counter = 0;
+                self.falling_edge(before);
+            }
+            // lost to the reload if it lands on the same cycle
+            TIMA if self.reloading => {}
+            TIMA => {
+                self.tima = v;
+                self.overflow = false;
+            }
+            TMA => {
+                self.tma = v;
+                if self.reloading {
+                    self.tima = v;
+                }
+            }
+            TAC => {
+                let before = self.signal();
+                self.tac = v & 0x07;
+                self.falling_edge(before);
+            }
+            _ => {}
+        }
+    }
+
+    // runs for `cycles` M-cycles, returns the IF bits to raise
+    pub fn tick(&mut self, cycles: u8) -> u8 {
+        for _ in 0..cycles {
+            self.step();
+        }
+        std::mem::take(&mut self.interrupts)
+    }
+
+    fn step(&mut self) {
+        self.reloading = false;
+        if self.overflow {
+            self.overflow = false;
+            self.reloading = true;
+            self.tima = self.tma;
+            self.interrupts |= Interrupt::Timer.bit();
+        }
+        let before = self.signal();
+        self.counter = self.counter.wrapping_add(4);
+        self.falling_edge(before);
+    }
+
+    // the counter bit TAC selects, ANDed with the enable bit
+    fn signal(&self) -> bool {
+        let bit = match self.tac & 0x03 {
+            0b00 => 9,
+            0b01 => 3,
+            0b10 => 5,
+            _ => 7,
+        };
+        self.tac & TAC_ENABLE != 0 && self.counter & (1 << bit) != 0
+    }
+
+    fn falling_edge(&mut self, before: bool) {
+        if before && !self.signal() {
+            self.increment_tima();
+        }
+    }
+
+    fn increment_tima(&mut self) {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        if overflow {
+            self.overflow = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use crate::bus::Bus;
+    use crate::cartridge::tests::fix_checksums;
+    use crate::cartridge::tests::make_rom;
+    use crate::cartridge::Cartridge;
+    use crate::emulator::Emulator;
+    use crate::emulator::CYCLES_PER_FRAME;
+    use crate::interrupts::Interrupt;
+    use crate::register_bank::Register;
+    use crate::register_bank::Register16;
+    use crate::timer::Timer;
+    use crate::timer::DIV;
+    use crate::timer::TAC;
+    use crate::timer::TIMA;
+    use crate::timer::TMA;
+
+    // M-cycles per TIMA increment for each TAC clock select
+    const PERIODS: [(u8, u32); 4] = [(0b00, 256), (0b01, 4), (0b10, 16), (0b11, 64)];
+
+    fn run(timer: &mut Timer, cycles: u32) {
+        for _ in 0..cycles {
+            timer.tick(1);
+        }
+    }
+
+    fn timer_with_tac(tac: u8) -> Timer {
+        let mut timer = Timer::new();
+        timer.write8(TAC, tac);
+        timer
+    }
+
+    #[test]
+    fn test_div() {
+        let mut timer = Timer::new();
+        timer.tick(63);
+        assert_eq!(timer.read8(DIV), 0);
+        timer.tick(1);
+        assert_eq!(timer.read8(DIV), 1);
+        run(&mut timer, 64 * 255);
+        assert_eq!(timer.read8(DIV), 0);
+        timer.tick(200);
+        timer.write8(DIV, 0x55);
+        assert_eq!(timer.read8(DIV), 0);
+        assert_eq!(timer.counter(), 0);
+    }
+
+    #[test]
+    fn test_tima_rates() {
+        for (tac, period) in PERIODS {
+            let mut timer = timer_with_tac(0x04 | tac);
+            run(&mut timer, period - 1);
+            assert_eq!(timer.read8(TIMA), 0, "{:02b}", tac);
+            timer.tick(1);
+            assert_eq!(timer.read8(TIMA), 1, "{:02b}", tac);
+            run(&mut timer, period * 10);
+            assert_eq!(timer.read8(TIMA), 11, "{:02b}", tac);
+        }
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut timer = timer_with_tac(0x01);
+        timer.tick(100);
+        assert_eq!(timer.read8(TIMA), 0);
+        assert_eq!(timer.read8(TAC), 0xF9);
+    }
+
+    #[test]
+    fn test_div_write_ticks_tima() {
+        // like mooneye's div_trigger tests: resetting the counter while
+        // the selected bit is high is a falling edge
+        for (tac, period) in PERIODS {
+            let mut timer = timer_with_tac(0x04 | tac);
+            run(&mut timer, period / 2);
+            timer.write8(DIV, 0);
+            assert_eq!(timer.read8(TIMA), 1, "{:02b}", tac);
+            // and the count starts over
+            run(&mut timer, period - 1);
+            assert_eq!(timer.read8(TIMA), 1, "{:02b}", tac);
+        }
+        // but not while the bit is low
+        let mut timer = timer_with_tac(0x05);
+        timer.tick(1);
+        timer.write8(DIV, 0);
+        assert_eq!(timer.read8(TIMA), 0);
+    }
+
+    #[test]
+    fn test_tac_write_ticks_tima() {
+        // mooneye rapid_toggle: disabling while the bit is high ticks TIMA
+        let mut timer = timer_with_tac(0x05);
+        timer.tick(2);
+        timer.write8(TAC, 0x01);
+        assert_eq!(timer.read8(TIMA), 1);
+        // as does switching to a clock whose bit is low
+        let mut timer = timer_with_tac(0x05);
+        timer.tick(2);
+        timer.write8(TAC, 0x06);
+        assert_eq!(timer.read8(TIMA), 1);
+    }
+
+    #[test]
+    fn test_overflow_reloads_a_cycle_late() {
+        let mut timer = timer_with_tac(0x05);
+        timer.write8(TMA, 0xFE);
+        timer.write8(TIMA, 0xFF);
+        assert_eq!(timer.tick(3), 0);
+        // overflowed on the 4th, reads 0 for a cycle
+        assert_eq!(timer.tick(1), 0);
+        assert_eq!(timer.read8(TIMA), 0x00);
+        assert_eq!(timer.tick(1), Interrupt::Timer.bit());
+        assert_eq!(timer.read8(TIMA), 0xFE);
+    }
+
+    #[test]
+    fn test_tima_write_cancels_reload() {
+        // mooneye tima_write_reloading
+        let mut timer = timer_with_tac(0x05);
+        timer.write8(TMA, 0xFE);
+        timer.write8(TIMA, 0xFF);
+        timer.tick(4);
+        timer.write8(TIMA, 0x10);
+        assert_eq!(timer.tick(1), 0);
+        assert_eq!(timer.read8(TIMA), 0x10);
+    }
+
+    #[test]
+    fn test_tima_write_during_reload_is_ignored() {
+        let mut timer = timer_with_tac(0x05);
+        timer.write8(TMA, 0xFE);
+        timer.write8(TIMA, 0xFF);
+        assert_eq!(timer.tick(5), Interrupt::Timer.bit());
+        timer.write8(TIMA, 0x10);
+        assert_eq!(timer.read8(TIMA), 0xFE);
+    }
+
+    #[test]
+    fn test_tma_write_during_reload() {
+        // mooneye tma_write_reloading
+        let mut timer = timer_with_tac(0x05);
+        timer.write8(TMA, 0xFE);
+        timer.write8(TIMA, 0xFF);
+        timer.tick(5);
+        timer.write8(TMA, 0x20);
+        assert_eq!(timer.read8(TIMA), 0x20);
+        // the next cycle it's just TMA
+        timer.tick(1);
+        timer.write8(TMA, 0x30);
+        assert_eq!(timer.read8(TIMA), 0x20);
+    }
+
+    // mooneye's test roms end on LD B,B, with the fibonacci numbers in
+    // B, C, D, E, H and L if they passed
+    const LD_B_B: u8 = 0x40;
+    const MOONEYE_PASS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+    // the timer roms are done well within a second
+    const MOONEYE_TIMEOUT: u32 = CYCLES_PER_FRAME * 60 * 10;
+
+    fn run_mooneye(cartridge: Cartridge) -> Result<(), String> {
+        let mut emulator = Emulator::new(cartridge).map_err(|e| e.to_string())?;
+        let mut cycles = 0;
+        loop {
+            let cpu = emulator.cpu();
+            if cpu.bus().read8(cpu.registers().read16(Register16::PC)) == LD_B_B {
+                break;
+            }
+            if cycles > MOONEYE_TIMEOUT {
+                return Err("never got to LD B,B".to_string());
+            }
+            cycles += emulator.step().map_err(|e| e.to_string())? as u32;
+        }
+        let registers = emulator.cpu().registers();
+        let result = [
+            Register::B,
+            Register::C,
+            Register::D,
+            Register::E,
+            Register::H,
+            Register::L,
+        ]
+        .map(|r| registers.read(r));
+        if result == MOONEYE_PASS {
+            Ok(())
+        } else {
+            Err(format!("finished with {:?}", result))
+        }
+    }
+
+    #[test]
+    fn test_mooneye_harness() {
+        // LD B,3; LD C,5; LD D,8; LD E,13; LD H,21; LD L,34; LD B,B
+        let mut program = vec![
+            0x06, 3, 0x0E, 5, 0x16, 8, 0x1E, 13, 0x26, 21, 0x2E, 34, LD_B_B,
+        ];
+        let cartridge = |program: &[u8]| {
+            let mut rom = make_rom("MOONEYE", 0x00, 0x00, 0x00);
+            rom[0x0100..0x0100 + program.len()].copy_from_slice(program);
+            fix_checksums(&mut rom);
+            Cartridge::from_bytes(rom).unwrap()
+        };
+        assert_eq!(run_mooneye(cartridge(&program)), Ok(()));
+        // failing roms put 0x42 everywhere
+        program[11] = 0x42;
+        assert!(run_mooneye(cartridge(&program)).is_err());
+    }
+
+    /*
+     * the acceptance/timer roms from the mooneye test suite, which aren't
+     * kept in the repo:
+     * MOONEYE_TIMER_DIR=.../acceptance/timer cargo test -- --ignored mooneye
+     * https://github.com/Gekkio/mooneye-test-suite
+     */
+    #[test]
+    #[ignore = "needs MOONEYE_TIMER_DIR set to mooneye's acceptance/timer"]
+    fn test_mooneye_timer_roms() {
+        let dir = env::var_os("MOONEYE_TIMER_DIR").expect("MOONEYE_TIMER_DIR isn't set");
+        let mut roms: Vec<PathBuf> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "gb"))
+            .collect();
+        roms.sort();
+        assert!(!roms.is_empty(), "no .gb files in {:?}", dir);
+        let mut failed = Vec::new();
+        for rom in &roms {
+            let result = Cartridge::load(rom)
+                .map_err(|e| e.to_string())
+                .and_then(run_mooneye);
+            println!("{}: {:?}", rom.display(), result);
+            if let Err(e) = result {
+                failed.push(format!("{}: {}", rom.display(), e));
+            }
+        }
+        assert!(failed.is_empty(), "{}", failed.join("\n"));
+    }
+}
@@ -0,0 +1,151 @@
+/*
+ * 16 bit PCM .wav files. the header goes out first with the sizes left at
+ * 0 and they're filled in by finish, so a recording can be streamed to
+ * disk as it goes.
+ * http://soundfile.sapp.org/doc/WaveFormat/
+ */
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+
+const HEADER_LEN: u32 = 44;
+const BITS_PER_SAMPLE: u16 = 16;
+const FORMAT_PCM: u16 = 1;
+// the RIFF size covers everything after its own 8 bytes and is 32 bits,
+// which is a bit over 6 hours of 48kHz stereo
+const MAX_DATA_LEN: u32 = u32::MAX - (HEADER_LEN - 8);
+
+#[derive(Debug)]
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    // bytes of samples written so far
+    data_len: u32,
+}
+
+impl WavWriter<BufWriter<File>> {
+    pub fn create(path: impl AsRef<Path>, sample_rate: u32, channels: u16) -> io::Result<Self> {
+        WavWriter::new(BufWriter::new(File::create(path)?), sample_rate, channels)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut out: W, sample_rate: u32, channels: u16) -> io::Result<Self> {
+        let block_align = channels * BITS_PER_SAMPLE / 8;
+        out.write_all(b"RIFF")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        out.write_all(b"WAVE")?;
+        out.write_all(b"fmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        out.write_all(&FORMAT_PCM.to_le_bytes())?;
+        out.write_all(&channels.to_le_bytes())?;
+        out.write_all(&sample_rate.to_le_bytes())?;
+        out.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        out.write_all(&block_align.to_le_bytes())?;
+        out.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+        out.write_all(b"data")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        Ok(WavWriter { out, data_len: 0 })
+    }
+
+    // interleaved if there's more than one channel, clipped to -1.0 - 1.0.
+    // fails without writing anything once the file would go past 4 GiB
+    pub fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        let data_len = u32::try_from(samples.len() * 2)
+            .ok()
+            .and_then(|len| self.data_len.checked_add(len))
+            .filter(|&len| len <= MAX_DATA_LEN)
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::FileTooLarge, "wav is limited to 4 GiB")
+            })?;
+        for &sample in samples {
+            let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16;
+            self.out.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_len = data_len;
+        Ok(())
+    }
+
+    // fills in the sizes, hands back the writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out
+            .write_all(&(HEADER_LEN - 8 + self.data_len).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(HEADER_LEN as u64 - 4))?;
+        self.out.write_all(&self.data_len.to_le_bytes())?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::Cursor;
+
+    use crate::wav::WavWriter;
+    use crate::wav::MAX_DATA_LEN;
+
+    fn u16_at(bytes: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes([bytes[at], bytes[at + 1]])
+    }
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_header() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 44_100, 2).unwrap();
+        wav.write(&[0.0; 6]).unwrap();
+        let bytes = wav.finish().unwrap().into_inner();
+        assert_eq!(bytes.len(), 44 + 12);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32_at(&bytes, 4), 36 + 12);
+        assert_eq!(&bytes[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(&bytes, 16), 16);
+        // PCM, stereo
+        assert_eq!(u16_at(&bytes, 20), 1);
+        assert_eq!(u16_at(&bytes, 22), 2);
+        assert_eq!(u32_at(&bytes, 24), 44_100);
+        assert_eq!(u32_at(&bytes, 28), 44_100 * 4);
+        assert_eq!(u16_at(&bytes, 32), 4);
+        assert_eq!(u16_at(&bytes, 34), 16);
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(u32_at(&bytes, 40), 12);
+    }
+
+    #[test]
+    fn test_samples() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 8000, 1).unwrap();
+        wav.write(&[0.0, 1.0, -1.0]).unwrap();
+        // clipped
+        wav.write(&[0.5, 2.0, -3.0]).unwrap();
+        let bytes = wav.finish().unwrap().into_inner();
+        let samples: Vec<i16> = bytes[44..]
+            .chunks(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        assert_eq!(samples, [0, 32767, -32767, 16384, 32767, -32767]);
+        assert_eq!(u32_at(&bytes, 40), 12);
+    }
+
+    #[test]
+    fn test_size_limit() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 48_000, 2).unwrap();
+        // as if hours had already gone out
+        wav.data_len = MAX_DATA_LEN - 4;
+        wav.write(&[0.0; 2]).unwrap();
+        let err = wav.write(&[0.0]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::FileTooLarge);
+        // the sizes still fit and the refused sample never went out
+        let bytes = wav.finish().unwrap().into_inner();
+        assert_eq!(u32_at(&bytes, 4), u32::MAX);
+        assert_eq!(u32_at(&bytes, 40), MAX_DATA_LEN);
+        assert_eq!(bytes.len(), 44 + 4);
+    }
+}